@@ -0,0 +1,821 @@
+//! Append only file: every write is logged as the RESP command that
+//! reproduces it, and the log is replayed on startup.
+//!
+//! The AOF uses the multi part layout of Redis 7. A directory holds a base
+//! file with a snapshot of the dataset, in RDB or command format, the
+//! incremental files with the writes made since, and a manifest listing
+//! them in order. A rewrite starts a new incremental file, writes a new base
+//! from a snapshot in the background, and then drops the older files.
+
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Cursor, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use tokio::time::sleep;
+
+use crate::{
+    config::{SystemConfig, SystemConfigArc},
+    parser::{parse_next, RedisValue},
+    rdb::{self, write_rdb, RdbFile},
+    request::{get_request, serialize_effects, Request, RequestHandler, WriteEffect},
+    store::StoreArc,
+    value::Value,
+};
+
+const AOF_CYCLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// When the log is flushed to disk: after every write, once per second, or
+/// whenever the OS decides.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl Display for AppendFsync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppendFsync::Always => write!(f, "always"),
+            AppendFsync::EverySec => write!(f, "everysec"),
+            AppendFsync::No => write!(f, "no"),
+        }
+    }
+}
+
+pub fn parse_append_fsync(policy: &str) -> Result<AppendFsync> {
+    match policy.to_lowercase().as_str() {
+        "always" => Ok(AppendFsync::Always),
+        "everysec" => Ok(AppendFsync::EverySec),
+        "no" => Ok(AppendFsync::No),
+        _ => Err(anyhow!("argument must be one of always, everysec or no")),
+    }
+}
+
+/// Settings that CONFIG SET can change while the server runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AofSettings {
+    pub fsync: AppendFsync,
+    /// Whether rewrites write the base as RDB rather than as commands.
+    pub use_rdb_preamble: bool,
+    /// Growth over the last base size, in percent, that triggers a rewrite.
+    /// Zero disables automatic rewrites.
+    pub rewrite_percentage: u64,
+    /// Size below which the AOF is never rewritten automatically.
+    pub rewrite_min_size: u64,
+}
+
+impl Default for AofSettings {
+    fn default() -> Self {
+        AofSettings {
+            fsync: AppendFsync::EverySec,
+            use_rdb_preamble: true,
+            rewrite_percentage: 100,
+            rewrite_min_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A file listed in the manifest, with its sequence number.
+#[derive(Clone, Debug, PartialEq)]
+struct AofFile {
+    name: String,
+    seq: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Manifest {
+    base: Option<AofFile>,
+    /// Incremental files in the order they are replayed.
+    incrs: Vec<AofFile>,
+}
+
+impl Manifest {
+    /// Parses lines such as `file appendonly.aof.1.base.rdb seq 1 type b`.
+    /// History files, left over from a rewrite, are skipped.
+    fn parse(text: &str) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<_> = line.split_whitespace().collect();
+            if parts.len() & 1 == 1 {
+                return Err(anyhow!("Invalid AOF manifest line: {line}"));
+            }
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+            for pair in parts.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_owned()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => file_type = Some(pair[1]),
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(file_type)) = (name, seq, file_type) else {
+                return Err(anyhow!("Invalid AOF manifest line: {line}"));
+            };
+            let file = AofFile { name, seq };
+            match file_type {
+                "b" if manifest.base.is_some() => {
+                    return Err(anyhow!("Found duplicate base file information"))
+                }
+                "b" => manifest.base = Some(file),
+                "i" => {
+                    if manifest.incrs.last().is_some_and(|last| last.seq >= seq) {
+                        return Err(anyhow!("Found a non-monotonic sequence number"));
+                    }
+                    manifest.incrs.push(file);
+                }
+                "h" => {}
+                _ => return Err(anyhow!("Unknown AOF file type {file_type}")),
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn serialize(&self) -> String {
+        let mut text = String::new();
+        if let Some(base) = &self.base {
+            text += &format!("file {} seq {} type b\n", base.name, base.seq);
+        }
+        for incr in &self.incrs {
+            text += &format!("file {} seq {} type i\n", incr.name, incr.seq);
+        }
+        text
+    }
+
+    fn next_incr(&self, file_name: &str) -> AofFile {
+        let seq = self.incrs.last().map_or(1, |last| last.seq + 1);
+        AofFile {
+            name: format!("{file_name}.{seq}.incr.aof"),
+            seq,
+        }
+    }
+
+    fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+}
+
+pub struct Aof {
+    state: Mutex<Option<AofState>>,
+    settings: Mutex<AofSettings>,
+    rewrite_in_progress: AtomicBool,
+}
+
+struct AofState {
+    dir: PathBuf,
+    /// Prefix of the file names, `appendfilename` in the config.
+    file_name: String,
+    manifest: Manifest,
+    /// Appends to the last incremental file.
+    writer: AofWriter,
+    base_size: u64,
+    /// Bytes in the incremental files of the manifest.
+    incr_size: u64,
+}
+
+struct AofWriter {
+    file: File,
+    /// Commands logged since the file was last written.
+    buf: Vec<u8>,
+    /// Database the logged commands apply to. Unknown after opening, so the
+    /// first command is always preceded by a SELECT.
+    selected_db: Option<usize>,
+    /// Whether data was written since the last fsync.
+    unsynced: bool,
+}
+
+impl Default for Aof {
+    fn default() -> Self {
+        Aof {
+            state: Mutex::new(None),
+            settings: Mutex::new(AofSettings::default()),
+            rewrite_in_progress: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Aof {
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().is_some()
+    }
+
+    pub fn get_settings(&self) -> AofSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    pub fn set_settings(&self, settings: AofSettings) {
+        *self.settings.lock().unwrap() = settings;
+    }
+
+    pub fn is_rewrite_in_progress(&self) -> bool {
+        self.rewrite_in_progress.load(Ordering::SeqCst)
+    }
+
+    /// Logs the writes of one request. Called with the keyspace locked so
+    /// the log follows the order the writes were applied in.
+    pub fn append(&self, effects: &[WriteEffect]) {
+        let mut state = self.state.lock().unwrap();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+        let writer = &mut state.writer;
+        serialize_effects(effects, &mut writer.selected_db, &mut writer.buf);
+    }
+
+    /// Writes the logged commands to the file, and syncs it to disk right
+    /// away with the `always` policy. Done before replying to the client.
+    pub fn flush(&self) -> Result<()> {
+        let always = self.get_settings().fsync == AppendFsync::Always;
+        let mut state = self.state.lock().unwrap();
+        let Some(state) = state.as_mut() else {
+            return Ok(());
+        };
+        state.write_buffer()?;
+        if always && state.writer.unsynced {
+            state.writer.file.sync_data()?;
+            state.writer.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Syncs what was written since the last sync. The file is synced
+    /// through a second handle so writers are not blocked meanwhile.
+    pub fn sync(&self) -> Result<()> {
+        let file = {
+            let mut state = self.state.lock().unwrap();
+            match state.as_mut() {
+                Some(state) if state.writer.unsynced => {
+                    state.writer.unsynced = false;
+                    state.writer.file.try_clone()?
+                }
+                _ => return Ok(()),
+            }
+        };
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Starts a rewrite from `snapshot`, taken with the keyspace locked.
+    /// Writes made from now on go to a new incremental file, which is all
+    /// that remains next to the new base once it is written.
+    pub fn background_rewrite(self: &Arc<Self>, snapshot: RdbFile) -> Result<()> {
+        if self
+            .rewrite_in_progress
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(anyhow!(
+                "Background append only file rewriting already in progress"
+            ));
+        }
+        let started = self.start_rewrite();
+        let (dir, file_name, base_seq, first_incr, rewritten_size) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                self.rewrite_in_progress.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        let use_rdb_preamble = self.get_settings().use_rdb_preamble;
+        let aof = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = write_base(&dir, &file_name, base_seq, &snapshot, use_rdb_preamble)
+                .and_then(|(base, size)| {
+                    aof.finish_rewrite(base, size, first_incr, rewritten_size)
+                });
+            if let Err(e) = result {
+                eprintln!("background append only file rewriting failed: {e}");
+            }
+            aof.rewrite_in_progress.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    /// Switches to a new incremental file and records it in the manifest.
+    fn start_rewrite(&self) -> Result<(PathBuf, String, u64, u64, u64)> {
+        let mut state = self.state.lock().unwrap();
+        let state = state
+            .as_mut()
+            .ok_or(anyhow!("Append only file is disabled"))?;
+        state.write_buffer()?;
+        let incr = state.manifest.next_incr(&state.file_name);
+        let mut manifest = state.manifest.clone();
+        manifest.incrs.push(incr.clone());
+        let writer = AofWriter::open(&state.dir.join(&incr.name))?;
+        write_manifest(&state.dir, &state.file_name, &manifest)?;
+        state.manifest = manifest;
+        state.writer = writer;
+        let base_seq = state.manifest.base.as_ref().map_or(1, |base| base.seq + 1);
+        Ok((
+            state.dir.clone(),
+            state.file_name.clone(),
+            base_seq,
+            incr.seq,
+            state.incr_size,
+        ))
+    }
+
+    /// Replaces the base and the incremental files written before the
+    /// rewrite started, then deletes them.
+    fn finish_rewrite(
+        &self,
+        base: AofFile,
+        base_size: u64,
+        first_incr: u64,
+        rewritten_size: u64,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = state
+            .as_mut()
+            .ok_or(anyhow!("Append only file is disabled"))?;
+        let mut manifest = state.manifest.clone();
+        let old_files: Vec<_> = manifest
+            .files()
+            .filter(|file| file.name != base.name)
+            .filter(|file| manifest.base.as_ref() == Some(*file) || file.seq < first_incr)
+            .cloned()
+            .collect();
+        manifest.base = Some(base);
+        manifest.incrs.retain(|incr| incr.seq >= first_incr);
+        write_manifest(&state.dir, &state.file_name, &manifest)?;
+        state.manifest = manifest;
+        state.base_size = base_size;
+        state.incr_size -= rewritten_size;
+        for file in old_files {
+            let _ = fs::remove_file(state.dir.join(file.name));
+        }
+        Ok(())
+    }
+
+    /// Whether the AOF grew enough since the last rewrite to compact it.
+    fn should_rewrite(&self) -> bool {
+        let settings = self.get_settings();
+        if settings.rewrite_percentage == 0 || self.is_rewrite_in_progress() {
+            return false;
+        }
+        let state = self.state.lock().unwrap();
+        let Some(state) = state.as_ref() else {
+            return false;
+        };
+        let size = state.base_size + state.incr_size;
+        let growth = state.incr_size * 100 / state.base_size.max(1);
+        size >= settings.rewrite_min_size && growth >= settings.rewrite_percentage
+    }
+}
+
+impl AofState {
+    fn write_buffer(&mut self) -> Result<()> {
+        let writer = &mut self.writer;
+        if writer.buf.is_empty() {
+            return Ok(());
+        }
+        writer.file.write_all(&writer.buf)?;
+        self.incr_size += writer.buf.len() as u64;
+        writer.buf.clear();
+        writer.unsynced = true;
+        Ok(())
+    }
+}
+
+impl AofWriter {
+    fn open(path: &Path) -> Result<AofWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AofWriter {
+            file,
+            buf: vec![],
+            selected_db: None,
+            unsynced: false,
+        })
+    }
+}
+
+/// Syncs the log once per second under the `everysec` policy, which bounds
+/// what a crash can lose to about a second of writes, and starts a rewrite
+/// when the AOF outgrew the configured thresholds.
+pub fn start_aof_cycle(store: StoreArc) {
+    tokio::spawn(async move {
+        loop {
+            sleep(AOF_CYCLE_PERIOD).await;
+            let aof = store.get_aof();
+            if aof.get_settings().fsync == AppendFsync::EverySec {
+                if let Err(e) = aof.sync() {
+                    eprintln!("failed to fsync the append only file: {e}");
+                }
+            }
+            if aof.should_rewrite() {
+                let keyspace = store.lock().await;
+                let snapshot = store.snapshot(&keyspace);
+                if let Err(e) = aof.background_rewrite(snapshot) {
+                    eprintln!("failed to start rewriting the append only file: {e}");
+                }
+            }
+        }
+    });
+}
+
+fn write_manifest(dir: &Path, file_name: &str, manifest: &Manifest) -> Result<()> {
+    let temp_path = dir.join(format!("temp-{file_name}.manifest"));
+    let mut temp = File::create(&temp_path)?;
+    temp.write_all(manifest.serialize().as_bytes())?;
+    temp.sync_all()?;
+    fs::rename(temp_path, dir.join(format!("{file_name}.manifest")))?;
+    Ok(())
+}
+
+/// Writes a base file from `snapshot` and returns it with its size. The
+/// command format can only hold strings, so datasets with other types are
+/// written as RDB either way.
+fn write_base(
+    dir: &Path,
+    file_name: &str,
+    seq: u64,
+    snapshot: &RdbFile,
+    use_rdb_preamble: bool,
+) -> Result<(AofFile, u64)> {
+    let commands = if use_rdb_preamble {
+        None
+    } else {
+        let commands = base_commands(snapshot);
+        if commands.is_none() {
+            eprintln!("writing the AOF base as RDB since the dataset has non-string values");
+        }
+        commands
+    };
+    let extension = if commands.is_some() { "aof" } else { "rdb" };
+    let base = AofFile {
+        name: format!("{file_name}.{seq}.base.{extension}"),
+        seq,
+    };
+    let temp_path = dir.join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+    let result = File::create(&temp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|temp| {
+            let mut writer = BufWriter::new(temp);
+            match &commands {
+                Some(commands) => writer.write_all(commands)?,
+                None => write_rdb(snapshot, &mut writer)?,
+            }
+            let temp = writer.into_inner()?;
+            temp.sync_all()?;
+            let size = temp.metadata()?.len();
+            fs::rename(&temp_path, dir.join(&base.name))?;
+            Ok(size)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    Ok((base, result?))
+}
+
+/// The commands that recreate `snapshot`, or `None` if it holds values
+/// without a command to create them.
+fn base_commands(snapshot: &RdbFile) -> Option<Vec<u8>> {
+    let mut commands = vec![];
+    let mut push = |parts: Vec<String>| {
+        commands.extend(RedisValue::make_bulk_array(parts).serialize());
+    };
+    for code in &snapshot.functions {
+        push(vec!["FUNCTION".to_owned(), "LOAD".to_owned(), code.clone()]);
+    }
+    for (db, database) in &snapshot.databases {
+        push(vec!["SELECT".to_owned(), db.to_string()]);
+        for (key, value) in &database.key_vals {
+            let Value::String(value) = value.as_ref() else {
+                return None;
+            };
+            let mut set = vec!["SET".to_owned(), key.clone(), value.clone()];
+            if let Some(deadline) = database.key_expires.get(key) {
+                let millis = deadline.duration_since(UNIX_EPOCH).ok()?.as_millis();
+                set.push("PXAT".to_owned());
+                set.push(millis.to_string());
+            }
+            push(set);
+        }
+    }
+    Some(commands)
+}
+
+/// Whether an AOF exists to load the dataset from, in the multi part
+/// layout or as a single file from before Redis 7.
+pub fn aof_exists(config: &SystemConfig) -> bool {
+    Path::new(&config.get_aof_dir())
+        .join(format!("{}.manifest", config.get_append_file_name()))
+        .exists()
+        || Path::new(&config.get_legacy_aof_path()).exists()
+}
+
+/// Loads the AOF and starts appending to it. A single file AOF is moved into
+/// the directory as the base, and without any AOF the current dataset
+/// becomes the first base.
+pub async fn open_aof(store: &StoreArc, config: SystemConfigArc) -> Result<()> {
+    let dir = PathBuf::from(config.get_aof_dir());
+    let file_name = config.get_append_file_name();
+    let manifest_path = dir.join(format!("{file_name}.manifest"));
+    let legacy_path = PathBuf::from(config.get_legacy_aof_path());
+    let load_truncated = config.get_aof_load_truncated();
+    store.get_aof().set_settings(config.get_aof_settings());
+    let mut handler = RequestHandler::new(store.clone(), config);
+
+    let mut manifest = if manifest_path.exists() {
+        let manifest = Manifest::parse(&fs::read_to_string(&manifest_path)?)?;
+        let files: Vec<_> = manifest.files().collect();
+        for (i, file) in files.iter().enumerate() {
+            // Only the file being appended to when the server stopped can
+            // end halfway through a command.
+            let last = i == files.len() - 1;
+            load_file(
+                store,
+                &mut handler,
+                &dir.join(&file.name),
+                last && load_truncated,
+            )
+            .await?;
+        }
+        manifest
+    } else {
+        fs::create_dir_all(&dir)?;
+        let base = if legacy_path.exists() {
+            load_file(store, &mut handler, &legacy_path, load_truncated).await?;
+            let base = AofFile {
+                name: format!("{file_name}.1.base.aof"),
+                seq: 1,
+            };
+            fs::rename(&legacy_path, dir.join(&base.name))?;
+            base
+        } else {
+            let snapshot = store.snapshot(&*store.lock().await);
+            let use_rdb_preamble = store.get_aof().get_settings().use_rdb_preamble;
+            write_base(&dir, &file_name, 1, &snapshot, use_rdb_preamble)?.0
+        };
+        Manifest {
+            base: Some(base),
+            incrs: vec![],
+        }
+    };
+    if manifest.incrs.is_empty() {
+        manifest.incrs.push(manifest.next_incr(&file_name));
+    }
+    let incr = manifest.incrs.last().unwrap();
+    let writer = AofWriter::open(&dir.join(&incr.name))?;
+    write_manifest(&dir, &file_name, &manifest)?;
+
+    let size = |file: &AofFile| fs::metadata(dir.join(&file.name)).map_or(0, |m| m.len());
+    let base_size = manifest.base.as_ref().map_or(0, size);
+    let incr_size = manifest.incrs.iter().map(size).sum();
+    *store.get_aof().state.lock().unwrap() = Some(AofState {
+        dir,
+        file_name,
+        manifest,
+        writer,
+        base_size,
+        incr_size,
+    });
+    store.get_persistence().reset_dirty();
+    Ok(())
+}
+
+/// Replays one AOF file, which may start with an RDB preamble. A file that
+/// ends in the middle of a command or of a transaction is cut back to its
+/// last complete command when `load_truncated` is set, and is an error
+/// otherwise.
+async fn load_file(
+    store: &StoreArc,
+    handler: &mut RequestHandler,
+    path: &Path,
+    load_truncated: bool,
+) -> Result<()> {
+    let data = fs::read(path)?;
+    let mut start = 0;
+    if data.starts_with(b"REDIS") {
+        let mut cursor = Cursor::new(data.as_slice());
+        let file = rdb::parse(&mut cursor)
+            .map_err(|e| anyhow!("Bad RDB preamble in the append only file: {e}"))?;
+        store
+            .load_rdb(file)
+            .await
+            .map_err(|e| anyhow!("Bad RDB preamble in the append only file: {e}"))?;
+        start = cursor.position() as usize;
+    }
+
+    let mut buf = BytesMut::from(&data[start..]);
+    let mut in_transaction = false;
+    // End of the last command that is not part of an unfinished transaction.
+    let mut valid_len = start;
+    loop {
+        let offset = data.len() - buf.len();
+        let value = match parse_next(&mut buf) {
+            Ok(Some(value)) => value,
+            Ok(None) => break,
+            Err(e) => {
+                return Err(anyhow!(
+                    "Bad file format reading the append only file at offset {offset}: {e}"
+                ))
+            }
+        };
+        let request = get_request(value).map_err(|e| {
+            anyhow!("Unknown command reading the append only file at offset {offset}: {e}")
+        })?;
+        match request {
+            Request::Multi => in_transaction = true,
+            Request::Exec => in_transaction = false,
+            _ => {}
+        }
+        handler.handle_request(request).await;
+        if !in_transaction {
+            valid_len = data.len() - buf.len();
+        }
+    }
+    if in_transaction {
+        // Commands queued by a transaction that never reached EXEC.
+        handler.handle_request(Request::Discard).await;
+    }
+    if valid_len < data.len() {
+        if !load_truncated {
+            return Err(anyhow!(
+                "Unexpected end of file reading the append only file at offset {valid_len}"
+            ));
+        }
+        eprintln!(
+            "!!! Warning: short read while loading the AOF file {}, truncating it to {valid_len} bytes",
+            path.display()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_len as u64)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use crate::{
+        aof::{open_aof, Manifest},
+        config::{parse_args, SystemConfig},
+        parser::RedisValue,
+        request::{Request, RequestHandler},
+        store::Store,
+    };
+
+    fn command(parts: &[&str]) -> Vec<u8> {
+        RedisValue::make_bulk_array(parts.iter().map(|part| part.to_string()).collect()).serialize()
+    }
+
+    fn config(dir: &Path, extra: &[&str]) -> Arc<SystemConfig> {
+        let dir = dir.to_str().unwrap();
+        let args = ["exec", "--dir", dir, "--dbfilename", "dump.rdb"];
+        let args = args.iter().chain(extra).map(|arg| arg.to_string());
+        Arc::new(parse_args(args).unwrap())
+    }
+
+    #[test]
+    fn should_parse_and_serialize_manifests() {
+        let text = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                    file appendonly.aof.1.incr.aof seq 1 type h\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\n\
+                    file appendonly.aof.4.incr.aof seq 4 type i startoffset 10\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 2);
+        assert_eq!(manifest.incrs.len(), 2);
+        assert_eq!(
+            manifest.next_incr("appendonly.aof").name,
+            "appendonly.aof.5.incr.aof"
+        );
+        assert_eq!(Manifest::parse(&manifest.serialize()).unwrap(), manifest);
+
+        assert!(Manifest::parse("file a seq 1 type b\nfile b seq 2 type b").is_err());
+        assert!(Manifest::parse("file a seq 2 type i\nfile b seq 1 type i").is_err());
+        assert!(Manifest::parse("file a seq x type i").is_err());
+        assert!(Manifest::parse("file a seq 1").is_err());
+    }
+
+    #[tokio::test]
+    async fn should_log_and_replay_writes() {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config(&dir, &["--appendonly", "yes"]);
+        let aof_dir = dir.join("appendonlydir");
+
+        let store = Arc::new(Store::new());
+        open_aof(&store, config.clone()).await.unwrap();
+        let mut h = RequestHandler::new(store.clone(), config.clone());
+        h.handle_request(Request::Set("a".to_owned(), "1".to_owned(), None))
+            .await;
+        h.handle_request(Request::Select(2)).await;
+        h.handle_request(Request::Multi).await;
+        h.handle_request(Request::Set("b".to_owned(), "2".to_owned(), None))
+            .await;
+        h.handle_request(Request::Exec).await;
+        h.handle_request(Request::Get("b".to_owned())).await;
+
+        let mut expected = command(&["SELECT", "0"]);
+        expected.extend(command(&["SET", "a", "1"]));
+        expected.extend(command(&["SELECT", "2"]));
+        expected.extend(command(&["SET", "b", "2"]));
+        let incr = aof_dir.join("appendonly.aof.1.incr.aof");
+        assert_eq!(std::fs::read(&incr).unwrap(), expected);
+        assert!(aof_dir.join("appendonly.aof.1.base.rdb").exists());
+
+        // A transaction cut short by a crash is dropped when replaying.
+        let mut log = expected.clone();
+        log.extend(command(&["MULTI"]));
+        log.extend(command(&["SET", "c", "3"]));
+        log.extend_from_slice(b"*1\r\n$4\r\nEX");
+        std::fs::write(&incr, &log).unwrap();
+
+        let strict = self::config(&dir, &["--aof-load-truncated", "no"]);
+        assert!(open_aof(&Arc::new(Store::new()), strict).await.is_err());
+        let restored = Arc::new(Store::new());
+        open_aof(&restored, config).await.unwrap();
+        assert_eq!(std::fs::read(&incr).unwrap(), expected);
+        let mut keyspace = restored.lock().await;
+        assert_eq!(keyspace.db(0).get("a").unwrap(), Some("1".to_owned()));
+        assert_eq!(keyspace.db(2).get("b").unwrap(), Some("2".to_owned()));
+        assert_eq!(keyspace.db(2).get("c").unwrap(), None);
+        drop(keyspace);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_rewrite_into_a_new_base() {
+        let dir = std::env::temp_dir().join(format!("aof-rewrite-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config(&dir, &["--aof-use-rdb-preamble", "no"]);
+        let aof_dir = dir.join("appendonlydir");
+
+        let store = Arc::new(Store::new());
+        open_aof(&store, config.clone()).await.unwrap();
+        let mut h = RequestHandler::new(store.clone(), config.clone());
+        for i in 0..10 {
+            h.handle_request(Request::Set("a".to_owned(), i.to_string(), None))
+                .await;
+        }
+        // The writes before BGREWRITEAOF in a transaction are in the new base
+        // and must not be logged again after it.
+        h.handle_request(Request::Multi).await;
+        h.handle_request(Request::Rename("a".to_owned(), "c".to_owned()))
+            .await;
+        h.handle_request(Request::BgRewriteAof).await;
+        h.handle_request(Request::Set("b".to_owned(), "1".to_owned(), None))
+            .await;
+        let reply = h.handle_request(Request::Exec).await;
+        assert_eq!(
+            reply,
+            RedisValue::Array(vec![
+                RedisValue::SimpleString("OK".to_owned()),
+                RedisValue::SimpleString(
+                    "Background append only file rewriting started".to_owned()
+                ),
+                RedisValue::SimpleString("OK".to_owned()),
+            ])
+        );
+        while store.get_aof().is_rewrite_in_progress() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let manifest = std::fs::read_to_string(aof_dir.join("appendonly.aof.manifest")).unwrap();
+        assert_eq!(
+            manifest,
+            "file appendonly.aof.2.base.aof seq 2 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert!(!aof_dir.join("appendonly.aof.1.base.aof").exists());
+        assert!(!aof_dir.join("appendonly.aof.1.incr.aof").exists());
+        let mut base = command(&["SELECT", "0"]);
+        base.extend(command(&["SET", "c", "9"]));
+        assert_eq!(
+            std::fs::read(aof_dir.join("appendonly.aof.2.base.aof")).unwrap(),
+            base
+        );
+        let mut incr = command(&["SELECT", "0"]);
+        incr.extend(command(&["SET", "b", "1"]));
+        assert_eq!(
+            std::fs::read(aof_dir.join("appendonly.aof.2.incr.aof")).unwrap(),
+            incr
+        );
+
+        let restored = Arc::new(Store::new());
+        open_aof(&restored, config).await.unwrap();
+        let mut keyspace = restored.lock().await;
+        assert_eq!(keyspace.db(0).get("a").unwrap(), None);
+        assert_eq!(keyspace.db(0).get("c").unwrap(), Some("9".to_owned()));
+        assert_eq!(keyspace.db(0).get("b").unwrap(), Some("1".to_owned()));
+        drop(keyspace);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
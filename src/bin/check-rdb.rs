@@ -0,0 +1,107 @@
+//! Checks an RDB file without starting a server, and optionally exports its
+//! keys as JSON lines or prints how much space they take.
+//!
+//! Usage: check-rdb [--json | --stats [--separator <sep>] [--top <n>]] <file>
+//!
+//! `cargo run` keeps starting the server, so run it by name:
+//! `cargo run --release --bin check-rdb -- --stats dump.rdb`.
+
+use std::io::{self, BufWriter, Write};
+
+use anyhow::{anyhow, Result};
+use redis_starter_rust::inspect::{write_json_lines, MemoryStats};
+use redis_starter_rust::rdb::{read_rdb_file, RdbError, RdbFile};
+
+const USAGE: &str = "usage: check-rdb [--json | --stats [--separator <sep>] [--top <n>]] <file>";
+
+enum Mode {
+    Check,
+    Json,
+    Stats { separator: String, top: usize },
+}
+
+fn main() {
+    let (mode, path) = match parse_args(std::env::args().skip(1)) {
+        Result::Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let file = match read_rdb_file(path.clone()) {
+        Result::Ok(file) => file,
+        Err(e) => {
+            eprintln!("--- RDB ERROR DETECTED ---");
+            eprintln!("{path}: {e}");
+            if let Some(e) = e.downcast_ref::<RdbError>() {
+                eprintln!("offset: {}", e.offset);
+            }
+            std::process::exit(1);
+        }
+    };
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let result = match mode {
+        Mode::Check => write_summary(&file, &mut out),
+        Mode::Json => write_json_lines(&file, &mut out),
+        Mode::Stats { separator, top } => {
+            MemoryStats::collect(&file, &separator).write_report(top, &mut out)
+        }
+    };
+    if let Err(e) = result.and_then(|_| out.flush().map_err(anyhow::Error::from)) {
+        eprintln!("failed to write the output: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn write_summary(file: &RdbFile, mut out: impl Write) -> Result<()> {
+    let keys: usize = file.databases.values().map(|db| db.key_vals.len()).sum();
+    let expires: usize = file.databases.values().map(|db| db.key_expires.len()).sum();
+    writeln!(out, "[info] RDB version {}", file.version)?;
+    for (key, value) in &file.aux {
+        writeln!(out, "[info] AUX FIELD {key} = '{value}'")?;
+    }
+    writeln!(
+        out,
+        "[info] {keys} keys read, {expires} expires, {} functions",
+        file.functions.len()
+    )?;
+    writeln!(out, "\\o/ RDB looks OK! \\o/")?;
+    Ok(())
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(Mode, String)> {
+    let mut mode = Mode::Check;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => mode = Mode::Json,
+            "--stats" => {
+                mode = Mode::Stats {
+                    separator: ":".to_owned(),
+                    top: 20,
+                }
+            }
+            "--separator" => {
+                let Mode::Stats { separator, .. } = &mut mode else {
+                    return Err(anyhow!("--separator needs --stats before it"));
+                };
+                *separator = args
+                    .next()
+                    .ok_or(anyhow!("should provide value for --separator"))?;
+            }
+            "--top" => {
+                let Mode::Stats { top, .. } = &mut mode else {
+                    return Err(anyhow!("--top needs --stats before it"));
+                };
+                *top = args
+                    .next()
+                    .ok_or(anyhow!("should provide value for --top"))?
+                    .parse()?;
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(anyhow!("unexpected argument {arg}")),
+        }
+    }
+    Ok((mode, path.ok_or(anyhow!("should provide the RDB file"))?))
+}
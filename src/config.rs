@@ -2,8 +2,21 @@ use std::{fmt::Display, sync::Arc};
 
 use anyhow::{anyhow, Result};
 
+use crate::{
+    aof::{parse_append_fsync, AofSettings, AppendFsync},
+    persistence::{parse_save_rules, DEFAULT_SAVE_RULES},
+    replication::DEFAULT_REPL_BACKLOG_SIZE,
+    store::DEFAULT_BUSY_REPLY_THRESHOLD,
+};
+
 pub type SystemConfigArc = Arc<SystemConfig>;
 
+const DEFAULT_DATABASES: usize = 16;
+const DEFAULT_DIR: &str = ".";
+const DEFAULT_DB_FILE_NAME: &str = "dump.rdb";
+const DEFAULT_APPEND_FILE_NAME: &str = "appendonly.aof";
+const DEFAULT_APPEND_DIR_NAME: &str = "appendonlydir";
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Role {
     Master,
@@ -22,8 +35,6 @@ impl Display for Role {
 #[derive(Debug, PartialEq, Clone)]
 pub struct ReplicationConfig {
     role: Role,
-    id: String,
-    offset: u32,
     master_ip: String,
     master_port: String,
 }
@@ -33,72 +44,134 @@ impl ReplicationConfig {
         self.role == Role::Slave
     }
 
-    pub fn get_id(&self) -> String {
-        self.id.clone()
-    }
-
-    pub fn get_offset(&self) -> u32 {
-        self.offset
-    }
-
     pub fn get_ip_port(&self) -> (String, String) {
         (self.master_ip.clone(), self.master_port.clone())
     }
 }
 
-impl Display for ReplicationConfig {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "role:{}\n", self.role)?;
-        write!(f, "master_replid:{}\n", self.id)?;
-        write!(f, "master_repl_offset:{}", self.offset)
-    }
-}
-
 impl Default for ReplicationConfig {
     fn default() -> Self {
         Self {
             role: Role::Master,
-            id: "0bc2cc0c5c37aee9000f72bdbb894c472a444051".to_owned(),
-            offset: 0,
             master_ip: String::default(),
             master_port: String::default(),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Default)]
 pub struct SystemConfig {
     db_dir: Option<String>,
     db_file_name: Option<String>,
     port: Option<String>,
+    databases: Option<usize>,
+    /// Initial `save` rules, which CONFIG SET can change later.
+    save: Option<String>,
+    appendonly: bool,
+    append_file_name: Option<String>,
+    append_dir_name: Option<String>,
+    appendfsync: Option<AppendFsync>,
+    aof_load_truncated: Option<bool>,
+    aof_use_rdb_preamble: Option<bool>,
+    auto_aof_rewrite_percentage: Option<u64>,
+    auto_aof_rewrite_min_size: Option<u64>,
+    repl_backlog_size: Option<u64>,
+    replica_read_only: Option<bool>,
+    busy_reply_threshold: Option<u64>,
     replication_config: ReplicationConfig,
 }
 
-impl Default for SystemConfig {
-    fn default() -> Self {
-        Self {
-            db_dir: None,
-            db_file_name: None,
-            port: None,
-            replication_config: ReplicationConfig::default(),
-        }
-    }
-}
-
 impl SystemConfig {
-    pub fn get_config(&self, key: &String) -> Option<String> {
-        match key.as_str() {
-            "dir" => self.db_dir.clone(),
-            "dbfilename" => self.db_file_name.clone(),
+    pub fn get_config(&self, key: &str) -> Option<String> {
+        match key {
+            "dir" => Some(self.db_dir.clone().unwrap_or(DEFAULT_DIR.to_owned())),
+            "dbfilename" => Some(
+                self.db_file_name
+                    .clone()
+                    .unwrap_or(DEFAULT_DB_FILE_NAME.to_owned()),
+            ),
+            "databases" => Some(self.get_databases().to_string()),
+            "appendonly" => Some(yes_no(self.appendonly)),
+            "appendfilename" => Some(self.get_append_file_name()),
+            "appenddirname" => Some(
+                self.append_dir_name
+                    .clone()
+                    .unwrap_or(DEFAULT_APPEND_DIR_NAME.to_owned()),
+            ),
+            "aof-load-truncated" => Some(yes_no(self.get_aof_load_truncated())),
             _ => None,
         }
     }
 
-    pub fn get_rdb_path(&self) -> Option<String> {
-        if self.db_dir.is_none() {
-            return None;
+    /// Where the dataset is loaded from and saved to, `./dump.rdb` unless
+    /// configured.
+    pub fn get_rdb_path(&self) -> String {
+        let db_dir = self.db_dir.as_deref().unwrap_or(DEFAULT_DIR);
+        let db_file_name = self.db_file_name.as_deref().unwrap_or(DEFAULT_DB_FILE_NAME);
+        format!("{db_dir}/{db_file_name}")
+    }
+
+    pub fn is_appendonly(&self) -> bool {
+        self.appendonly
+    }
+
+    pub fn get_append_file_name(&self) -> String {
+        self.append_file_name
+            .clone()
+            .unwrap_or(DEFAULT_APPEND_FILE_NAME.to_owned())
+    }
+
+    /// The directory with the AOF files, inside the configured dir.
+    pub fn get_aof_dir(&self) -> String {
+        let db_dir = self.db_dir.as_deref().unwrap_or(DEFAULT_DIR);
+        let dir_name = self
+            .append_dir_name
+            .as_deref()
+            .unwrap_or(DEFAULT_APPEND_DIR_NAME);
+        format!("{db_dir}/{dir_name}")
+    }
+
+    /// Where versions before Redis 7 kept the AOF as a single file.
+    pub fn get_legacy_aof_path(&self) -> String {
+        let db_dir = self.db_dir.as_deref().unwrap_or(DEFAULT_DIR);
+        format!("{db_dir}/{}", self.get_append_file_name())
+    }
+
+    /// Initial AOF settings, which CONFIG SET can change later.
+    pub fn get_aof_settings(&self) -> AofSettings {
+        let default = AofSettings::default();
+        AofSettings {
+            fsync: self.appendfsync.unwrap_or(default.fsync),
+            use_rdb_preamble: self
+                .aof_use_rdb_preamble
+                .unwrap_or(default.use_rdb_preamble),
+            rewrite_percentage: self
+                .auto_aof_rewrite_percentage
+                .unwrap_or(default.rewrite_percentage),
+            rewrite_min_size: self
+                .auto_aof_rewrite_min_size
+                .unwrap_or(default.rewrite_min_size),
         }
-        Some(self.db_dir.clone().unwrap() + "/" + &self.db_file_name.clone().unwrap())
+    }
+
+    pub fn get_aof_load_truncated(&self) -> bool {
+        self.aof_load_truncated.unwrap_or(true)
+    }
+
+    /// Whether an RDB file was configured with `--dir` or `--dbfilename`.
+    /// Without one the dataset is not loaded at startup nor saved by
+    /// default.
+    pub fn has_rdb_file(&self) -> bool {
+        self.db_dir.is_some() || self.db_file_name.is_some()
+    }
+
+    pub fn get_save_rules(&self) -> String {
+        let default = if self.has_rdb_file() {
+            DEFAULT_SAVE_RULES
+        } else {
+            ""
+        };
+        self.save.clone().unwrap_or(default.to_owned())
     }
 
     pub fn get_port(&self) -> String {
@@ -108,6 +181,24 @@ impl SystemConfig {
         self.port.clone().unwrap()
     }
 
+    pub fn get_databases(&self) -> usize {
+        self.databases.unwrap_or(DEFAULT_DATABASES)
+    }
+
+    pub fn get_repl_backlog_size(&self) -> u64 {
+        self.repl_backlog_size.unwrap_or(DEFAULT_REPL_BACKLOG_SIZE)
+    }
+
+    pub fn get_replica_read_only(&self) -> bool {
+        self.replica_read_only.unwrap_or(true)
+    }
+
+    /// Milliseconds a script may run, 0 for no limit.
+    pub fn get_busy_reply_threshold(&self) -> u64 {
+        self.busy_reply_threshold
+            .unwrap_or(DEFAULT_BUSY_REPLY_THRESHOLD)
+    }
+
     pub fn get_replication_config(&self) -> ReplicationConfig {
         self.replication_config.clone()
     }
@@ -137,6 +228,91 @@ pub fn parse_args(args: impl Iterator<Item = String>) -> Result<SystemConfig> {
                     .ok_or(anyhow!("should provide value for --port"))?;
                 config.port = Some(port)
             }
+            "--databases" => {
+                let databases = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --databases"))?
+                    .parse::<usize>()?;
+                if databases == 0 {
+                    return Err(anyhow!("--databases should be at least 1"));
+                }
+                config.databases = Some(databases);
+            }
+            "--save" => {
+                let rules = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --save"))?;
+                parse_save_rules(&rules)?;
+                config.save = Some(rules);
+            }
+            "--appendonly" => {
+                let appendonly = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --appendonly"))?;
+                config.appendonly = parse_yes_no(&appendonly)?;
+            }
+            "--appendfilename" => {
+                let file_name = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --appendfilename"))?;
+                config.append_file_name = Some(file_name);
+            }
+            "--appenddirname" => {
+                let dir_name = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --appenddirname"))?;
+                config.append_dir_name = Some(dir_name);
+            }
+            "--appendfsync" => {
+                let policy = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --appendfsync"))?;
+                config.appendfsync = Some(parse_append_fsync(&policy)?);
+            }
+            "--aof-load-truncated" => {
+                let load_truncated = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --aof-load-truncated"))?;
+                config.aof_load_truncated = Some(parse_yes_no(&load_truncated)?);
+            }
+            "--aof-use-rdb-preamble" => {
+                let use_rdb_preamble = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --aof-use-rdb-preamble"))?;
+                config.aof_use_rdb_preamble = Some(parse_yes_no(&use_rdb_preamble)?);
+            }
+            "--auto-aof-rewrite-percentage" => {
+                let percentage = peek
+                    .next()
+                    .ok_or(anyhow!(
+                        "should provide value for --auto-aof-rewrite-percentage"
+                    ))?
+                    .parse::<u64>()?;
+                config.auto_aof_rewrite_percentage = Some(percentage);
+            }
+            "--auto-aof-rewrite-min-size" => {
+                let min_size = peek.next().ok_or(anyhow!(
+                    "should provide value for --auto-aof-rewrite-min-size"
+                ))?;
+                config.auto_aof_rewrite_min_size = Some(parse_memory(&min_size)?);
+            }
+            "--repl-backlog-size" => {
+                let size = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for --repl-backlog-size"))?;
+                config.repl_backlog_size = Some(parse_memory(&size)?);
+            }
+            "--replica-read-only" | "--slave-read-only" => {
+                let read_only = peek.next().ok_or(anyhow!("should provide value for {x}"))?;
+                config.replica_read_only = Some(parse_yes_no(&read_only)?);
+            }
+            "--busy-reply-threshold" | "--lua-time-limit" => {
+                let millis = peek
+                    .next()
+                    .ok_or(anyhow!("should provide value for {x}"))?
+                    .parse::<u64>()?;
+                config.busy_reply_threshold = Some(millis);
+            }
             "--replicaof" => {
                 config.replication_config.role = Role::Slave;
                 let ip_port = peek
@@ -150,19 +326,56 @@ pub fn parse_args(args: impl Iterator<Item = String>) -> Result<SystemConfig> {
             _ => {}
         }
     }
-    if config.db_dir != None && config.db_file_name == None {
+    if config.db_dir.is_some() && config.db_file_name.is_none() {
         return Err(anyhow!("should provide --dbfilename with --dir"));
     }
     Ok(config)
 }
 
+/// Parses a size such as `64mb`, with an optional k, kb, m, mb, g or gb
+/// unit. The units without `b` are powers of 1000 and the others of 1024.
+pub fn parse_memory(value: &str) -> Result<u64> {
+    let value = value.to_lowercase();
+    let units = [
+        ("gb", 1 << 30),
+        ("mb", 1 << 20),
+        ("kb", 1 << 10),
+        ("g", 1_000_000_000),
+        ("m", 1_000_000),
+        ("k", 1_000),
+        ("b", 1),
+    ];
+    let (number, unit) = units
+        .iter()
+        .find_map(|(suffix, unit)| value.strip_suffix(suffix).map(|number| (number, *unit)))
+        .unwrap_or((value.as_str(), 1));
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or(anyhow!("argument must be a memory value"))
+}
+
+pub fn parse_yes_no(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(anyhow!("argument must be 'yes' or 'no'")),
+    }
+}
+
+pub fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_owned()
+}
+
 #[cfg(test)]
 mod test {
     use anyhow::Result;
 
+    use crate::aof::AppendFsync;
     use crate::config::{ReplicationConfig, Role, SystemConfig};
 
-    use super::parse_args;
+    use super::{parse_args, parse_memory, DEFAULT_SAVE_RULES};
 
     fn check_err<T>(res: Result<T>, err_message: &str) {
         match res {
@@ -203,6 +416,36 @@ mod test {
         check_err(res, "provide --dbfilename with --dir");
     }
 
+    #[test]
+    fn should_only_save_by_default_with_an_rdb_file() {
+        let parse = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let config = parse(&["exec"]);
+        assert!(!config.has_rdb_file());
+        assert_eq!(config.get_save_rules(), "");
+        let config = parse(&["exec", "--dir", "/tmp", "--dbfilename", "dump.rdb"]);
+        assert!(config.has_rdb_file());
+        assert_eq!(config.get_save_rules(), DEFAULT_SAVE_RULES);
+        assert_eq!(parse(&["exec", "--save", "60 1"]).get_save_rules(), "60 1");
+    }
+
+    #[test]
+    fn should_err_if_save_rules_are_invalid() {
+        let args = vec!["exec", "--save", "60"];
+        let res = parse_args(args.into_iter().map(|arg| arg.to_owned()));
+        check_err(res, "Invalid save parameters");
+    }
+
+    #[test]
+    fn should_parse_memory_values() {
+        assert_eq!(parse_memory("100").unwrap(), 100);
+        assert_eq!(parse_memory("2k").unwrap(), 2000);
+        assert_eq!(parse_memory("2KB").unwrap(), 2048);
+        assert_eq!(parse_memory("64mb").unwrap(), 64 << 20);
+        assert_eq!(parse_memory("1g").unwrap(), 1_000_000_000);
+        assert!(parse_memory("mb").is_err());
+        assert!(parse_memory("-1").is_err());
+    }
+
     #[test]
     fn should_return_config_with_given_values() {
         let args = vec![
@@ -213,12 +456,35 @@ mod test {
             "filename",
             "--port",
             "7070",
+            "--databases",
+            "4",
+            "--save",
+            "60 1",
+            "--appendonly",
+            "yes",
+            "--appendfsync",
+            "always",
+            "--auto-aof-rewrite-min-size",
+            "32mb",
         ];
         let res = parse_args(args.into_iter().map(|arg| arg.to_owned()));
         let expected_config = SystemConfig {
             db_dir: Some("filedir".to_owned()),
             db_file_name: Some("filename".to_owned()),
             port: Some("7070".to_owned()),
+            databases: Some(4),
+            save: Some("60 1".to_owned()),
+            appendonly: true,
+            append_file_name: None,
+            append_dir_name: None,
+            appendfsync: Some(AppendFsync::Always),
+            aof_load_truncated: None,
+            aof_use_rdb_preamble: None,
+            auto_aof_rewrite_percentage: None,
+            auto_aof_rewrite_min_size: Some(32 * 1024 * 1024),
+            repl_backlog_size: None,
+            replica_read_only: None,
+            busy_reply_threshold: None,
             replication_config: ReplicationConfig::default(),
         };
         assert_eq!(res.unwrap(), expected_config);
@@ -226,17 +492,41 @@ mod test {
 
     #[test]
     fn should_return_slave_config() {
-        let args = vec!["exec", "--port", "7070", "--replicaof", "localhost 7171"];
+        let args = vec![
+            "exec",
+            "--port",
+            "7070",
+            "--replicaof",
+            "localhost 7171",
+            "--repl-backlog-size",
+            "1mb",
+            "--replica-read-only",
+            "no",
+            "--lua-time-limit",
+            "100",
+        ];
         let res = parse_args(args.into_iter().map(|arg| arg.to_owned()));
         let expected_config = SystemConfig {
             db_dir: None,
             db_file_name: None,
             port: Some("7070".to_owned()),
+            databases: None,
+            save: None,
+            appendonly: false,
+            append_file_name: None,
+            append_dir_name: None,
+            appendfsync: None,
+            aof_load_truncated: None,
+            aof_use_rdb_preamble: None,
+            auto_aof_rewrite_percentage: None,
+            auto_aof_rewrite_min_size: None,
+            repl_backlog_size: Some(1 << 20),
+            replica_read_only: Some(false),
+            busy_reply_threshold: Some(100),
             replication_config: ReplicationConfig {
                 role: Role::Slave,
                 master_ip: "localhost".to_owned(),
                 master_port: "7171".to_owned(),
-                ..ReplicationConfig::default()
             },
         };
         assert_eq!(res.unwrap(), expected_config);
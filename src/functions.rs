@@ -0,0 +1,236 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+
+use crate::{glob::glob_match, parser::RedisValue, rdb, scripting::discover_functions};
+
+/// How FUNCTION RESTORE treats libraries that already exist.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RestorePolicy {
+    Append,
+    Replace,
+    Flush,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    /// Registered function names with their flags, sorted by name.
+    pub functions: Vec<(String, Vec<String>)>,
+}
+
+impl Library {
+    /// Parses the `#!lua name=<library>` header and discovers the functions
+    /// the library registers.
+    pub fn parse(code: &str) -> Result<Library> {
+        let header = code.lines().next().unwrap_or_default();
+        let metadata = header
+            .strip_prefix("#!")
+            .ok_or(anyhow!("Missing library metadata"))?;
+        let mut parts = metadata.split_whitespace();
+        if parts.next() != Some("lua") {
+            return Err(anyhow!("Engine not found"));
+        }
+        let name = parts
+            .find_map(|part| part.strip_prefix("name="))
+            .ok_or(anyhow!("Library name was not given"))?;
+        let functions =
+            discover_functions(code).map_err(|e| anyhow!("Error registering functions: {e}"))?;
+        if functions.is_empty() {
+            return Err(anyhow!("No functions registered"));
+        }
+        Ok(Library {
+            name: name.to_owned(),
+            code: code.to_owned(),
+            functions,
+        })
+    }
+
+    fn to_redis_value(&self, with_code: bool) -> RedisValue {
+        let functions = self
+            .functions
+            .iter()
+            .map(|(name, flags)| {
+                RedisValue::Array(vec![
+                    RedisValue::BulkString("name".to_owned()),
+                    RedisValue::BulkString(name.clone()),
+                    RedisValue::BulkString("description".to_owned()),
+                    RedisValue::NullBulkString,
+                    RedisValue::BulkString("flags".to_owned()),
+                    RedisValue::make_bulk_array(flags.clone()),
+                ])
+            })
+            .collect();
+        let mut value = vec![
+            RedisValue::BulkString("library_name".to_owned()),
+            RedisValue::BulkString(self.name.clone()),
+            RedisValue::BulkString("engine".to_owned()),
+            RedisValue::BulkString("LUA".to_owned()),
+            RedisValue::BulkString("functions".to_owned()),
+            RedisValue::Array(functions),
+        ];
+        if with_code {
+            value.push(RedisValue::BulkString("library_code".to_owned()));
+            value.push(RedisValue::BulkString(self.code.clone()));
+        }
+        RedisValue::Array(value)
+    }
+}
+
+/// Function libraries loaded with FUNCTION LOAD, keyed by library name.
+#[derive(Default)]
+pub struct FunctionLibraries {
+    libraries: Mutex<BTreeMap<String, Library>>,
+}
+
+impl FunctionLibraries {
+    /// Adds a library and returns its name. Fails if the library exists and
+    /// `replace` is false, or if one of its functions is already defined by
+    /// another library.
+    pub fn load(&self, code: &str, replace: bool) -> Result<String> {
+        let library = Library::parse(code)?;
+        let mut libraries = self.libraries.lock().unwrap();
+        Self::add(&mut libraries, library, replace)
+    }
+
+    fn add(
+        libraries: &mut BTreeMap<String, Library>,
+        library: Library,
+        replace: bool,
+    ) -> Result<String> {
+        if libraries.contains_key(&library.name) && !replace {
+            return Err(anyhow!("Library '{}' already exists", library.name));
+        }
+        for other in libraries
+            .values()
+            .filter(|other| other.name != library.name)
+        {
+            if let Some((function, _)) = library
+                .functions
+                .iter()
+                .find(|(name, _)| other.functions.iter().any(|(f, _)| f == name))
+            {
+                return Err(anyhow!("Function {function} already exists"));
+            }
+        }
+        let name = library.name.clone();
+        libraries.insert(name.clone(), library);
+        Ok(name)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        self.libraries
+            .lock()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or(anyhow!("Library not found"))
+    }
+
+    pub fn flush(&self) {
+        self.libraries.lock().unwrap().clear();
+    }
+
+    pub fn list(&self, pattern: Option<&str>, with_code: bool) -> RedisValue {
+        let libraries = self.libraries.lock().unwrap();
+        RedisValue::Array(
+            libraries
+                .values()
+                .filter(|library| !matches!(pattern, Some(p) if !glob_match(p, &library.name)))
+                .map(|library| library.to_redis_value(with_code))
+                .collect(),
+        )
+    }
+
+    /// Returns the code of the library defining `function` and its flags.
+    pub fn find(&self, function: &str) -> Option<(String, Vec<String>)> {
+        let libraries = self.libraries.lock().unwrap();
+        libraries.values().find_map(|library| {
+            library
+                .functions
+                .iter()
+                .find(|(name, _)| name == function)
+                .map(|(_, flags)| (library.code.clone(), flags.clone()))
+        })
+    }
+
+    /// Codes of all libraries, which is what gets persisted and dumped.
+    pub fn get_codes(&self) -> Vec<String> {
+        let libraries = self.libraries.lock().unwrap();
+        libraries
+            .values()
+            .map(|library| library.code.clone())
+            .collect()
+    }
+
+    pub fn dump(&self) -> Vec<u8> {
+        rdb::dump_functions(&self.get_codes())
+    }
+
+    /// Replaces every library with the ones in `codes`, as loaded from an
+    /// RDB image. Libraries that fail to load are skipped.
+    pub fn replace_all(&self, codes: Vec<String>) {
+        let mut libraries = BTreeMap::new();
+        for code in codes {
+            let added =
+                Library::parse(&code).and_then(|library| Self::add(&mut libraries, library, true));
+            if let Err(e) = added {
+                eprintln!("failed to load a function library from the RDB file: {e}");
+            }
+        }
+        *self.libraries.lock().unwrap() = libraries;
+    }
+
+    /// Loads the libraries of a FUNCTION DUMP payload. Nothing changes if any
+    /// of them fails to load.
+    pub fn restore(&self, payload: &[u8], policy: RestorePolicy) -> Result<()> {
+        let codes = rdb::restore_functions(payload)?;
+        let mut libraries = self.libraries.lock().unwrap();
+        let mut restored = match policy {
+            RestorePolicy::Flush => BTreeMap::new(),
+            _ => libraries.clone(),
+        };
+        for code in codes {
+            let library = Library::parse(&code)?;
+            Self::add(&mut restored, library, policy == RestorePolicy::Replace)?;
+        }
+        *libraries = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::{FunctionLibraries, RestorePolicy};
+
+    const LIB_A: &str =
+        "#!lua name=liba\nredis.register_function('fa', function(keys, args) return 1 end)";
+    const LIB_B: &str =
+        "#!lua name=libb\nredis.register_function('fa', function(keys, args) return 2 end)";
+
+    #[test]
+    fn should_reject_duplicate_libraries_and_functions() {
+        let functions = FunctionLibraries::default();
+        assert_eq!(functions.load(LIB_A, false).unwrap(), "liba");
+        assert!(functions.load(LIB_A, false).is_err());
+        assert!(functions.load(LIB_A, true).is_ok());
+        assert!(functions.load(LIB_B, false).is_err());
+        assert!(functions.load("return 1", false).is_err());
+    }
+
+    #[test]
+    fn should_dump_and_restore_libraries() {
+        let functions = FunctionLibraries::default();
+        functions.load(LIB_A, false).unwrap();
+        let payload = functions.dump();
+
+        let restored = FunctionLibraries::default();
+        restored.load(LIB_B, false).unwrap();
+        assert!(restored.restore(&payload, RestorePolicy::Append).is_err());
+        assert_eq!(restored.get_codes(), vec![LIB_B.to_owned()]);
+        restored.restore(&payload, RestorePolicy::Flush).unwrap();
+        assert_eq!(restored.get_codes(), vec![LIB_A.to_owned()]);
+        assert!(restored.find("fa").is_some());
+    }
+}
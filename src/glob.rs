@@ -0,0 +1,109 @@
+/// Redis style glob matching supporting `*`, `?`, `[...]` classes (with `^`
+/// negation and `a-z` ranges) and `\` escapes.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == '*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (t..=text.len()).any(|start| match_from(&pattern[p + 1..], &text[start..]));
+            }
+            '?' => {
+                if t >= text.len() {
+                    return false;
+                }
+                t += 1;
+            }
+            '[' => {
+                if t >= text.len() {
+                    return false;
+                }
+                let (matched, next) = match_class(pattern, p + 1, text[t]);
+                if !matched {
+                    return false;
+                }
+                p = next;
+                t += 1;
+                continue;
+            }
+            c => {
+                let c = if c == '\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    pattern[p]
+                } else {
+                    c
+                };
+                if t >= text.len() || text[t] != c {
+                    return false;
+                }
+                t += 1;
+            }
+        }
+        p += 1;
+    }
+    t == text.len()
+}
+
+/// Matches `c` against the class starting right after `[`, returning whether
+/// it matched and the pattern index following the closing `]`.
+fn match_class(pattern: &[char], mut p: usize, c: char) -> (bool, usize) {
+    let negate = p < pattern.len() && pattern[p] == '^';
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != ']' {
+        if pattern[p] == '\\' && p + 1 < pattern.len() {
+            p += 1;
+            matched |= pattern[p] == c;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == '-' && pattern[p + 2] != ']' {
+            let (mut low, mut high) = (pattern[p], pattern[p + 2]);
+            if low > high {
+                std::mem::swap(&mut low, &mut high);
+            }
+            matched |= low <= c && c <= high;
+            p += 2;
+        } else {
+            matched |= pattern[p] == c;
+        }
+        p += 1;
+    }
+    (matched != negate, p + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glob::glob_match;
+
+    #[test]
+    fn should_match_wildcards() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(!glob_match("h*llo", "helloo"));
+        assert!(glob_match(
+            "__keyevent@*__:expired",
+            "__keyevent@0__:expired"
+        ));
+    }
+
+    #[test]
+    fn should_match_classes_and_escapes() {
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-f]llo", "hdllo"));
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+    }
+}
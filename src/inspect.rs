@@ -0,0 +1,282 @@
+//! Offline inspection of RDB files: JSON export and memory statistics, used
+//! by the `check-rdb` example binary.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Result;
+
+use crate::{
+    rdb::{dump_value, RdbFile},
+    value::{Stream, StreamId, Value},
+};
+
+/// Size of the DUMP footer, which is not part of the value itself.
+const DUMP_FOOTER_LEN: usize = 10;
+
+/// Writes one JSON object per key, with its database, type, expiry as a Unix
+/// time in milliseconds (or null) and value. Keys are sorted so exports of
+/// the same dataset compare equal.
+pub fn write_json_lines(file: &RdbFile, mut writer: impl Write) -> Result<()> {
+    for (db, database) in &file.databases {
+        let mut keys: Vec<_> = database.key_vals.keys().collect();
+        keys.sort();
+        for key in keys {
+            let value = &database.key_vals[key];
+            let expiry = database
+                .key_expires
+                .get(key)
+                .and_then(|deadline| deadline.duration_since(UNIX_EPOCH).ok())
+                .map_or("null".to_owned(), |d| d.as_millis().to_string());
+            writeln!(
+                writer,
+                "{{\"db\":{db},\"key\":{},\"type\":\"{}\",\"expiry\":{expiry},\"value\":{}}}",
+                json_string(key),
+                value.type_name(),
+                json_value(value)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => json_string(s),
+        Value::List(list) => json_array(list.iter().map(|item| json_string(item))),
+        Value::Set(set) => {
+            let mut members: Vec<_> = set.iter().collect();
+            members.sort();
+            json_array(members.into_iter().map(|member| json_string(member)))
+        }
+        Value::SortedSet(zset) => {
+            let mut members: Vec<_> = zset.iter().collect();
+            members.sort_by(|a, b| a.1.total_cmp(b.1).then_with(|| a.0.cmp(b.0)));
+            json_object(
+                members
+                    .into_iter()
+                    .map(|(member, score)| (member.as_str(), json_number(*score))),
+            )
+        }
+        Value::Hash(hash) => json_object(sorted(hash).map(|(field, v)| (field, json_string(v)))),
+        Value::Stream(stream) => json_stream(stream),
+    }
+}
+
+fn json_stream(stream: &Stream) -> String {
+    let entries = stream.entries.iter().map(|(id, fields)| {
+        let fields = fields
+            .iter()
+            .map(|(field, value)| (field.as_str(), json_string(value)));
+        json_object([
+            ("id", json_string(&format_id(*id))),
+            ("fields", json_object(fields)),
+        ])
+    });
+    let groups = stream.groups.iter().map(|group| {
+        let consumers = group
+            .consumers
+            .iter()
+            .map(|consumer| json_string(&consumer.name));
+        json_object([
+            ("name", json_string(&group.name)),
+            ("last_id", json_string(&format_id(group.last_id))),
+            ("pending", group.pending.len().to_string()),
+            ("consumers", json_array(consumers)),
+        ])
+    });
+    json_object([
+        ("last_id", json_string(&format_id(stream.last_id))),
+        ("entries", json_array(entries)),
+        ("groups", json_array(groups)),
+    ])
+}
+
+fn format_id(id: StreamId) -> String {
+    format!("{}-{}", id.ms, id.seq)
+}
+
+fn sorted(map: &HashMap<String, String>) -> impl Iterator<Item = (&str, &String)> {
+    let mut pairs: Vec<_> = map.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    pairs.sort();
+    pairs.into_iter()
+}
+
+/// JSON has no infinities, so they are written as strings like in ZSCORE
+/// replies.
+fn json_number(number: f64) -> String {
+    if number.is_finite() {
+        number.to_string()
+    } else if number > 0.0 {
+        "\"inf\"".to_owned()
+    } else {
+        "\"-inf\"".to_owned()
+    }
+}
+
+fn json_array(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+fn json_object<'a>(pairs: impl IntoIterator<Item = (&'a str, String)>) -> String {
+    let pairs: Vec<_> = pairs
+        .into_iter()
+        .map(|(key, value)| format!("{}:{value}", json_string(key)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Number of keys and bytes they take, estimated as the size of the key and
+/// of the value in its RDB encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    pub by_type: BTreeMap<&'static str, Usage>,
+    /// Keys grouped by the part before the first separator, or the whole key
+    /// when it has none.
+    pub by_prefix: BTreeMap<String, Usage>,
+}
+
+impl MemoryStats {
+    pub fn collect(file: &RdbFile, separator: &str) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        for database in file.databases.values() {
+            for (key, value) in &database.key_vals {
+                let bytes = (key.len() + dump_value(value).len() - DUMP_FOOTER_LEN) as u64;
+                let prefix = key.split(separator).next().unwrap_or(key);
+                for usage in [
+                    stats.by_type.entry(value.type_name()).or_default(),
+                    stats.by_prefix.entry(prefix.to_owned()).or_default(),
+                ] {
+                    usage.keys += 1;
+                    usage.bytes += bytes;
+                }
+            }
+        }
+        stats
+    }
+
+    /// Prints the usage per type, then the `top` prefixes taking the most
+    /// bytes.
+    pub fn write_report(&self, top: usize, mut writer: impl Write) -> Result<()> {
+        writeln!(writer, "{:<24} {:>10} {:>14}", "# type", "keys", "bytes")?;
+        for (name, usage) in &self.by_type {
+            writeln!(writer, "{name:<24} {:>10} {:>14}", usage.keys, usage.bytes)?;
+        }
+        let mut prefixes: Vec<_> = self.by_prefix.iter().collect();
+        prefixes.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(b.0)));
+        writeln!(writer)?;
+        writeln!(writer, "{:<24} {:>10} {:>14}", "# prefix", "keys", "bytes")?;
+        for (prefix, usage) in prefixes.into_iter().take(top) {
+            writeln!(
+                writer,
+                "{prefix:<24} {:>10} {:>14}",
+                usage.keys, usage.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::{
+        inspect::{write_json_lines, MemoryStats, Usage},
+        rdb::{RdbDatabase, RdbFile},
+        value::Value,
+    };
+
+    fn file() -> RdbFile {
+        let key_vals = [
+            ("user:1".to_owned(), Value::String("a\"b\n".to_owned())),
+            (
+                "user:2".to_owned(),
+                Value::SortedSet(HashMap::from([
+                    ("x".to_owned(), 2.5),
+                    ("y".to_owned(), f64::NEG_INFINITY),
+                ])),
+            ),
+            ("plain".to_owned(), Value::List(["1".to_owned()].into())),
+        ]
+        .map(|(key, value)| (key, Arc::new(value)));
+        let key_expires = HashMap::from([(
+            "user:1".to_owned(),
+            UNIX_EPOCH + Duration::from_millis(1700000000000),
+        )]);
+        RdbFile {
+            version: 11,
+            aux: vec![],
+            databases: BTreeMap::from([(
+                3,
+                RdbDatabase {
+                    key_vals: HashMap::from(key_vals),
+                    key_expires,
+                },
+            )]),
+            functions: vec![],
+        }
+    }
+
+    #[test]
+    fn should_export_keys_as_json_lines() {
+        let mut out = vec![];
+        write_json_lines(&file(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"db\":3,\"key\":\"plain\",\"type\":\"list\",\"expiry\":null,\"value\":[\"1\"]}\n\
+             {\"db\":3,\"key\":\"user:1\",\"type\":\"string\",\"expiry\":1700000000000,\"value\":\"a\\\"b\\n\"}\n\
+             {\"db\":3,\"key\":\"user:2\",\"type\":\"zset\",\"expiry\":null,\"value\":{\"y\":\"-inf\",\"x\":2.5}}\n"
+        );
+    }
+
+    #[test]
+    fn should_sum_usage_by_type_and_prefix() {
+        let stats = MemoryStats::collect(&file(), ":");
+        assert_eq!(stats.by_prefix["user"].keys, 2);
+        assert_eq!(stats.by_prefix["plain"].keys, 1);
+        assert_eq!(stats.by_type["string"].keys, 1);
+        // Key, type byte, length byte and the four bytes of the string.
+        assert_eq!(stats.by_type["string"].bytes, 6 + 1 + 1 + 4);
+        let total: u64 = stats
+            .by_type
+            .values()
+            .map(|usage: &Usage| usage.bytes)
+            .sum();
+        assert_eq!(
+            total,
+            stats.by_prefix.values().map(|usage| usage.bytes).sum()
+        );
+    }
+}
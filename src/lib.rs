@@ -1,6 +1,17 @@
+pub mod aof;
 pub mod config;
+pub mod functions;
+pub mod glob;
+pub mod inspect;
+pub mod listpack;
+pub mod notify;
 pub mod parser;
+pub mod persistence;
+pub mod pubsub;
 pub mod rdb;
+pub mod replication;
 pub mod request;
+pub mod scripting;
 pub mod slave;
 pub mod store;
+pub mod value;
@@ -0,0 +1,333 @@
+//! Decoders for the compact encodings Redis embeds in RDB files as strings:
+//! ziplists, listpacks, intsets and zipmaps. Integers are returned in their
+//! decimal form, the way Redis hands them to commands. Listpacks can also be
+//! encoded, since streams are always persisted as listpacks.
+
+use anyhow::{anyhow, Result};
+
+const ZIPLIST_END: u8 = 0xff;
+const LISTPACK_END: u8 = 0xff;
+const ZIPMAP_END: u8 = 0xff;
+
+/// Cursor over an encoded blob that fails instead of panicking when the
+/// blob is shorter than its headers claim.
+struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Bytes { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(anyhow!("encoded value is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or(anyhow!("encoded value is truncated"))
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.take(len).map(|_| ())
+    }
+
+    /// Little endian two's complement integer of `len` bytes.
+    fn int_le(&mut self, len: usize) -> Result<i64> {
+        let bytes = self.take(len)?;
+        let mut buf = [0; 8];
+        buf[..len].copy_from_slice(bytes);
+        let shift = 64 - 8 * len as u32;
+        Ok((i64::from_le_bytes(buf) << shift) >> shift)
+    }
+
+    fn u32_le(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+fn int_entry(value: i64) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+pub fn ziplist_entries(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut bytes = Bytes::new(data);
+    // zlbytes and zltail are only needed to walk the list backwards.
+    bytes.skip(8)?;
+    let len = u16::from_le_bytes(bytes.take(2)?.try_into().unwrap());
+    let mut entries = Vec::with_capacity(len as usize);
+    while bytes.peek()? != ZIPLIST_END {
+        let prev_len = bytes.byte()?;
+        if prev_len == 0xfe {
+            bytes.skip(4)?;
+        }
+        let encoding = bytes.byte()?;
+        let entry = match encoding >> 6 {
+            0 => bytes.take((encoding & 0x3f) as usize)?.to_vec(),
+            1 => {
+                let len = ((encoding & 0x3f) as usize) << 8 | bytes.byte()? as usize;
+                bytes.take(len)?.to_vec()
+            }
+            2 => {
+                let len = u32::from_be_bytes(bytes.take(4)?.try_into().unwrap());
+                bytes.take(len as usize)?.to_vec()
+            }
+            _ => match encoding {
+                0xc0 => int_entry(bytes.int_le(2)?),
+                0xd0 => int_entry(bytes.int_le(4)?),
+                0xe0 => int_entry(bytes.int_le(8)?),
+                0xf0 => int_entry(bytes.int_le(3)?),
+                0xfe => int_entry(bytes.int_le(1)?),
+                0xf1..=0xfd => int_entry((encoding & 0x0f) as i64 - 1),
+                _ => return Err(anyhow!("invalid ziplist encoding {encoding:#x}")),
+            },
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+pub fn listpack_entries(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut bytes = Bytes::new(data);
+    bytes.skip(4)?;
+    let len = u16::from_le_bytes(bytes.take(2)?.try_into().unwrap());
+    let mut entries = Vec::with_capacity(len as usize);
+    while bytes.peek()? != LISTPACK_END {
+        let start = bytes.pos;
+        let encoding = bytes.byte()?;
+        let entry = if encoding & 0x80 == 0 {
+            int_entry(encoding as i64)
+        } else if encoding & 0xc0 == 0x80 {
+            bytes.take((encoding & 0x3f) as usize)?.to_vec()
+        } else if encoding & 0xe0 == 0xc0 {
+            let value = ((encoding & 0x1f) as i64) << 8 | bytes.byte()? as i64;
+            int_entry((value << 51) >> 51)
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = ((encoding & 0x0f) as usize) << 8 | bytes.byte()? as usize;
+            bytes.take(len)?.to_vec()
+        } else {
+            match encoding {
+                0xf0 => {
+                    let len = bytes.u32_le()?;
+                    bytes.take(len as usize)?.to_vec()
+                }
+                0xf1 => int_entry(bytes.int_le(2)?),
+                0xf2 => int_entry(bytes.int_le(3)?),
+                0xf3 => int_entry(bytes.int_le(4)?),
+                0xf4 => int_entry(bytes.int_le(8)?),
+                _ => return Err(anyhow!("invalid listpack encoding {encoding:#x}")),
+            }
+        };
+        bytes.skip(backlen_size(bytes.pos - start))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Encodes items as a listpack, storing the ones that are canonical integers
+/// in the integer encodings like Redis does.
+pub fn encode_listpack<T: AsRef<[u8]>>(items: &[T]) -> Vec<u8> {
+    let mut body = vec![];
+    for item in items {
+        let start = body.len();
+        let item = item.as_ref();
+        match canonical_int(item) {
+            Some(int) => encode_int(&mut body, int),
+            None => encode_str(&mut body, item),
+        }
+        let len = body.len() - start;
+        encode_backlen(&mut body, len);
+    }
+    let total = 4 + 2 + body.len() + 1;
+    let mut listpack = (total as u32).to_le_bytes().to_vec();
+    let count = if items.len() < u16::MAX as usize {
+        items.len() as u16
+    } else {
+        u16::MAX
+    };
+    listpack.extend_from_slice(&count.to_le_bytes());
+    listpack.extend(body);
+    listpack.push(LISTPACK_END);
+    listpack
+}
+
+/// Integer value of `item` if converting it back gives the same bytes.
+fn canonical_int(item: &[u8]) -> Option<i64> {
+    let text = std::str::from_utf8(item).ok()?;
+    let int = text.parse::<i64>().ok()?;
+    (int.to_string() == text).then_some(int)
+}
+
+fn encode_int(buf: &mut Vec<u8>, int: i64) {
+    match int {
+        0..=127 => buf.push(int as u8),
+        -4096..=4095 => {
+            let value = (int as u16) & 0x1fff;
+            buf.push(0xc0 | (value >> 8) as u8);
+            buf.push(value as u8);
+        }
+        _ => {
+            let (encoding, width) = if i16::try_from(int).is_ok() {
+                (0xf1, 2)
+            } else if (-(1 << 23)..1 << 23).contains(&int) {
+                (0xf2, 3)
+            } else if i32::try_from(int).is_ok() {
+                (0xf3, 4)
+            } else {
+                (0xf4, 8)
+            };
+            buf.push(encoding);
+            buf.extend_from_slice(&int.to_le_bytes()[..width]);
+        }
+    }
+}
+
+fn encode_str(buf: &mut Vec<u8>, item: &[u8]) {
+    let len = item.len();
+    if len < 64 {
+        buf.push(0x80 | len as u8);
+    } else if len < 4096 {
+        buf.push(0xe0 | (len >> 8) as u8);
+        buf.push(len as u8);
+    } else {
+        buf.push(0xf0);
+        buf.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+    buf.extend_from_slice(item);
+}
+
+/// Writes the length of the previous entry so the listpack can be walked
+/// backwards: 7 bits per byte, most significant first, with the high bit set
+/// on every byte but the first.
+fn encode_backlen(buf: &mut Vec<u8>, len: usize) {
+    let size = backlen_size(len);
+    for i in (0..size).rev() {
+        let bits = ((len >> (7 * i)) & 0x7f) as u8;
+        buf.push(if i == size - 1 { bits } else { bits | 0x80 });
+    }
+}
+
+/// Number of bytes used by the back length that follows a listpack entry of
+/// `len` bytes.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+pub fn intset_entries(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut bytes = Bytes::new(data);
+    let width = bytes.u32_le()? as usize;
+    if ![2, 4, 8].contains(&width) {
+        return Err(anyhow!("invalid intset encoding {width}"));
+    }
+    let len = bytes.u32_le()?;
+    (0..len)
+        .map(|_| Ok(int_entry(bytes.int_le(width)?)))
+        .collect()
+}
+
+/// Entries of the zipmap encoding used for small hashes before Redis 2.6, as
+/// alternating fields and values.
+pub fn zipmap_entries(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut bytes = Bytes::new(data);
+    bytes.skip(1)?;
+    let mut entries = vec![];
+    while bytes.peek()? != ZIPMAP_END {
+        let field_len = zipmap_length(&mut bytes)?;
+        entries.push(bytes.take(field_len)?.to_vec());
+        let value_len = zipmap_length(&mut bytes)?;
+        let free = bytes.byte()?;
+        entries.push(bytes.take(value_len)?.to_vec());
+        bytes.skip(free as usize)?;
+    }
+    Ok(entries)
+}
+
+fn zipmap_length(bytes: &mut Bytes) -> Result<usize> {
+    match bytes.byte()? {
+        254 => Ok(bytes.u32_le()? as usize),
+        255 => Err(anyhow!("unexpected end of zipmap")),
+        len => Ok(len as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::listpack::{encode_listpack, intset_entries, listpack_entries, ziplist_entries};
+
+    fn strings(entries: Vec<Vec<u8>>) -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|entry| String::from_utf8(entry).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn should_decode_listpack() {
+        // "a", 7, -3 as a 13 bit int, 1000 as int16
+        let data = b"\x00\x00\x00\x00\x04\x00\x81a\x02\x07\x01\xdf\xfd\x02\xf1\xe8\x03\x03\xff";
+        assert_eq!(
+            strings(listpack_entries(data).unwrap()),
+            vec!["a", "7", "-3", "1000"]
+        );
+        assert!(listpack_entries(&data[..9]).is_err());
+    }
+
+    #[test]
+    fn should_round_trip_listpack() {
+        let items = [
+            "a",
+            "0",
+            "127",
+            "-3",
+            "4095",
+            "-32768",
+            "8000000",
+            "-2147483648",
+            "1e3",
+            "007",
+        ]
+        .map(|item| item.to_owned());
+        let mut long = items.to_vec();
+        long.push("x".repeat(200));
+        long.push("y".repeat(5000));
+        let encoded = encode_listpack(&long);
+        assert_eq!(strings(listpack_entries(&encoded).unwrap()), long);
+        assert_eq!(&encoded[..4], &(encoded.len() as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn should_decode_ziplist() {
+        // "ab", 5 as an immediate, -2 as int16
+        let data =
+            b"\x00\x00\x00\x00\x00\x00\x00\x00\x03\x00\x00\x02ab\x04\xf6\x02\xc0\xfe\xff\xff";
+        assert_eq!(
+            strings(ziplist_entries(data).unwrap()),
+            vec!["ab", "5", "-2"]
+        );
+    }
+
+    #[test]
+    fn should_decode_intset() {
+        let data = b"\x02\x00\x00\x00\x02\x00\x00\x00\xff\xff\x05\x00";
+        assert_eq!(strings(intset_entries(data).unwrap()), vec!["-1", "5"]);
+    }
+}
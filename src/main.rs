@@ -3,16 +3,17 @@ use std::sync::Arc;
 
 use anyhow::Ok;
 use bytes::BytesMut;
+use redis_starter_rust::aof::{aof_exists, open_aof, start_aof_cycle};
 use redis_starter_rust::config::{parse_args, SystemConfigArc};
-use redis_starter_rust::parser::{parse_redis_value, RedisValue};
-use redis_starter_rust::rdb::read_rdb_file;
+use redis_starter_rust::parser::{parse_next, RedisValue};
+use redis_starter_rust::persistence::start_save_cycle;
+use redis_starter_rust::rdb::write_rdb;
+use redis_starter_rust::replication::SyncPoint;
 use redis_starter_rust::request::{get_request, Request, RequestHandler};
-use redis_starter_rust::slave::start_slave_replica;
+use redis_starter_rust::slave::replicate;
 use redis_starter_rust::store::{Store, StoreArc};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::Sender;
 
 #[tokio::main]
 async fn main() {
@@ -22,96 +23,198 @@ async fn main() {
         .await
         .unwrap();
     println!("start listening on {}", config.get_port());
-    let store = Arc::new(Store::new());
-    load_rdb_file(config.clone(), store.clone()).await;
+    let store = Arc::new(Store::with_databases(config.get_databases()));
+    if config.is_appendonly() {
+        start_aof(config.clone(), store.clone()).await;
+    } else {
+        load_rdb_file(config.clone(), store.clone()).await;
+    }
+    store
+        .get_persistence()
+        .set_save_rules(&config.get_save_rules())
+        .unwrap();
+    start_save_cycle(store.clone(), config.get_rdb_path());
+    store
+        .get_replication()
+        .set_backlog_size(config.get_repl_backlog_size());
+    store
+        .get_replication()
+        .set_read_only(config.get_replica_read_only());
+    store.set_busy_reply_threshold(config.get_busy_reply_threshold());
 
     if config.get_replication_config().is_slave() {
-        tokio::spawn(start_slave_replica(store.clone(), config.clone()));
+        let (ip, port) = config.get_replication_config().get_ip_port();
+        replicate(&store, config.clone(), ip, port);
     }
-    let (sender, mut _rx) = broadcast::channel::<Request>(100);
     loop {
         let (socket, _) = listener.accept().await.unwrap();
         let store_c = store.clone();
         let config_c = config.clone();
-        let sender_c = sender.clone();
         tokio::spawn(async move {
-            handle_clinet(socket, store_c, config_c, sender_c).await;
+            handle_clinet(socket, store_c, config_c).await;
         });
     }
 }
 
 async fn load_rdb_file(config: SystemConfigArc, store: StoreArc) {
     let rdb_file_path = config.get_rdb_path();
-    if rdb_file_path.is_none() {
+    if !config.has_rdb_file() || File::open(&rdb_file_path).is_err() {
         return;
     }
-    if File::open(rdb_file_path.clone().unwrap()).is_err() {
-        return;
+    if let Err(e) = store.load_rdb_file(rdb_file_path).await {
+        eprintln!("failed to load the RDB file: {e}");
+        std::process::exit(1);
     }
-    let rdb_file = read_rdb_file(rdb_file_path.unwrap()).unwrap();
-    store.add_multiple_keys(rdb_file.key_vals).await;
-    store.set_multiple_expires(rdb_file.key_expires);
 }
 
-async fn handle_clinet(
-    mut stream: TcpStream,
-    store: StoreArc,
-    config: SystemConfigArc,
-    sender: Sender<Request>,
-) {
+/// Loads the AOF, which has every write since the dataset was created and
+/// so takes precedence over the RDB file. Without an AOF yet, the RDB file
+/// is loaded and becomes the base of a new AOF.
+async fn start_aof(config: SystemConfigArc, store: StoreArc) {
+    if !aof_exists(&config) {
+        load_rdb_file(config.clone(), store.clone()).await;
+    }
+    if let Err(e) = open_aof(&store, config).await {
+        eprintln!("failed to open the append only file: {e}");
+        std::process::exit(1);
+    }
+    start_aof_cycle(store);
+}
+
+async fn handle_clinet(mut stream: TcpStream, store: StoreArc, config: SystemConfigArc) {
     let mut buf = BytesMut::with_capacity(512);
-    let mut req_handler = RequestHandler::new(store, config.clone());
+    let mut req_handler = RequestHandler::new(store.clone(), config);
     loop {
-        let read_size = stream.read_buf(&mut buf).await.unwrap();
-        if read_size == 0 {
-            return;
-        }
-
-        let request = get_request(parse_redis_value(&mut buf).unwrap()).unwrap();
-        let response = req_handler.handle_request(request.clone()).await;
-        stream
-            .write_all(response.serialize().as_bytes())
-            .await
-            .unwrap();
-        if !config.get_replication_config().is_slave() {
-            manage_replica(request, &mut stream, sender.clone())
-                .await
-                .unwrap();
+        tokio::select! {
+            read_size = stream.read_buf(&mut buf) => {
+                if read_size.unwrap() == 0 {
+                    return;
+                }
+                loop {
+                    let value = match parse_next(&mut buf) {
+                        Result::Ok(Some(value)) => value,
+                        Result::Ok(None) => break,
+                        Err(e) => {
+                            let error = RedisValue::Error(format!("ERR Protocol error: {e}"));
+                            let _ = stream.write_all(&error.serialize()).await;
+                            return;
+                        }
+                    };
+                    let request = match get_request(value) {
+                        Result::Ok(request) => request,
+                        Err(e) => {
+                            let error = req_handler.handle_invalid_request(e);
+                            stream.write_all(&error.serialize()).await.unwrap();
+                            continue;
+                        }
+                    };
+                    if let Request::PSYNC(replid, offset) = request {
+                        // The connection now only carries the replication
+                        // stream.
+                        let port = req_handler.get_listening_port().unwrap_or_default();
+                        let psync = (replid.as_str(), offset);
+                        if let Err(e) = serve_replica(store, stream, buf, psync, &port).await {
+                            eprintln!("lost the connection to a replica: {e}");
+                        }
+                        return;
+                    }
+                    let responses = if request.is_subscription() {
+                        req_handler.handle_subscription(request)
+                    } else {
+                        vec![req_handler.handle_request(request).await]
+                    };
+                    for response in responses {
+                        stream
+                            .write_all(&response.serialize())
+                            .await
+                            .unwrap();
+                    }
+                }
+            }
+            Some(message) = req_handler.next_message() => {
+                stream
+                    .write_all(&message.serialize())
+                    .await
+                    .unwrap();
+            }
         }
     }
 }
 
-async fn manage_replica(
-    request: Request,
-    stream: &mut TcpStream,
-    sender: Sender<Request>,
+/// Continues the stream of a replica that asked for PSYNC from the backlog
+/// if possible. Otherwise sends it a snapshot of the dataset, then every
+/// write made since the snapshot was taken. Meanwhile the replica reports
+/// the offsets it applied with REPLCONF ACK.
+async fn serve_replica(
+    store: StoreArc,
+    mut stream: TcpStream,
+    mut buf: BytesMut,
+    (replid, offset): (&str, i64),
+    port: &str,
 ) -> anyhow::Result<()> {
-    match request {
-        Request::REPLCONF => {}
-        Request::PSYNC => {
-            let empty_rdb = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2").expect("could not decode");
+    let ip = stream.peer_addr()?.ip().to_string();
+    let (sync_point, mut link, snapshot) = {
+        let keyspace = store.lock().await;
+        let (sync_point, link) = store.get_replication().attach(replid, offset, &ip, port);
+        let snapshot = match sync_point {
+            SyncPoint::Full { .. } => Some(store.snapshot(&keyspace)),
+            SyncPoint::Partial { .. } => None,
+        };
+        (sync_point, link, snapshot)
+    };
+    match sync_point {
+        SyncPoint::Partial { replid, missing } => {
+            let reply = RedisValue::SimpleString(format!("CONTINUE {replid}"));
+            stream.write_all(&reply.serialize()).await?;
+            stream.write_all(&missing).await?;
+        }
+        SyncPoint::Full { replid, offset } => {
+            let reply = RedisValue::SimpleString(format!("FULLRESYNC {replid} {offset}"));
+            stream.write_all(&reply.serialize()).await?;
+            let snapshot = snapshot.unwrap();
+            let image = tokio::task::spawn_blocking(move || {
+                let mut image = vec![];
+                write_rdb(&snapshot, &mut image).map(|_| image)
+            })
+            .await??;
             stream
-                .write(format!("${}\r\n", empty_rdb.len()).as_bytes())
-                .await
-                .unwrap();
-            stream.write(empty_rdb.as_slice()).await?;
-            let mut receiver = sender.subscribe();
-            loop {
-                let req = receiver.recv().await?;
-                let command = match req {
-                    Request::Set(key, val, _) => RedisValue::make_bulk_array(vec!["SET".to_string(), key, val]),
-                    _ => panic!("not implemented"),
-                };
-                stream
-                    .write_all(command.serialize().as_bytes())
-                    .await
-                    .unwrap();
+                .write_all(format!("${}\r\n", image.len()).as_bytes())
+                .await?;
+            stream.write_all(&image).await?;
+        }
+    }
+    loop {
+        tokio::select! {
+            writes = link.writes.recv() => match writes {
+                Some(writes) => stream.write_all(&writes).await?,
+                None => return Ok(()),
+            },
+            read_size = stream.read_buf(&mut buf) => {
+                if read_size? == 0 {
+                    return Ok(());
+                }
+                while let Some(value) = parse_next(&mut buf)? {
+                    if let Result::Ok(Request::REPLCONF(options)) = get_request(value) {
+                        if let Some((offset, aof_offset)) = parse_ack(&options) {
+                            store.get_replication().ack(link.id, offset, aof_offset);
+                        }
+                    }
+                }
             }
         }
-        Request::Set(_, _, _) => {
-            sender.send(request)?;
+    }
+}
+
+/// Reads `ACK <offset>`, optionally followed by `FACK <aof offset>`.
+fn parse_ack(options: &[(String, String)]) -> Option<(u64, Option<u64>)> {
+    let mut offset = None;
+    let mut aof_offset = None;
+    for (option, value) in options {
+        match option.as_str() {
+            "ack" => offset = value.parse().ok(),
+            "fack" => aof_offset = value.parse().ok(),
+            _ => {}
         }
-        _ => {}
     }
-    Ok(())
+    Some((offset?, aof_offset))
 }
@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::{anyhow, Result};
+
+use crate::pubsub::PubSubArc;
+
+pub const KEYSPACE: u32 = 1 << 0;
+pub const KEYEVENT: u32 = 1 << 1;
+pub const GENERIC: u32 = 1 << 2;
+pub const STRING: u32 = 1 << 3;
+pub const LIST: u32 = 1 << 4;
+pub const SET: u32 = 1 << 5;
+pub const HASH: u32 = 1 << 6;
+pub const ZSET: u32 = 1 << 7;
+pub const EXPIRED: u32 = 1 << 8;
+pub const EVICTED: u32 = 1 << 9;
+pub const STREAM: u32 = 1 << 10;
+pub const KEY_MISS: u32 = 1 << 11;
+pub const MODULE: u32 = 1 << 12;
+pub const NEW: u32 = 1 << 13;
+/// What the `A` alias expands to; key-miss and new-key events are excluded
+/// like in Redis.
+pub const ALL: u32 =
+    GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+const CLASS_CHARS: [(char, u32); 13] = [
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('d', MODULE),
+    ('K', KEYSPACE),
+    ('E', KEYEVENT),
+    ('m', KEY_MISS),
+];
+
+/// Parses a `notify-keyspace-events` value such as `"KEA"` or `"Ex"`.
+pub fn parse_flags(flags: &str) -> Result<u32> {
+    let mut parsed = 0;
+    for c in flags.chars() {
+        parsed |= match c {
+            'A' => ALL,
+            'n' => NEW,
+            c => CLASS_CHARS
+                .iter()
+                .find(|(flag, _)| *flag == c)
+                .map(|(_, class)| *class)
+                .ok_or(anyhow!("Invalid event class character '{c}'"))?,
+        };
+    }
+    Ok(parsed)
+}
+
+/// Inverse of [`parse_flags`], collapsing the full set of classes into `A`.
+pub fn flags_to_string(flags: u32) -> String {
+    let mut res = String::new();
+    if flags & ALL == ALL {
+        res.push('A');
+    }
+    for (c, class) in CLASS_CHARS {
+        let is_type_class = class & ALL != 0;
+        if flags & class != 0 && !(is_type_class && flags & ALL == ALL) {
+            res.push(c);
+        }
+    }
+    if flags & NEW != 0 {
+        res.push('n');
+    }
+    res
+}
+
+/// Publishes keyspace (`__keyspace@<db>__:<key>`) and keyevent
+/// (`__keyevent@<db>__:<event>`) messages for the enabled event classes.
+pub struct Notifier {
+    pubsub: PubSubArc,
+    flags: AtomicU32,
+}
+
+impl Notifier {
+    pub fn new(pubsub: PubSubArc) -> Self {
+        Notifier {
+            pubsub,
+            flags: AtomicU32::new(0),
+        }
+    }
+
+    pub fn get_pubsub(&self) -> PubSubArc {
+        self.pubsub.clone()
+    }
+
+    pub fn get_flags(&self) -> u32 {
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    pub fn set_flags(&self, flags: u32) {
+        self.flags.store(flags, Ordering::Relaxed);
+    }
+
+    pub fn notify(&self, class: u32, event: &str, key: &str, db: usize) {
+        let flags = self.get_flags();
+        if flags & class == 0 {
+            return;
+        }
+        if flags & KEYSPACE != 0 {
+            self.pubsub
+                .publish(&format!("__keyspace@{db}__:{key}"), event);
+        }
+        if flags & KEYEVENT != 0 {
+            self.pubsub
+                .publish(&format!("__keyevent@{db}__:{event}"), key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notify::{flags_to_string, parse_flags, ALL, EXPIRED, KEYEVENT, KEYSPACE};
+
+    #[test]
+    fn should_parse_flags() {
+        assert_eq!(parse_flags("Ex").unwrap(), KEYEVENT | EXPIRED);
+        assert_eq!(parse_flags("KEA").unwrap(), KEYSPACE | KEYEVENT | ALL);
+        assert!(parse_flags("Q").is_err());
+    }
+
+    #[test]
+    fn should_format_flags() {
+        assert_eq!(flags_to_string(parse_flags("KEA").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("xE").unwrap()), "xE");
+        assert_eq!(flags_to_string(0), "");
+    }
+}
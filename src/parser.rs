@@ -8,6 +8,12 @@ pub enum RedisValue {
     SimpleString(String),
     BulkString(String),
     Array(Vec<RedisValue>),
+    Integer(i64),
+    Error(String),
+    NullBulkString,
+    NullArray,
+    /// Bulk string whose content is not valid UTF-8, like DUMP payloads.
+    BulkBytes(Vec<u8>),
 }
 
 impl RedisValue {
@@ -17,28 +23,41 @@ impl RedisValue {
             _ => Err(anyhow!("value is not bulkstring")),
         }
     }
-    
+
+    /// Content of a bulk string, whether or not it is valid UTF-8.
+    pub fn get_bytes(&self) -> Result<Vec<u8>> {
+        match self {
+            RedisValue::BulkString(s) => Ok(s.as_bytes().to_vec()),
+            RedisValue::BulkBytes(b) => Ok(b.clone()),
+            _ => Err(anyhow!("value is not bulkstring")),
+        }
+    }
+
     pub fn make_bulk_array(strs: Vec<String>) -> RedisValue {
-        let bulk_arr = strs
-            .into_iter()
-            .map(|str| RedisValue::BulkString(str))
-            .collect();
+        let bulk_arr = strs.into_iter().map(RedisValue::BulkString).collect();
         RedisValue::Array(bulk_arr)
     }
 
-    pub fn serialize(&self) -> String {
+    pub fn serialize(&self) -> Vec<u8> {
         match self {
-            RedisValue::SimpleString(s) => format!("+{}\r\n", s),
-            RedisValue::BulkString(s) => {
-                if s.is_empty() {
-                    return format!("$-1\r\n");
+            RedisValue::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+            RedisValue::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
+            RedisValue::Array(arr) => {
+                let mut serialized = format!("*{}\r\n", arr.len()).into_bytes();
+                for val in arr {
+                    serialized.extend(val.serialize());
                 }
-                format!("${}\r\n{}\r\n", s.len(), s)
+                serialized
             }
-            RedisValue::Array(arr) => {
-                let values_serialized: Vec<String> =
-                    arr.iter().map(|val| val.serialize()).collect();
-                format!("*{}\r\n{}", arr.len(), values_serialized.concat())
+            RedisValue::Integer(i) => format!(":{}\r\n", i).into_bytes(),
+            RedisValue::Error(e) => format!("-{}\r\n", e).into_bytes(),
+            RedisValue::NullBulkString => b"$-1\r\n".to_vec(),
+            RedisValue::NullArray => b"*-1\r\n".to_vec(),
+            RedisValue::BulkBytes(b) => {
+                let mut serialized = format!("${}\r\n", b.len()).into_bytes();
+                serialized.extend_from_slice(b);
+                serialized.extend_from_slice(CRLF);
+                serialized
             }
         }
     }
@@ -49,10 +68,81 @@ pub fn parse_redis_value(buffer: &mut BytesMut) -> Result<RedisValue> {
         '*' => parse_array(buffer),
         '$' => parse_bulk_string(buffer),
         '+' => parse_simple_string(buffer),
+        ':' => parse_integer(buffer),
+        '-' => parse_error(buffer),
+        _ => Err(anyhow!("improper RESP format")),
+    }
+}
+
+/// Parses the next value only if the buffer holds all of its bytes, so partial
+/// reads and pipelined commands can be handled by the caller's read loop.
+pub fn parse_next(buffer: &mut BytesMut) -> Result<Option<RedisValue>> {
+    if buffer.is_empty() || frame_length(buffer, 0)?.is_none() {
+        return Ok(None);
+    }
+    parse_redis_value(buffer).map(Some)
+}
+
+/// Length in bytes of the complete value starting at `start`, or `None` if
+/// more bytes are needed.
+pub fn frame_length(buffer: &[u8], start: usize) -> Result<Option<usize>> {
+    let Some(line_end) = find_crlf(buffer, start) else {
+        return Ok(None);
+    };
+    let header_len = line_end + 2 - start;
+    match buffer[start] as char {
+        '+' | '-' | ':' => Ok(Some(header_len)),
+        '$' => {
+            let len = parse_int_slice(&buffer[start + 1..line_end])?;
+            if len < 0 {
+                return Ok(Some(header_len));
+            }
+            let total = header_len + len as usize + 2;
+            Ok((buffer.len() >= start + total).then_some(total))
+        }
+        '*' => {
+            let len = parse_int_slice(&buffer[start + 1..line_end])?;
+            let mut total = header_len;
+            for _ in 0..len.max(0) {
+                match frame_length(buffer, start + total)? {
+                    Some(item_len) => total += item_len,
+                    None => return Ok(None),
+                }
+            }
+            Ok(Some(total))
+        }
         _ => Err(anyhow!("improper RESP format")),
     }
 }
 
+fn find_crlf(buffer: &[u8], start: usize) -> Option<usize> {
+    if start >= buffer.len() {
+        return None;
+    }
+    buffer[start..]
+        .windows(2)
+        .position(|bytes| bytes == CRLF)
+        .map(|pos| start + pos)
+}
+
+fn parse_int_slice(bytes: &[u8]) -> Result<i64> {
+    Ok(std::str::from_utf8(bytes)?.parse::<i64>()?)
+}
+
+fn parse_integer(buffer: &mut BytesMut) -> Result<RedisValue> {
+    assert_eq!(buffer[0] as char, ':');
+    buffer.advance(1);
+    let val = split_by_next_crlf(buffer).ok_or(anyhow!("incomplete integer"))?;
+    Ok(RedisValue::Integer(parse_int(val)?))
+}
+
+fn parse_error(buffer: &mut BytesMut) -> Result<RedisValue> {
+    assert_eq!(buffer[0] as char, '-');
+    buffer.advance(1);
+    let val = split_by_next_crlf(buffer).ok_or(anyhow!("incomplete error"))?;
+    Ok(RedisValue::Error(String::from_utf8(val.to_vec())?))
+}
+
 fn parse_simple_string(buffer: &mut BytesMut) -> Result<RedisValue> {
     assert_eq!(buffer[0] as char, '+');
     buffer.advance(1);
@@ -65,7 +155,10 @@ fn parse_simple_string(buffer: &mut BytesMut) -> Result<RedisValue> {
 fn parse_array(buffer: &mut BytesMut) -> Result<RedisValue> {
     assert_eq!(buffer[0] as char, '*');
     buffer.advance(1);
-    let len = parse_int(split_by_next_crlf(buffer).unwrap())? as usize;
+    let len = parse_int(split_by_next_crlf(buffer).unwrap())?;
+    if len < 0 {
+        return Ok(RedisValue::NullArray);
+    }
     let vals = (0..len)
         .map(|_| parse_redis_value(buffer))
         .collect::<Result<_>>()?;
@@ -75,10 +168,17 @@ fn parse_array(buffer: &mut BytesMut) -> Result<RedisValue> {
 fn parse_bulk_string(buffer: &mut BytesMut) -> Result<RedisValue> {
     assert_eq!(buffer[0] as char, '$');
     buffer.advance(1);
-    let len = parse_int(split_by_next_crlf(buffer).unwrap())? as usize;
-    let message = String::from_utf8(buffer.split_to(len).to_vec())?;
+    let len = parse_int(split_by_next_crlf(buffer).unwrap())?;
+    if len < 0 {
+        return Ok(RedisValue::NullBulkString);
+    }
+    let len = len as usize;
+    let message = buffer.split_to(len).to_vec();
     buffer.advance(2);
-    Ok(RedisValue::BulkString(message))
+    match String::from_utf8(message) {
+        Ok(message) => Ok(RedisValue::BulkString(message)),
+        Err(e) => Ok(RedisValue::BulkBytes(e.into_bytes())),
+    }
 }
 
 fn parse_int(buffer: BytesMut) -> Result<i64> {
@@ -97,7 +197,7 @@ fn split_by_next_crlf(buffer: &mut BytesMut) -> Option<BytesMut> {
 
 #[cfg(test)]
 mod test {
-    use crate::parser::{parse_redis_value, RedisValue};
+    use crate::parser::{parse_next, parse_redis_value, RedisValue};
     use bytes::BytesMut;
 
     #[test]
@@ -124,6 +224,60 @@ mod test {
         );
     }
 
+    #[test]
+    fn should_parse_integer_and_error() {
+        assert_eq!(
+            parse_redis_value(&mut BytesMut::from(":42\r\n")).unwrap(),
+            RedisValue::Integer(42)
+        );
+        assert_eq!(
+            parse_redis_value(&mut BytesMut::from("-ERR oops\r\n")).unwrap(),
+            RedisValue::Error("ERR oops".to_string())
+        );
+    }
+
+    #[test]
+    fn should_wait_for_complete_frame() {
+        let mut buf = BytesMut::from("*2\r\n$4\r\necho\r\n$5\r\nhel");
+        assert_eq!(parse_next(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"lo\r\n*1\r\n$4\r\nping\r\n");
+        assert_eq!(
+            parse_next(&mut buf).unwrap(),
+            Some(RedisValue::make_bulk_array(vec![
+                "echo".to_string(),
+                "hello".to_string()
+            ]))
+        );
+        assert_eq!(
+            parse_next(&mut buf).unwrap(),
+            Some(RedisValue::make_bulk_array(vec!["ping".to_string()]))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn should_round_trip_null_and_empty_bulk_strings() {
+        assert_eq!(
+            RedisValue::BulkString(String::new()).serialize(),
+            b"$0\r\n\r\n"
+        );
+        assert_eq!(
+            parse_redis_value(&mut BytesMut::from("$-1\r\n")).unwrap(),
+            RedisValue::NullBulkString
+        );
+        assert_eq!(
+            parse_redis_value(&mut BytesMut::from("*-1\r\n")).unwrap(),
+            RedisValue::NullArray
+        );
+    }
+
+    #[test]
+    fn should_keep_binary_bulk_strings() {
+        let value = RedisValue::BulkBytes(vec![0xff, 0x00, b'a']);
+        let mut buf = BytesMut::from(value.serialize().as_slice());
+        assert_eq!(parse_redis_value(&mut buf).unwrap(), value);
+    }
+
     #[test]
     fn should_parse_array() {
         let array = RedisValue::Array(vec![
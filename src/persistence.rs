@@ -0,0 +1,227 @@
+//! Snapshots of the dataset taken with SAVE, BGSAVE and the
+//! `save <seconds> <changes>` rules.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::time::sleep;
+
+use crate::{
+    rdb::{write_rdb_file, RdbFile},
+    store::StoreArc,
+};
+
+const SAVE_CYCLE_PERIOD: Duration = Duration::from_millis(100);
+/// Seconds to wait before retrying after a failed background save.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+pub const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+
+pub struct Persistence {
+    /// Changes made since the last successful save.
+    dirty: AtomicU64,
+    /// Value of `dirty` when the running background save took its snapshot.
+    dirty_at_bgsave: AtomicU64,
+    /// Unix time in seconds of the last successful save.
+    last_save: AtomicU64,
+    last_bgsave_try: AtomicU64,
+    last_bgsave_ok: AtomicBool,
+    bgsave_in_progress: AtomicBool,
+    /// Pairs of seconds and changes after which a background save starts.
+    save_rules: Mutex<Vec<(u64, u64)>>,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Persistence {
+            dirty: AtomicU64::new(0),
+            dirty_at_bgsave: AtomicU64::new(0),
+            last_save: AtomicU64::new(now()),
+            last_bgsave_try: AtomicU64::new(0),
+            last_bgsave_ok: AtomicBool::new(true),
+            bgsave_in_progress: AtomicBool::new(false),
+            save_rules: Mutex::new(parse_save_rules(DEFAULT_SAVE_RULES).unwrap()),
+        }
+    }
+
+    pub fn add_changes(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::SeqCst);
+    }
+
+    pub fn get_dirty(&self) -> u64 {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    /// Forgets the changes made while loading the dataset on startup.
+    pub fn reset_dirty(&self) {
+        self.dirty.store(0, Ordering::SeqCst);
+    }
+
+    pub fn get_last_save(&self) -> u64 {
+        self.last_save.load(Ordering::SeqCst)
+    }
+
+    pub fn is_bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress.load(Ordering::SeqCst)
+    }
+
+    pub fn get_save_rules(&self) -> String {
+        let rules = self.save_rules.lock().unwrap();
+        rules
+            .iter()
+            .map(|(seconds, changes)| format!("{seconds} {changes}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Replaces the rules with the ones in `rules`. An empty string disables
+    /// automatic saving.
+    pub fn set_save_rules(&self, rules: &str) -> Result<()> {
+        *self.save_rules.lock().unwrap() = parse_save_rules(rules)?;
+        Ok(())
+    }
+
+    /// Writes `file` while the caller keeps the keyspace locked.
+    pub fn save(&self, file: &RdbFile, path: &str) -> Result<()> {
+        if self.is_bgsave_in_progress() {
+            return Err(anyhow!("Background save already in progress"));
+        }
+        write_rdb_file(path, file)?;
+        self.dirty.store(0, Ordering::SeqCst);
+        self.last_save.store(now(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Writes `file` on a blocking thread. The snapshot shares its values
+    /// with the keyspace, so clients are only stalled while the keys are
+    /// collected. Changes made after the snapshot stay dirty.
+    pub fn background_save(self: &Arc<Self>, file: RdbFile, path: String) -> Result<()> {
+        if self
+            .bgsave_in_progress
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(anyhow!("Background save already in progress"));
+        }
+        self.dirty_at_bgsave
+            .store(self.get_dirty(), Ordering::SeqCst);
+        self.last_bgsave_try.store(now(), Ordering::SeqCst);
+        let persistence = self.clone();
+        tokio::task::spawn_blocking(move || {
+            match write_rdb_file(&path, &file) {
+                Ok(()) => {
+                    let saved = persistence.dirty_at_bgsave.load(Ordering::SeqCst);
+                    persistence.dirty.fetch_sub(saved, Ordering::SeqCst);
+                    persistence.last_save.store(now(), Ordering::SeqCst);
+                    persistence.last_bgsave_ok.store(true, Ordering::SeqCst);
+                }
+                Err(e) => {
+                    eprintln!("background saving failed: {e}");
+                    persistence.last_bgsave_ok.store(false, Ordering::SeqCst);
+                }
+            }
+            persistence
+                .bgsave_in_progress
+                .store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    /// Whether a save rule is met. After a failed background save, retries
+    /// wait a few seconds so a full disk is not hammered.
+    fn should_save(&self) -> bool {
+        let now = now();
+        if self.is_bgsave_in_progress()
+            || (!self.last_bgsave_ok.load(Ordering::SeqCst)
+                && now < self.last_bgsave_try.load(Ordering::SeqCst) + BGSAVE_RETRY_DELAY)
+        {
+            return false;
+        }
+        let dirty = self.get_dirty();
+        let elapsed = now.saturating_sub(self.get_last_save());
+        let rules = self.save_rules.lock().unwrap();
+        rules
+            .iter()
+            .any(|(seconds, changes)| dirty >= *changes && elapsed >= *seconds)
+    }
+}
+
+/// Parses pairs of `<seconds> <changes>`, as in `"3600 1 300 100"`.
+pub fn parse_save_rules(rules: &str) -> Result<Vec<(u64, u64)>> {
+    let numbers = rules
+        .split_whitespace()
+        .map(|number| {
+            number
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Invalid save parameters"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if numbers.len() & 1 == 1 {
+        return Err(anyhow!("Invalid save parameters"));
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// Starts a background save to `path` whenever a save rule is met.
+pub fn start_save_cycle(store: StoreArc, path: String) {
+    tokio::spawn(async move {
+        loop {
+            sleep(SAVE_CYCLE_PERIOD).await;
+            let persistence = store.get_persistence();
+            if !persistence.should_save() {
+                continue;
+            }
+            let keyspace = store.lock().await;
+            let file = store.snapshot(&keyspace);
+            if let Err(e) = persistence.background_save(file, path.clone()) {
+                eprintln!("background saving failed: {e}");
+            }
+        }
+    });
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::persistence::{parse_save_rules, Persistence};
+
+    #[test]
+    fn should_parse_save_rules() {
+        assert_eq!(
+            parse_save_rules("900 1 300 10").unwrap(),
+            vec![(900, 1), (300, 10)]
+        );
+        assert!(parse_save_rules("").unwrap().is_empty());
+        assert!(parse_save_rules("900").is_err());
+        assert!(parse_save_rules("900 x").is_err());
+    }
+
+    #[test]
+    fn should_save_when_a_rule_is_met() {
+        let persistence = Persistence::new();
+        persistence.set_save_rules("0 2").unwrap();
+        persistence.add_changes(1);
+        assert!(!persistence.should_save());
+        persistence.add_changes(1);
+        assert!(persistence.should_save());
+        persistence.set_save_rules("").unwrap();
+        assert!(!persistence.should_save());
+        assert_eq!(persistence.get_save_rules(), "");
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{glob::glob_match, parser::RedisValue};
+
+pub type PubSubArc = Arc<PubSub>;
+
+/// Registry of channel and pattern subscriptions shared by all connections.
+/// Messages are pushed to subscribers as ready to send RESP values.
+pub struct PubSub {
+    next_id: AtomicU64,
+    channels: Mutex<HashMap<String, HashMap<u64, UnboundedSender<RedisValue>>>>,
+    patterns: Mutex<HashMap<String, HashMap<u64, UnboundedSender<RedisValue>>>>,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        PubSub {
+            next_id: AtomicU64::new(0),
+            channels: Mutex::new(HashMap::new()),
+            patterns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Delivers `message` to every subscriber of `channel` and returns how
+    /// many subscribers received it.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut receivers = 0;
+        if let Some(subscribers) = self.channels.lock().unwrap().get(channel) {
+            for sender in subscribers.values() {
+                let msg = RedisValue::make_bulk_array(vec![
+                    "message".to_owned(),
+                    channel.to_owned(),
+                    message.to_owned(),
+                ]);
+                if sender.send(msg).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        for (pattern, subscribers) in self.patterns.lock().unwrap().iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for sender in subscribers.values() {
+                let msg = RedisValue::make_bulk_array(vec![
+                    "pmessage".to_owned(),
+                    pattern.clone(),
+                    channel.to_owned(),
+                    message.to_owned(),
+                ]);
+                if sender.send(msg).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        receivers
+    }
+
+    fn add(
+        registry: &Mutex<HashMap<String, HashMap<u64, UnboundedSender<RedisValue>>>>,
+        name: &str,
+        id: u64,
+        sender: UnboundedSender<RedisValue>,
+    ) {
+        registry
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .insert(id, sender);
+    }
+
+    fn remove(
+        registry: &Mutex<HashMap<String, HashMap<u64, UnboundedSender<RedisValue>>>>,
+        name: &str,
+        id: u64,
+    ) {
+        let mut registry = registry.lock().unwrap();
+        if let Some(subscribers) = registry.get_mut(name) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                registry.remove(name);
+            }
+        }
+    }
+}
+
+/// Per connection subscription state. Dropping it removes every
+/// subscription from the shared registry.
+pub struct Subscriber {
+    id: u64,
+    pubsub: PubSubArc,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    sender: UnboundedSender<RedisValue>,
+    receiver: UnboundedReceiver<RedisValue>,
+}
+
+impl Subscriber {
+    pub fn new(pubsub: PubSubArc) -> Self {
+        let (sender, receiver) = unbounded_channel();
+        Subscriber {
+            id: pubsub.next_id.fetch_add(1, Ordering::Relaxed),
+            pubsub,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            sender,
+            receiver,
+        }
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty()
+    }
+
+    fn count(&self) -> i64 {
+        (self.channels.len() + self.patterns.len()) as i64
+    }
+
+    fn confirmation(&self, kind: &str, name: Option<String>) -> RedisValue {
+        RedisValue::Array(vec![
+            RedisValue::BulkString(kind.to_owned()),
+            name.map_or(RedisValue::NullBulkString, RedisValue::BulkString),
+            RedisValue::Integer(self.count()),
+        ])
+    }
+
+    pub fn subscribe(&mut self, channels: Vec<String>) -> Vec<RedisValue> {
+        let mut replies = vec![];
+        for channel in channels {
+            if self.channels.insert(channel.clone()) {
+                PubSub::add(
+                    &self.pubsub.channels,
+                    &channel,
+                    self.id,
+                    self.sender.clone(),
+                );
+            }
+            replies.push(self.confirmation("subscribe", Some(channel)));
+        }
+        replies
+    }
+
+    pub fn psubscribe(&mut self, patterns: Vec<String>) -> Vec<RedisValue> {
+        let mut replies = vec![];
+        for pattern in patterns {
+            if self.patterns.insert(pattern.clone()) {
+                PubSub::add(
+                    &self.pubsub.patterns,
+                    &pattern,
+                    self.id,
+                    self.sender.clone(),
+                );
+            }
+            replies.push(self.confirmation("psubscribe", Some(pattern)));
+        }
+        replies
+    }
+
+    /// Unsubscribes from the given channels, or from all of them when empty.
+    pub fn unsubscribe(&mut self, channels: Vec<String>) -> Vec<RedisValue> {
+        let channels = if channels.is_empty() {
+            self.channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            return vec![self.confirmation("unsubscribe", None)];
+        }
+        let mut replies = vec![];
+        for channel in channels {
+            if self.channels.remove(&channel) {
+                PubSub::remove(&self.pubsub.channels, &channel, self.id);
+            }
+            replies.push(self.confirmation("unsubscribe", Some(channel)));
+        }
+        replies
+    }
+
+    /// Unsubscribes from the given patterns, or from all of them when empty.
+    pub fn punsubscribe(&mut self, patterns: Vec<String>) -> Vec<RedisValue> {
+        let patterns = if patterns.is_empty() {
+            self.patterns.iter().cloned().collect()
+        } else {
+            patterns
+        };
+        if patterns.is_empty() {
+            return vec![self.confirmation("punsubscribe", None)];
+        }
+        let mut replies = vec![];
+        for pattern in patterns {
+            if self.patterns.remove(&pattern) {
+                PubSub::remove(&self.pubsub.patterns, &pattern, self.id);
+            }
+            replies.push(self.confirmation("punsubscribe", Some(pattern)));
+        }
+        replies
+    }
+
+    /// Waits for the next message published to one of our subscriptions.
+    pub async fn recv(&mut self) -> Option<RedisValue> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for channel in &self.channels {
+            PubSub::remove(&self.pubsub.channels, channel, self.id);
+        }
+        for pattern in &self.patterns {
+            PubSub::remove(&self.pubsub.patterns, pattern, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        parser::RedisValue,
+        pubsub::{PubSub, Subscriber},
+    };
+
+    #[tokio::test]
+    async fn should_deliver_to_channel_and_pattern_subscribers() {
+        let pubsub = Arc::new(PubSub::new());
+        let mut sub = Subscriber::new(pubsub.clone());
+        sub.subscribe(vec!["news".to_owned()]);
+        sub.psubscribe(vec!["n*".to_owned()]);
+
+        assert_eq!(pubsub.publish("news", "hi"), 2);
+        assert_eq!(
+            sub.recv().await.unwrap(),
+            RedisValue::make_bulk_array(vec![
+                "message".to_owned(),
+                "news".to_owned(),
+                "hi".to_owned()
+            ])
+        );
+        assert_eq!(
+            sub.recv().await.unwrap(),
+            RedisValue::make_bulk_array(vec![
+                "pmessage".to_owned(),
+                "n*".to_owned(),
+                "news".to_owned(),
+                "hi".to_owned()
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn should_stop_delivering_after_drop() {
+        let pubsub = Arc::new(PubSub::new());
+        let mut sub = Subscriber::new(pubsub.clone());
+        sub.subscribe(vec!["news".to_owned()]);
+        drop(sub);
+        assert_eq!(pubsub.publish("news", "hi"), 0);
+    }
+}
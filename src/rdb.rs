@@ -1,21 +1,117 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{self, BufRead, Read},
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, Ok, Result};
+use bytes::{Bytes, BytesMut};
+use tokio::{io::AsyncRead, sync::mpsc};
+
+use crate::{
+    listpack,
+    value::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId, Value},
+};
 
 const RDB_MAGIC: &str = "REDIS";
-const STRING_VALUE: u8 = 0;
+/// Version written into DUMP payloads, matching Redis 7.
+pub const RDB_VERSION: u16 = 11;
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE: u8 = 6;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+/// Quicklist 2 node containers.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+/// Flags of the entries inside stream listpacks.
+const STREAM_ITEM_DELETED: i64 = 1;
+const STREAM_ITEM_SAME_FIELDS: i64 = 2;
+const FUNCTION2: u8 = 0xf5;
 const EXPIRE_S: u8 = 0xfd;
 const EXPIRE_MS: u8 = 0xfc;
+const RESIZE_DB: u8 = 0xfb;
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+const SELECT_DB: u8 = 0xfe;
+const AUX: u8 = 0xfa;
+const MODULE_AUX: u8 = 0xf7;
+const IDLE: u8 = 0xf8;
+const FREQ: u8 = 0xf9;
+const FIRST_VERSION_WITH_CHECKSUM: u32 = 5;
+const EOF: u8 = 0xff;
+/// Most elements Redis puts in one quicklist node or stream listpack.
+const QUICKLIST_NODE_MAX_ENTRIES: usize = 128;
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+const CRC64_TABLE: [u64; 256] = make_crc64_table();
+
+/// Reflected polynomial of the CRC-64/Jones variant used by Redis.
+const fn make_crc64_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x95ac9329ac4bc9b5
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        crc = CRC64_TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
 
 #[derive(Debug, PartialEq)]
 pub struct RdbFile {
-    pub key_vals: HashMap<String, String>,
-    pub key_expires: HashMap<String, Duration>,
+    pub version: u32,
+    /// Auxiliary fields such as redis-ver and ctime, in file order.
+    pub aux: Vec<(String, String)>,
+    /// Contents of each non-empty database, by index.
+    pub databases: BTreeMap<usize, RdbDatabase>,
+    /// Codes of the function libraries.
+    pub functions: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct RdbDatabase {
+    /// Values shared with the keyspace a snapshot was taken from.
+    pub key_vals: HashMap<String, Arc<Value>>,
+    pub key_expires: HashMap<String, SystemTime>,
 }
 
 pub fn read_rdb_file(path: String) -> Result<RdbFile> {
@@ -24,111 +120,1447 @@ pub fn read_rdb_file(path: String) -> Result<RdbFile> {
     parse(reader)
 }
 
-fn parse(mut reader: impl BufRead) -> Result<RdbFile> {
-    read_header(&mut reader)?;
+/// Error of an RDB image that could not be parsed, with the byte offset at
+/// which parsing stopped.
+#[derive(Debug)]
+pub struct RdbError {
+    pub offset: u64,
+    /// Why parsing stopped, or `None` when the image ended too early.
+    pub cause: Option<String>,
+}
+
+impl Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cause {
+            None => write!(f, "RDB file is truncated at offset {}", self.offset),
+            Some(cause) => write!(f, "RDB file is corrupt at offset {}: {cause}", self.offset),
+        }
+    }
+}
+
+impl std::error::Error for RdbError {}
+
+/// Reader that keeps the offset and the running checksum of what was read,
+/// so errors can say where the file is broken.
+struct RdbReader<R> {
+    inner: R,
+    offset: u64,
+    crc: u64,
+}
+
+impl<R: Read> Read for RdbReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.crc = crc64(self.crc, &buf[..len]);
+        self.offset += len as u64;
+        io::Result::Ok(len)
+    }
+}
+
+/// A section of an RDB image, handed out as soon as it is parsed so large
+/// images can be loaded without holding them in memory.
+#[derive(Debug, PartialEq)]
+pub enum RdbItem {
+    Aux(String, String),
+    Function(String),
+    /// Announced size of a database, to reserve room for its keys.
+    ResizeDb(usize, u64),
+    /// A key that has not expired, with its database and deadline.
+    Key(usize, String, Value, Option<SystemTime>),
+}
+
+/// Parses an RDB image up to its checksum, leaving `reader` right after it
+/// so an AOF preamble can be followed by commands.
+pub fn parse(reader: impl Read) -> Result<RdbFile> {
+    let mut file = RdbFile {
+        version: 0,
+        aux: vec![],
+        databases: BTreeMap::new(),
+        functions: vec![],
+    };
+    file.version = parse_with(reader, |item| {
+        match item {
+            RdbItem::Aux(key, value) => file.aux.push((key, value)),
+            RdbItem::Function(code) => file.functions.push(code),
+            RdbItem::ResizeDb(db, size) => {
+                let database = file.databases.entry(db).or_default();
+                database.key_vals.reserve(size.min(1 << 20) as usize);
+            }
+            RdbItem::Key(db, key, value, expire_time) => {
+                let database = file.databases.entry(db).or_default();
+                if let Some(expire_time) = expire_time {
+                    database.key_expires.insert(key.clone(), expire_time);
+                }
+                database.key_vals.insert(key, Arc::new(value));
+            }
+        }
+        Ok(())
+    })?;
+    file.databases
+        .retain(|_, database| !database.key_vals.is_empty());
+    Ok(file)
+}
+
+/// Parses an RDB image, passing each section to `sink` as it is read, and
+/// returns the RDB version. Errors from `sink` stop the parsing.
+pub fn parse_with(reader: impl Read, mut sink: impl FnMut(RdbItem) -> Result<()>) -> Result<u32> {
+    let mut reader = RdbReader {
+        inner: reader,
+        offset: 0,
+        crc: 0,
+    };
+    parse_sections(&mut reader, &mut sink).map_err(|e| {
+        let truncated = e
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof);
+        RdbError {
+            offset: reader.offset,
+            cause: (!truncated).then(|| e.to_string()),
+        }
+        .into()
+    })
+}
+
+fn parse_sections<R: Read>(
+    reader: &mut RdbReader<R>,
+    sink: &mut impl FnMut(RdbItem) -> Result<()>,
+) -> Result<u32> {
+    let version = read_header(&mut *reader)?;
+    let mut db = 0;
+    let mut expire_time = None;
+    loop {
+        let mut opcode = [0];
+        reader.read_exact(&mut opcode)?;
+        match opcode[0] {
+            EOF => break,
+            AUX => {
+                let key = read_value_string(reader)?;
+                let value = read_value_string(reader)?;
+                sink(RdbItem::Aux(key, value))?;
+            }
+            SELECT_DB => db = read_length(reader)? as usize,
+            RESIZE_DB => {
+                let size = read_length(reader)?;
+                let _expires_size = read_length(reader)?;
+                sink(RdbItem::ResizeDb(db, size))?;
+            }
+            FUNCTION2 => sink(RdbItem::Function(read_value_string(reader)?))?,
+            MODULE_AUX => return Err(anyhow!("module data is not supported")),
+            EXPIRE_MS => {
+                let mut expire_time_ms = [0; 8];
+                reader.read_exact(&mut expire_time_ms)?;
+                let expire_time_ms = u64::from_le_bytes(expire_time_ms);
+                expire_time = Some(UNIX_EPOCH + Duration::from_millis(expire_time_ms));
+            }
+            EXPIRE_S => {
+                let mut expire_time_s = [0; 4];
+                reader.read_exact(&mut expire_time_s)?;
+                let expire_time_s = u32::from_le_bytes(expire_time_s) as u64;
+                expire_time = Some(UNIX_EPOCH + Duration::from_secs(expire_time_s));
+            }
+            // Eviction metadata of the next key. Access times restart on load.
+            IDLE => {
+                read_length(reader)?;
+            }
+            FREQ => {
+                reader.read_exact(&mut [0])?;
+            }
+            value_type => {
+                if !matches!(value_type, TYPE_STRING..=TYPE_MODULE_2 | TYPE_HASH_ZIPMAP..=TYPE_STREAM_LISTPACKS_3)
+                {
+                    return Err(anyhow!("unknown value type {value_type}"));
+                }
+                let key = String::from_utf8(read_string(reader)?)?;
+                let value = read_value(value_type, reader)?;
+                let expire_time = expire_time.take();
+                let expired =
+                    matches!(expire_time, Some(deadline) if deadline <= SystemTime::now());
+                if !expired {
+                    sink(RdbItem::Key(db, key, value, expire_time))?;
+                }
+            }
+        }
+    }
+    if version >= FIRST_VERSION_WITH_CHECKSUM {
+        let expected = reader.crc;
+        let mut checksum = [0; 8];
+        reader.read_exact(&mut checksum)?;
+        let checksum = u64::from_le_bytes(checksum);
+        // A zero checksum means the file was saved with checksums disabled.
+        if checksum != 0 && checksum != expected {
+            return Err(anyhow!(
+                "wrong checksum {checksum:#018x}, expected {expected:#018x}"
+            ));
+        }
+    }
+    Ok(version)
+}
+
+/// Reads the `$<len>\r\n` header of an RDB image sent as a bulk string,
+/// taking bytes from `buf` first and reading more from `reader` as needed.
+pub async fn read_bulk_len<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+) -> Result<u64> {
+    use tokio::io::AsyncReadExt;
+    loop {
+        if let Some(end) = buf.windows(2).position(|window| window == b"\r\n") {
+            let header = buf.split_to(end + 2);
+            let header = std::str::from_utf8(&header[..end])?;
+            let len = header
+                .strip_prefix('$')
+                .ok_or(anyhow!("expected an RDB bulk length, got {header:?}"))?;
+            if len.starts_with("EOF:") {
+                return Err(anyhow!("diskless RDB transfers are not supported"));
+            }
+            return len
+                .parse::<u64>()
+                .map_err(|_| anyhow!("invalid RDB bulk length {len:?}"));
+        }
+        if reader.read_buf(buf).await? == 0 {
+            return Err(anyhow!("connection closed before the RDB was sent"));
+        }
+    }
+}
+
+/// Parses an RDB image of `len` bytes coming from `reader`, after what is
+/// already in `buf`, and passes its sections to `sink` as they arrive. The
+/// parser runs on a blocking thread fed through a bounded channel, so only a
+/// few chunks are held in memory. Bytes after the image stay in `buf`.
+pub async fn parse_stream<R, S>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    len: u64,
+    sink: S,
+) -> Result<u32>
+where
+    R: AsyncRead + Unpin,
+    S: FnMut(RdbItem) -> Result<()> + Send + 'static,
+{
+    use tokio::io::AsyncReadExt;
+    let (sender, receiver) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+    let parser = tokio::task::spawn_blocking(move || {
+        let reader = ChannelReader {
+            receiver,
+            chunk: Bytes::new(),
+        };
+        parse_with(reader, sink)
+    });
+    let mut remaining = len;
+    let mut parser_done = false;
+    while remaining > 0 {
+        if buf.is_empty() && reader.read_buf(buf).await? == 0 {
+            return Err(anyhow!(
+                "connection closed with {remaining} bytes of the RDB left"
+            ));
+        }
+        let chunk = buf.split_to(buf.len().min(remaining as usize)).freeze();
+        remaining -= chunk.len() as u64;
+        // Once the parser stopped, on an error or at the checksum, the rest
+        // of the image is still read so what follows it stays in sync.
+        if !parser_done && sender.send(chunk).await.is_err() {
+            parser_done = true;
+        }
+    }
+    drop(sender);
+    parser.await?
+}
+
+const STREAM_CHANNEL_CHUNKS: usize = 16;
+
+/// Blocking reader over the chunks sent by `parse_stream`.
+struct ChannelReader {
+    receiver: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return io::Result::Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        io::Result::Ok(len)
+    }
+}
+
+/// Saves `file` to `path` through a temporary file in the same directory,
+/// so the previous snapshot stays intact until the new one is complete.
+pub fn write_rdb_file(path: &str, file: &RdbFile) -> Result<()> {
+    let path = Path::new(path);
+    let temp_path = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let result = File::create(&temp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|temp| {
+            let mut writer = BufWriter::new(temp);
+            write_rdb(file, &mut writer)?;
+            writer.into_inner()?.sync_all()?;
+            fs::rename(&temp_path, path)?;
+            Ok(())
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Writer that keeps the running checksum of what was written.
+struct RdbWriter<W> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> Write for RdbWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.crc = crc64(self.crc, &buf[..len]);
+        io::Result::Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes `file` in the format of its version, which should be
+/// [`RDB_VERSION`], with keys buffered one at a time.
+pub fn write_rdb(file: &RdbFile, writer: impl Write) -> Result<()> {
+    let mut writer = RdbWriter {
+        inner: writer,
+        crc: 0,
+    };
+    writer.write_all(format!("{RDB_MAGIC}{:04}", file.version).as_bytes())?;
     let mut buf = vec![];
-    reader.read_until(0xfb, &mut buf)?;
-    let hash_size = read_hash_size(&mut reader)?;
-
-    let mut key_vals = HashMap::<String, String>::new();
-    let mut key_expires = HashMap::<String, Duration>::new();
-    for _ in 0..hash_size {
-        let (key, value, expire_time) = read_string_key_value(&mut reader)?;
-        if let Some(expire_time) = expire_time {
-            if SystemTime::now() < expire_time {
-                let duration = expire_time.duration_since(SystemTime::now())?;
-                key_expires.insert(key.clone(), duration);
-                key_vals.insert(key, value);
+    for (key, value) in &file.aux {
+        buf.push(AUX);
+        write_encoded_string(&mut buf, key.as_bytes());
+        write_encoded_string(&mut buf, value.as_bytes());
+    }
+    for code in &file.functions {
+        buf.push(FUNCTION2);
+        write_string(&mut buf, code.as_bytes());
+    }
+    writer.write_all(&buf)?;
+    for (db, database) in &file.databases {
+        buf.clear();
+        buf.push(SELECT_DB);
+        write_length(&mut buf, *db as u64);
+        buf.push(RESIZE_DB);
+        write_length(&mut buf, database.key_vals.len() as u64);
+        write_length(&mut buf, database.key_expires.len() as u64);
+        writer.write_all(&buf)?;
+        for (key, value) in &database.key_vals {
+            buf.clear();
+            if let Some(expire_time) = database.key_expires.get(key) {
+                let millis = expire_time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+                buf.push(EXPIRE_MS);
+                buf.extend_from_slice(&millis.to_le_bytes());
+            }
+            let value_type = value_type(value);
+            buf.push(value_type);
+            write_encoded_string(&mut buf, key.as_bytes());
+            write_value(&mut buf, value_type, value);
+            writer.write_all(&buf)?;
+        }
+    }
+    writer.write_all(&[EOF])?;
+    let crc = writer.crc;
+    writer.write_all(&crc.to_le_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// RDB type a value is saved as, following its in-memory encoding like
+/// Redis 7 does.
+fn value_type(value: &Value) -> u8 {
+    let compact = value.encoding() == "listpack";
+    match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST_QUICKLIST_2,
+        Value::Set(_) if compact => TYPE_SET_LISTPACK,
+        Value::Set(_) => TYPE_SET,
+        Value::SortedSet(_) if compact => TYPE_ZSET_LISTPACK,
+        Value::SortedSet(_) => TYPE_ZSET_2,
+        Value::Hash(_) if compact => TYPE_HASH_LISTPACK,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+fn write_value(buf: &mut Vec<u8>, value_type: u8, value: &Value) {
+    match value {
+        Value::String(s) => write_encoded_string(buf, s.as_bytes()),
+        Value::List(list) => {
+            let items: Vec<_> = list.iter().collect();
+            let nodes = items.chunks(QUICKLIST_NODE_MAX_ENTRIES);
+            write_length(buf, nodes.len() as u64);
+            for node in nodes {
+                write_length(buf, QUICKLIST_NODE_PACKED);
+                write_string(buf, &listpack::encode_listpack(node));
+            }
+        }
+        Value::Set(set) if value_type == TYPE_SET_LISTPACK => {
+            write_string(
+                buf,
+                &listpack::encode_listpack(&set.iter().collect::<Vec<_>>()),
+            );
+        }
+        Value::Set(set) => {
+            write_length(buf, set.len() as u64);
+            for member in set {
+                write_encoded_string(buf, member.as_bytes());
+            }
+        }
+        Value::SortedSet(zset) if value_type == TYPE_ZSET_LISTPACK => {
+            // Listpack sorted sets are kept ordered by score, then member.
+            let mut members: Vec<_> = zset.iter().collect();
+            members.sort_by(|a, b| a.1.total_cmp(b.1).then_with(|| a.0.cmp(b.0)));
+            let items: Vec<_> = members
+                .into_iter()
+                .flat_map(|(member, score)| [member.clone(), format_score(*score)])
+                .collect();
+            write_string(buf, &listpack::encode_listpack(&items));
+        }
+        Value::SortedSet(zset) => {
+            write_length(buf, zset.len() as u64);
+            for (member, score) in zset {
+                write_encoded_string(buf, member.as_bytes());
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::Hash(hash) if value_type == TYPE_HASH_LISTPACK => {
+            let items: Vec<_> = hash
+                .iter()
+                .flat_map(|(field, value)| [field, value])
+                .collect();
+            write_string(buf, &listpack::encode_listpack(&items));
+        }
+        Value::Hash(hash) => {
+            write_length(buf, hash.len() as u64);
+            for (field, value) in hash {
+                write_encoded_string(buf, field.as_bytes());
+                write_encoded_string(buf, value.as_bytes());
+            }
+        }
+        Value::Stream(stream) => write_stream(buf, stream),
+    }
+}
+
+fn format_score(score: f64) -> String {
+    match score {
+        f64::INFINITY => "inf".to_owned(),
+        f64::NEG_INFINITY => "-inf".to_owned(),
+        _ => score.to_string(),
+    }
+}
+
+fn write_stream(buf: &mut Vec<u8>, stream: &Stream) {
+    let entries: Vec<_> = stream.entries.iter().collect();
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+    write_length(buf, nodes.len() as u64);
+    for node in nodes {
+        let (master_id, _) = node[0];
+        write_string(buf, &raw_stream_id(*master_id));
+        write_string(buf, &listpack::encode_listpack(&stream_listpack(node)));
+    }
+    write_length(buf, stream.entries.len() as u64);
+    for id in [stream.last_id, stream.first_id, stream.max_deleted_id] {
+        write_stream_id(buf, id);
+    }
+    write_length(buf, stream.entries_added);
+
+    write_length(buf, stream.groups.len() as u64);
+    for group in &stream.groups {
+        write_string(buf, group.name.as_bytes());
+        write_stream_id(buf, group.last_id);
+        write_length(buf, group.entries_read as u64);
+        write_length(buf, group.pending.len() as u64);
+        for entry in &group.pending {
+            buf.extend_from_slice(&raw_stream_id(entry.id));
+            buf.extend_from_slice(&entry.delivery_time.to_le_bytes());
+            write_length(buf, entry.delivery_count);
+        }
+        write_length(buf, group.consumers.len() as u64);
+        for consumer in &group.consumers {
+            write_string(buf, consumer.name.as_bytes());
+            buf.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            buf.extend_from_slice(&consumer.active_time.to_le_bytes());
+            let owned: Vec<_> = group
+                .pending
+                .iter()
+                .filter(|entry| entry.consumer == consumer.name)
+                .collect();
+            write_length(buf, owned.len() as u64);
+            for entry in owned {
+                buf.extend_from_slice(&raw_stream_id(entry.id));
             }
+        }
+    }
+}
+
+/// Items of one stream listpack: the master entry takes its fields from the
+/// first entry, and entries with the same fields only store their values.
+fn stream_listpack(node: &[(&StreamId, &Vec<(String, String)>)]) -> Vec<String> {
+    let (master_id, master_fields) = node[0];
+    let mut items = vec![node.len().to_string(), "0".to_owned()];
+    items.push(master_fields.len().to_string());
+    items.extend(master_fields.iter().map(|(field, _)| field.clone()));
+    items.push("0".to_owned());
+    for (id, fields) in node {
+        let same_fields = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields.iter())
+                .all(|((a, _), (b, _))| a == b);
+        let start = items.len();
+        let flags = if same_fields {
+            STREAM_ITEM_SAME_FIELDS
+        } else {
+            0
+        };
+        items.push(flags.to_string());
+        items.push((id.ms.wrapping_sub(master_id.ms) as i64).to_string());
+        items.push((id.seq.wrapping_sub(master_id.seq) as i64).to_string());
+        if same_fields {
+            items.extend(fields.iter().map(|(_, value)| value.clone()));
         } else {
-            key_vals.insert(key, value);
+            items.push(fields.len().to_string());
+            for (field, value) in fields.iter() {
+                items.push(field.clone());
+                items.push(value.clone());
+            }
         }
+        items.push((items.len() - start).to_string());
     }
+    items
+}
 
-    Ok(RdbFile {
-        key_vals,
-        key_expires,
-    })
+fn write_stream_id(buf: &mut Vec<u8>, id: StreamId) {
+    write_length(buf, id.ms);
+    write_length(buf, id.seq);
 }
 
-fn read_string_key_value(
-    reader: &mut impl BufRead,
-) -> Result<(String, String, Option<SystemTime>), anyhow::Error> {
-    let mut value_type = [0];
-    reader.read_exact(&mut value_type)?;
-    let expire_time = match value_type[0] {
-        EXPIRE_MS => {
-            let mut expire_time_ms = [0; 8];
-            reader.read_exact(&mut expire_time_ms)?;
-            reader.read_exact(&mut value_type)?;
-            let expire_time_ms = u64::from_le_bytes(expire_time_ms);
-            let expire_time = UNIX_EPOCH + Duration::from_millis(expire_time_ms);
-            Some(expire_time)
-        }
-        EXPIRE_S => {
-            let mut expire_time_s = [0; 4];
-            reader.read_exact(&mut expire_time_s)?;
-            reader.read_exact(&mut value_type)?;
-            let expire_time_s = u32::from_le_bytes(expire_time_s) as u64;
-            let expire_time = UNIX_EPOCH + Duration::from_secs(expire_time_s);
-            Some(expire_time)
-        }
-        _ => None,
+fn raw_stream_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+/// Keys and values are kept as strings, so binary data is converted lossily
+/// rather than failing the whole load.
+fn to_string(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+fn read_value_string(reader: &mut impl Read) -> Result<String> {
+    Ok(to_string(read_string(reader)?))
+}
+
+/// Reads a value of the given RDB type in any of the encodings written by
+/// RDB versions 6 through 11.
+fn read_value(value_type: u8, reader: &mut impl Read) -> Result<Value> {
+    let value = match value_type {
+        TYPE_STRING => Value::String(read_value_string(reader)?),
+        TYPE_LIST => {
+            let len = read_length(reader)?;
+            Value::List(
+                (0..len)
+                    .map(|_| read_value_string(reader))
+                    .collect::<Result<_>>()?,
+            )
+        }
+        TYPE_SET => {
+            let len = read_length(reader)?;
+            Value::Set(
+                (0..len)
+                    .map(|_| read_value_string(reader))
+                    .collect::<Result<_>>()?,
+            )
+        }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = read_length(reader)?;
+            let mut zset = HashMap::new();
+            for _ in 0..len {
+                let member = read_value_string(reader)?;
+                let score = if value_type == TYPE_ZSET {
+                    read_string_double(reader)?
+                } else {
+                    let mut score = [0; 8];
+                    reader.read_exact(&mut score)?;
+                    f64::from_le_bytes(score)
+                };
+                zset.insert(member, score);
+            }
+            Value::SortedSet(zset)
+        }
+        TYPE_HASH => {
+            let len = read_length(reader)?;
+            let mut hash = HashMap::new();
+            for _ in 0..len {
+                hash.insert(read_value_string(reader)?, read_value_string(reader)?);
+            }
+            Value::Hash(hash)
+        }
+        TYPE_HASH_ZIPMAP => Value::Hash(pairs(listpack::zipmap_entries(&read_string(reader)?)?)?),
+        TYPE_LIST_ZIPLIST => {
+            Value::List(strings(listpack::ziplist_entries(&read_string(reader)?)?))
+        }
+        TYPE_SET_INTSET => Value::Set(strings(listpack::intset_entries(&read_string(reader)?)?)),
+        TYPE_SET_LISTPACK => {
+            Value::Set(strings(listpack::listpack_entries(&read_string(reader)?)?))
+        }
+        TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+            let blob = read_string(reader)?;
+            let entries = if value_type == TYPE_ZSET_ZIPLIST {
+                listpack::ziplist_entries(&blob)?
+            } else {
+                listpack::listpack_entries(&blob)?
+            };
+            let zset = pairs(entries)?
+                .into_iter()
+                .map(|(member, score)| Ok((member, parse_score(&score)?)))
+                .collect::<Result<_>>()?;
+            Value::SortedSet(zset)
+        }
+        TYPE_HASH_ZIPLIST => Value::Hash(pairs(listpack::ziplist_entries(&read_string(reader)?)?)?),
+        TYPE_HASH_LISTPACK => {
+            Value::Hash(pairs(listpack::listpack_entries(&read_string(reader)?)?)?)
+        }
+        TYPE_LIST_QUICKLIST => {
+            let nodes = read_length(reader)?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                list.extend(strings::<Vec<_>>(listpack::ziplist_entries(&read_string(
+                    reader,
+                )?)?));
+            }
+            Value::List(list)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let nodes = read_length(reader)?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                let container = read_length(reader)?;
+                let blob = read_string(reader)?;
+                match container {
+                    QUICKLIST_NODE_PLAIN => list.push_back(to_string(blob)),
+                    QUICKLIST_NODE_PACKED => {
+                        list.extend(strings::<Vec<_>>(listpack::listpack_entries(&blob)?))
+                    }
+                    _ => return Err(anyhow!("unknown quicklist container {container}")),
+                }
+            }
+            Value::List(list)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            Value::Stream(read_stream(value_type, reader)?)
+        }
+        TYPE_MODULE | TYPE_MODULE_2 => return Err(anyhow!("module values are not supported")),
+        _ => return Err(anyhow!("unknown value type {value_type}")),
     };
-    assert!(value_type[0] == STRING_VALUE);
-    let mut key_string_size = [0];
-    reader.read_exact(&mut key_string_size)?;
-    let mut key = vec![0; key_string_size[0].into()];
-    reader.read_exact(&mut key)?;
-    let mut value_string_size = [0];
-    reader.read_exact(&mut value_string_size)?;
-    let mut value = vec![0; value_string_size[0].into()];
-    reader.read_exact(&mut value)?;
-    let key = String::from_utf8(key)?;
-    let value = String::from_utf8(value)?;
-    Ok((key, value, expire_time))
-}
-
-fn read_hash_size(reader: &mut impl BufRead) -> Result<u8, anyhow::Error> {
-    let mut hash_size = [0];
-    let mut expire_hash_size = [0];
-    reader.read_exact(&mut hash_size)?;
-    reader.read_exact(&mut expire_hash_size)?;
-    Ok(hash_size[0])
-}
-
-fn read_header(mut reader: impl Read) -> Result<()> {
-    check_magic(&mut reader)?;
-    check_version(reader)?;
+    Ok(value)
+}
+
+fn strings<C: FromIterator<String>>(entries: Vec<Vec<u8>>) -> C {
+    entries.into_iter().map(to_string).collect()
+}
+
+/// Groups alternating fields and values.
+fn pairs(entries: Vec<Vec<u8>>) -> Result<HashMap<String, String>> {
+    if entries.len() & 1 == 1 {
+        return Err(anyhow!("odd number of entries in a map encoding"));
+    }
+    let mut entries = entries.into_iter().map(to_string);
+    let mut map = HashMap::new();
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        map.insert(field, value);
+    }
+    Ok(map)
+}
+
+fn parse_score(score: &str) -> Result<f64> {
+    match score {
+        "inf" | "+inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        _ => score
+            .parse::<f64>()
+            .map_err(|_| anyhow!("invalid sorted set score {score}")),
+    }
+}
+
+/// Scores of the original sorted set type are stored as text with a one
+/// byte length, where 253, 254 and 255 stand for NaN, +inf and -inf.
+fn read_string_double(reader: &mut impl Read) -> Result<f64> {
+    let mut len = [0];
+    reader.read_exact(&mut len)?;
+    match len[0] {
+        253 => Ok(f64::NAN),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        len => parse_score(&to_string(read_bytes(reader, len as u64)?)),
+    }
+}
+
+fn read_stream_id(reader: &mut impl Read) -> Result<StreamId> {
+    Ok(StreamId {
+        ms: read_length(reader)?,
+        seq: read_length(reader)?,
+    })
+}
+
+/// Stream IDs used as listpack keys and in pending lists are stored as two
+/// big endian 64 bit numbers.
+fn read_raw_stream_id(bytes: &[u8]) -> Result<StreamId> {
+    if bytes.len() != 16 {
+        return Err(anyhow!("invalid stream ID"));
+    }
+    Ok(StreamId {
+        ms: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+        seq: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+    })
+}
+
+fn read_millis(reader: &mut impl Read) -> Result<u64> {
+    let mut millis = [0; 8];
+    reader.read_exact(&mut millis)?;
+    Ok(u64::from_le_bytes(millis))
+}
+
+fn read_stream(value_type: u8, reader: &mut impl Read) -> Result<Stream> {
+    let mut stream = Stream::default();
+    let listpacks = read_length(reader)?;
+    for _ in 0..listpacks {
+        let master_id = read_raw_stream_id(&read_string(reader)?)?;
+        let entries = listpack::listpack_entries(&read_string(reader)?)?;
+        read_stream_listpack(master_id, entries, &mut stream.entries)?;
+    }
+    let _length = read_length(reader)?;
+    stream.last_id = read_stream_id(reader)?;
+    if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        stream.first_id = read_stream_id(reader)?;
+        stream.max_deleted_id = read_stream_id(reader)?;
+        stream.entries_added = read_length(reader)?;
+    } else {
+        stream.first_id = stream.entries.keys().next().copied().unwrap_or_default();
+        stream.entries_added = stream.entries.len() as u64;
+    }
+
+    let groups = read_length(reader)?;
+    for _ in 0..groups {
+        let name = read_value_string(reader)?;
+        let last_id = read_stream_id(reader)?;
+        let entries_read = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            read_length(reader)? as i64
+        } else {
+            -1
+        };
+        let pending_len = read_length(reader)?;
+        let mut deliveries = HashMap::new();
+        for _ in 0..pending_len {
+            let id = read_raw_stream_id(&read_bytes(reader, 16)?)?;
+            let delivery_time = read_millis(reader)?;
+            let delivery_count = read_length(reader)?;
+            deliveries.insert(id, (delivery_time, delivery_count));
+        }
+        let mut pending = vec![];
+        let mut consumers = vec![];
+        let consumers_len = read_length(reader)?;
+        for _ in 0..consumers_len {
+            let name = read_value_string(reader)?;
+            let seen_time = read_millis(reader)?;
+            let active_time = if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                read_millis(reader)?
+            } else {
+                seen_time
+            };
+            let owned = read_length(reader)?;
+            for _ in 0..owned {
+                let id = read_raw_stream_id(&read_bytes(reader, 16)?)?;
+                let (delivery_time, delivery_count) = deliveries.get(&id).copied().ok_or(
+                    anyhow!("consumer owns an entry missing from the pending list"),
+                )?;
+                pending.push(PendingEntry {
+                    id,
+                    consumer: name.clone(),
+                    delivery_time,
+                    delivery_count,
+                });
+            }
+            consumers.push(Consumer {
+                name,
+                seen_time,
+                active_time,
+            });
+        }
+        pending.sort_by_key(|entry| entry.id);
+        stream.groups.push(ConsumerGroup {
+            name,
+            last_id,
+            entries_read,
+            pending,
+            consumers,
+        });
+    }
+    Ok(stream)
+}
+
+/// Decodes the entries of one stream listpack. It starts with a master
+/// entry holding the field names most entries share, and every entry stores
+/// its ID as a delta from `master_id`.
+fn read_stream_listpack(
+    master_id: StreamId,
+    entries: Vec<Vec<u8>>,
+    stream: &mut BTreeMap<StreamId, Vec<(String, String)>>,
+) -> Result<()> {
+    let mut items = entries.into_iter().map(to_string);
+    let mut next = || items.next().ok_or(anyhow!("stream listpack is truncated"));
+    let count = next_int(&mut next)?;
+    let deleted = next_int(&mut next)?;
+    let master_fields_len = next_int(&mut next)?;
+    let master_fields = (0..master_fields_len)
+        .map(|_| next())
+        .collect::<Result<Vec<_>>>()?;
+    // Terminator of the master entry.
+    next_int(&mut next)?;
+
+    for _ in 0..count + deleted {
+        let flags = next_int(&mut next)?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(next_int(&mut next)? as u64),
+            seq: master_id.seq.wrapping_add(next_int(&mut next)? as u64),
+        };
+        let fields = if flags & STREAM_ITEM_SAME_FIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next()?)))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            let len = next_int(&mut next)?;
+            (0..len)
+                .map(|_| Ok((next()?, next()?)))
+                .collect::<Result<Vec<_>>>()?
+        };
+        // Number of listpack items of the entry, used to iterate backwards.
+        next_int(&mut next)?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            stream.insert(id, fields);
+        }
+    }
     Ok(())
 }
 
+fn next_int(next: &mut impl FnMut() -> Result<String>) -> Result<i64> {
+    let item = next()?;
+    item.parse::<i64>()
+        .map_err(|_| anyhow!("invalid integer {item} in stream listpack"))
+}
+
+fn read_header(mut reader: impl Read) -> Result<u32> {
+    check_magic(&mut reader)?;
+    check_version(reader)
+}
+
 fn check_magic(reader: &mut impl Read) -> Result<()> {
     let mut magic = [0; 5];
     reader.read_exact(&mut magic)?;
-    Ok(if magic != RDB_MAGIC.as_bytes() {
+    if magic != RDB_MAGIC.as_bytes() {
         return Err(anyhow!("wrong magic"));
-    })
+    }
+    Ok(())
 }
 
-fn check_version(mut reader: impl Read) -> Result<()> {
+fn check_version(mut reader: impl Read) -> Result<u32> {
     let mut version_bytes = [0; 4];
     reader.read_exact(&mut version_bytes)?;
-    let adjusted_bytes = [
-        version_bytes[0] - 48,
-        version_bytes[1] - 48,
-        version_bytes[2] - 48,
-        version_bytes[3] - 48,
-    ];
-    let _version = u32::from_be_bytes(adjusted_bytes);
-    Ok(())
+    let version = std::str::from_utf8(&version_bytes)
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or(anyhow!("invalid version"))?;
+    if version == 0 || version > RDB_VERSION as u32 {
+        return Err(anyhow!("can't handle RDB format version {version}"));
+    }
+    Ok(version)
+}
+
+fn write_length(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.extend_from_slice(&(0x4000 | len as u16).to_be_bytes());
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    write_length(buf, s.len() as u64);
+    buf.extend_from_slice(s);
+}
+
+/// Writes short integers in their 8, 16 or 32 bit encoding like Redis does,
+/// and everything else as a raw string.
+fn write_encoded_string(buf: &mut Vec<u8>, s: &[u8]) {
+    let int = std::str::from_utf8(s)
+        .ok()
+        .filter(|text| text.len() <= 11)
+        .and_then(|text| {
+            text.parse::<i32>()
+                .ok()
+                .filter(|int| int.to_string() == text)
+        });
+    match int {
+        Some(int) if i8::try_from(int).is_ok() => {
+            buf.push(0xc0 | ENC_INT8);
+            buf.push(int as u8);
+        }
+        Some(int) if i16::try_from(int).is_ok() => {
+            buf.push(0xc0 | ENC_INT16);
+            buf.extend_from_slice(&(int as i16).to_le_bytes());
+        }
+        Some(int) => {
+            buf.push(0xc0 | ENC_INT32);
+            buf.extend_from_slice(&int.to_le_bytes());
+        }
+        None => write_string(buf, s),
+    }
+}
+
+/// A length prefix, or the special format of a string stored in the
+/// remaining 6 bits when the two most significant bits are set.
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+fn read_length_encoding(reader: &mut impl Read) -> Result<Length> {
+    let mut first = [0];
+    reader.read_exact(&mut first)?;
+    match first[0] >> 6 {
+        0 => Ok(Length::Plain((first[0] & 0x3f) as u64)),
+        1 => {
+            let mut second = [0];
+            reader.read_exact(&mut second)?;
+            Ok(Length::Plain(
+                ((first[0] & 0x3f) as u64) << 8 | second[0] as u64,
+            ))
+        }
+        3 => Ok(Length::Encoded(first[0] & 0x3f)),
+        _ if first[0] == 0x80 => {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            Ok(Length::Plain(u32::from_be_bytes(len) as u64))
+        }
+        _ if first[0] == 0x81 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            Ok(Length::Plain(u64::from_be_bytes(len)))
+        }
+        _ => Err(anyhow!("unsupported length encoding {:#x}", first[0])),
+    }
+}
+
+fn read_length(reader: &mut impl Read) -> Result<u64> {
+    match read_length_encoding(reader)? {
+        Length::Plain(len) => Ok(len),
+        Length::Encoded(format) => Err(anyhow!("unexpected string encoding {format}")),
+    }
+}
+
+fn read_bytes(reader: &mut impl Read, len: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    reader.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(anyhow!("unexpected end of file"));
+    }
+    Ok(buf)
+}
+
+fn read_raw_string(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let len = read_length(reader)?;
+    read_bytes(reader, len)
+}
+
+/// Reads a string in any of its encodings: raw, as an 8, 16 or 32 bit
+/// integer, or LZF compressed.
+fn read_string(reader: &mut impl Read) -> Result<Vec<u8>> {
+    match read_length_encoding(reader)? {
+        Length::Plain(len) => read_bytes(reader, len),
+        Length::Encoded(ENC_INT8) => {
+            let mut int = [0; 1];
+            reader.read_exact(&mut int)?;
+            Ok(i8::from_le_bytes(int).to_string().into_bytes())
+        }
+        Length::Encoded(ENC_INT16) => {
+            let mut int = [0; 2];
+            reader.read_exact(&mut int)?;
+            Ok(i16::from_le_bytes(int).to_string().into_bytes())
+        }
+        Length::Encoded(ENC_INT32) => {
+            let mut int = [0; 4];
+            reader.read_exact(&mut int)?;
+            Ok(i32::from_le_bytes(int).to_string().into_bytes())
+        }
+        Length::Encoded(ENC_LZF) => {
+            let compressed_len = read_length(reader)?;
+            let len = read_length(reader)?;
+            let compressed = read_bytes(reader, compressed_len)?;
+            lzf_decompress(&compressed, len as usize)
+        }
+        Length::Encoded(format) => Err(anyhow!("unknown string encoding {format}")),
+    }
+}
+
+/// Decompresses LZF data, which is a sequence of literal runs and
+/// back references into the output produced so far. `len` comes from the
+/// input too, so it only caps the output and the room reserved up front.
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(len.min(1 << 20));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let run = ctrl + 1;
+            let literal = input.get(i..i + run).ok_or(anyhow!("invalid LZF data"))?;
+            if output.len() + run > len {
+                return Err(anyhow!("invalid LZF data"));
+            }
+            output.extend_from_slice(literal);
+            i += run;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or(anyhow!("invalid LZF data"))? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or(anyhow!("invalid LZF data"))? as usize;
+            i += 1;
+            let offset = ((ctrl & 0x1f) << 8 | low) + 1;
+            if offset > output.len() || output.len() + run + 2 > len {
+                return Err(anyhow!("invalid LZF data"));
+            }
+            // The reference may overlap the bytes it produces.
+            let start = output.len() - offset;
+            for j in 0..run + 2 {
+                output.push(output[start + j]);
+            }
+        }
+    }
+    if output.len() != len {
+        return Err(anyhow!("invalid LZF data"));
+    }
+    Ok(output)
+}
+
+/// Appends the DUMP footer: the RDB version and a CRC64 of the payload.
+fn add_dump_footer(payload: &mut Vec<u8>) {
+    payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let crc = crc64(0, payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// Checks the DUMP footer and returns the payload without it.
+fn verify_dump_footer(payload: &[u8]) -> Result<&[u8]> {
+    if payload.len() < 10 {
+        return Err(anyhow!("DUMP payload version or checksum are wrong"));
+    }
+    let (body, crc) = payload.split_at(payload.len() - 8);
+    if crc64(0, body).to_le_bytes() != crc {
+        return Err(anyhow!("DUMP payload version or checksum are wrong"));
+    }
+    let version = u16::from_le_bytes([body[body.len() - 2], body[body.len() - 1]]);
+    if version > RDB_VERSION {
+        return Err(anyhow!("DUMP payload version or checksum are wrong"));
+    }
+    Ok(&body[..body.len() - 2])
+}
+
+/// Serializes function libraries the way FUNCTION DUMP does.
+pub fn dump_functions(codes: &[String]) -> Vec<u8> {
+    let mut payload = vec![];
+    for code in codes {
+        payload.push(FUNCTION2);
+        write_string(&mut payload, code.as_bytes());
+    }
+    add_dump_footer(&mut payload);
+    payload
+}
+
+/// Parses a FUNCTION DUMP payload back into library codes.
+pub fn restore_functions(payload: &[u8]) -> Result<Vec<String>> {
+    let mut reader = verify_dump_footer(payload)?;
+    let mut codes = vec![];
+    while !reader.is_empty() {
+        let mut opcode = [0];
+        reader.read_exact(&mut opcode)?;
+        if opcode[0] != FUNCTION2 {
+            return Err(anyhow!("given type is not a function"));
+        }
+        codes.push(String::from_utf8(read_raw_string(&mut reader)?)?);
+    }
+    Ok(codes)
+}
+
+/// Serializes a value the way DUMP does: its RDB type and encoding,
+/// followed by the DUMP footer.
+pub fn dump_value(value: &Value) -> Vec<u8> {
+    let value_type = value_type(value);
+    let mut payload = vec![value_type];
+    write_value(&mut payload, value_type, value);
+    add_dump_footer(&mut payload);
+    payload
+}
+
+/// Parses a DUMP payload back into a value.
+pub fn restore_value(payload: &[u8]) -> Result<Value> {
+    let mut reader = verify_dump_footer(payload)?;
+    let mut value_type = [0];
+    reader.read_exact(&mut value_type)?;
+    let value = read_value(value_type[0], &mut reader).map_err(|_| anyhow!("Bad data format"))?;
+    if !reader.is_empty() {
+        return Err(anyhow!("Bad data format"));
+    }
+    Ok(value)
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
-    use crate::rdb::read_header;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::rdb::{
+        add_dump_footer, crc64, dump_functions, dump_value, parse, read_header, read_length,
+        read_string, restore_functions, restore_value, write_length, write_rdb, write_string,
+        RdbDatabase, RdbError, RdbFile, RDB_VERSION,
+    };
+    use crate::value::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId, Value};
+
+    #[test]
+    fn should_round_trip_dumped_values() {
+        let values = [
+            Value::String("12345".to_owned()),
+            Value::List((0..300).map(|i| i.to_string()).collect()),
+            Value::Hash([("f".to_owned(), "v".to_owned())].into()),
+        ];
+        for value in values {
+            let payload = dump_value(&value);
+            assert_eq!(restore_value(&payload).unwrap(), value);
+        }
+        let mut payload = dump_value(&Value::String("v".to_owned()));
+        payload.insert(2, b'x');
+        assert!(restore_value(&payload).is_err());
+    }
+
+    #[test]
+    fn should_compute_redis_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn should_round_trip_lengths() {
+        for len in [
+            0,
+            63,
+            64,
+            16383,
+            16384,
+            u32::MAX as u64,
+            u32::MAX as u64 + 1,
+        ] {
+            let mut buf = vec![];
+            write_length(&mut buf, len);
+            assert_eq!(read_length(&mut Cursor::new(buf)).unwrap(), len);
+        }
+    }
+
+    #[test]
+    fn should_round_trip_function_dump() {
+        let codes = vec!["#!lua name=a\n".to_owned(), "#!lua name=b\n".to_owned()];
+        let mut payload = dump_functions(&codes);
+        assert_eq!(restore_functions(&payload).unwrap(), codes);
+        payload[3] ^= 1;
+        assert!(restore_functions(&payload).is_err());
+    }
+
+    fn add_checksum(data: &mut Vec<u8>) {
+        let crc = crc64(0, data);
+        data.extend_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn should_report_where_a_file_is_broken() {
+        let mut data = b"REDIS0011\xfe\x00\x00\x01a\x011\xf5\x01f\xff".to_vec();
+        add_checksum(&mut data);
+        let file = parse(Cursor::new(data.clone())).unwrap();
+        assert_eq!(file.functions, vec!["f".to_owned()]);
+
+        let mut corrupt = data.clone();
+        corrupt[15] = b'2';
+        let err = parse(Cursor::new(corrupt)).unwrap_err().to_string();
+        assert!(err.contains("wrong checksum"), "{err}");
+
+        let err = parse(Cursor::new(&data[..14])).unwrap_err().to_string();
+        assert_eq!(err, "RDB file is truncated at offset 14");
+
+        let mut unknown = data[..11].to_vec();
+        unknown.push(0x42);
+        let err = parse(Cursor::new(unknown)).unwrap_err().to_string();
+        assert!(err.starts_with("RDB file is corrupt at offset 12"), "{err}");
+
+        let err = parse(Cursor::new(&data[..14])).unwrap_err();
+        let err = err.downcast_ref::<RdbError>().unwrap();
+        assert_eq!((err.offset, err.cause.as_deref()), (14, None));
+    }
+
+    #[test]
+    fn should_reject_newer_versions() {
+        let data: &[u8] = b"REDIS0099";
+        assert!(read_header(Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn should_load_keys_into_their_databases() {
+        let mut data = b"REDIS0011\xfa\x03ver\x017\xfe\x00\xfb\x01\x00".to_vec();
+        data.extend_from_slice(b"\x00\x01a\x011\xfe\x03\xfb\x01\x00\x00\x01b\x012\xff");
+        add_checksum(&mut data);
+        let file = parse(Cursor::new(data)).unwrap();
+        assert_eq!(file.aux, vec![("ver".to_owned(), "7".to_owned())]);
+        assert_eq!(
+            *file.databases[&0].key_vals["a"],
+            Value::String("1".to_owned())
+        );
+        assert_eq!(
+            *file.databases[&3].key_vals["b"],
+            Value::String("2".to_owned())
+        );
+        assert_eq!(file.databases.len(), 2);
+    }
+
+    #[test]
+    fn should_read_encoded_strings() {
+        let mut data: &[u8] = b"\xc0\x40\xc1\x18\xfc\xc2\x87\xd6\x12\x00";
+        assert_eq!(read_string(&mut data).unwrap(), b"64");
+        assert_eq!(read_string(&mut data).unwrap(), b"-1000");
+        assert_eq!(read_string(&mut data).unwrap(), b"1234567");
+
+        let mut lzf: &[u8] = b"\xc3\x05\x0a\x00a\xe0\x00\x00";
+        assert_eq!(read_string(&mut lzf).unwrap(), b"aaaaaaaaaa");
+        let mut truncated: &[u8] = b"\x05abc";
+        assert!(read_string(&mut truncated).is_err());
+    }
+
+    #[test]
+    fn should_reject_lzf_data_longer_than_announced() {
+        // A literal of two bytes for an announced length of one.
+        let mut literal: &[u8] = b"\xc3\x03\x01\x01ab";
+        assert!(read_string(&mut literal).is_err());
+        let mut reference: &[u8] = b"\xc3\x04\x02\x00a\x20\x00";
+        assert!(read_string(&mut reference).is_err());
+
+        // The announced length is not allocated up front.
+        let mut payload = vec![0, 0xc3, 0x01, 0x81];
+        payload.extend_from_slice(&(1u64 << 62).to_be_bytes());
+        payload.push(0x00);
+        add_dump_footer(&mut payload);
+        assert!(restore_value(&payload).is_err());
+    }
+
+    fn listpack(items: &[&str]) -> Vec<u8> {
+        let mut body = vec![];
+        for item in items {
+            match item.parse::<u8>() {
+                Result::Ok(int) if int < 128 => body.extend_from_slice(&[int, 1]),
+                _ => {
+                    body.push(0x80 | item.len() as u8);
+                    body.extend_from_slice(item.as_bytes());
+                    body.push(item.len() as u8 + 1);
+                }
+            }
+        }
+        let mut lp = ((body.len() + 7) as u32).to_le_bytes().to_vec();
+        lp.extend_from_slice(&(items.len() as u16).to_le_bytes());
+        lp.extend(body);
+        lp.push(0xff);
+        lp
+    }
+
+    fn raw_id(ms: u64, seq: u64) -> Vec<u8> {
+        [ms.to_be_bytes(), seq.to_be_bytes()].concat()
+    }
+
+    #[test]
+    fn should_load_every_value_type() {
+        let mut data = b"REDIS0011\xfe\x00".to_vec();
+        data.extend_from_slice(b"\x12\x01l\x01\x02");
+        write_string(&mut data, &listpack(&["x", "5"]));
+        data.extend_from_slice(b"\x0b\x01s");
+        write_string(&mut data, b"\x02\x00\x00\x00\x01\x00\x00\x00\x07\x00");
+        data.extend_from_slice(b"\x05\x01z\x01\x01m");
+        data.extend_from_slice(&1.5f64.to_le_bytes());
+        data.extend_from_slice(b"\x10\x01h");
+        write_string(&mut data, &listpack(&["f", "v"]));
+
+        data.extend_from_slice(b"\x15\x02st\x01");
+        write_string(&mut data, &raw_id(1000, 0));
+        // Master entry with field "f", an entry reusing it and one with its
+        // own field.
+        let entries = [
+            "2", "0", "1", "f", "0", "2", "0", "0", "a", "3", "0", "1", "0", "1", "g", "b", "5",
+        ];
+        write_string(&mut data, &listpack(&entries));
+        data.extend_from_slice(b"\x02");
+        for len in [1001, 0, 1000, 0, 0, 0, 2] {
+            write_length(&mut data, len);
+        }
+        data.extend_from_slice(b"\x01\x01g");
+        for len in [1000, 0, 1] {
+            write_length(&mut data, len);
+        }
+        data.push(1);
+        data.extend(raw_id(1000, 0));
+        data.extend_from_slice(&42u64.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(b"\x01\x01c");
+        data.extend_from_slice(&40u64.to_le_bytes());
+        data.extend_from_slice(&41u64.to_le_bytes());
+        data.push(1);
+        data.extend(raw_id(1000, 0));
+        data.push(0xff);
+        add_checksum(&mut data);
+
+        let mut file = parse(Cursor::new(data)).unwrap();
+        let db = file.databases.remove(&0).unwrap().key_vals;
+        assert_eq!(
+            *db["l"],
+            Value::List(["x".to_owned(), "5".to_owned()].into())
+        );
+        assert_eq!(*db["s"], Value::Set(["7".to_owned()].into()));
+        assert_eq!(*db["z"], Value::SortedSet([("m".to_owned(), 1.5)].into()));
+        assert_eq!(
+            *db["h"],
+            Value::Hash([("f".to_owned(), "v".to_owned())].into())
+        );
+        let Value::Stream(stream) = db["st"].as_ref() else {
+            panic!("expected a stream");
+        };
+        let ids: Vec<_> = stream.entries.keys().map(|id| (id.ms, id.seq)).collect();
+        assert_eq!(ids, vec![(1000, 0), (1001, 0)]);
+        assert_eq!(
+            stream.entries.values().last().unwrap(),
+            &vec![("g".to_owned(), "b".to_owned())]
+        );
+        let group = &stream.groups[0];
+        assert_eq!(group.pending[0].consumer, "c");
+        assert_eq!(group.pending[0].delivery_time, 42);
+        assert_eq!(group.consumers[0].active_time, 41);
+    }
+
+    fn stream() -> Stream {
+        let mut stream = Stream::default();
+        for i in 0..150u64 {
+            let field = if i % 7 == 0 { "other" } else { "f" };
+            let id = StreamId {
+                ms: 1000 + i,
+                seq: i % 3,
+            };
+            stream
+                .entries
+                .insert(id, vec![(field.to_owned(), i.to_string())]);
+        }
+        stream.first_id = *stream.entries.keys().next().unwrap();
+        stream.last_id = *stream.entries.keys().last().unwrap();
+        stream.entries_added = 151;
+        stream.max_deleted_id = StreamId { ms: 900, seq: 0 };
+        stream.groups.push(ConsumerGroup {
+            name: "g".to_owned(),
+            last_id: stream.first_id,
+            entries_read: -1,
+            pending: vec![PendingEntry {
+                id: stream.first_id,
+                consumer: "c".to_owned(),
+                delivery_time: 42,
+                delivery_count: 2,
+            }],
+            consumers: vec![Consumer {
+                name: "c".to_owned(),
+                seen_time: 40,
+                active_time: 41,
+            }],
+        });
+        stream
+    }
+
+    #[test]
+    fn should_round_trip_every_value_type() {
+        let big = |prefix: &'static str| (0..200).map(move |i| format!("{prefix}{i}"));
+        let key_vals = [
+            ("int".to_owned(), Value::String("-70000".to_owned())),
+            ("str".to_owned(), Value::String("x".repeat(100))),
+            ("list".to_owned(), Value::List(big("").collect())),
+            ("small-set".to_owned(), Value::Set(["a".to_owned()].into())),
+            ("set".to_owned(), Value::Set(big("m").collect())),
+            (
+                "small-zset".to_owned(),
+                Value::SortedSet([("a".to_owned(), 1.5), ("b".to_owned(), f64::INFINITY)].into()),
+            ),
+            (
+                "zset".to_owned(),
+                Value::SortedSet(big("m").map(|m| (m, 0.25)).collect()),
+            ),
+            (
+                "small-hash".to_owned(),
+                Value::Hash([("f".to_owned(), "1".to_owned())].into()),
+            ),
+            (
+                "hash".to_owned(),
+                Value::Hash(big("f").zip(big("v")).collect()),
+            ),
+            ("stream".to_owned(), Value::Stream(stream())),
+        ]
+        .map(|(key, value)| (key, Arc::new(value)));
+        // Expire times only keep millisecond precision.
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let deadline = UNIX_EPOCH + Duration::from_millis(millis + 60_000);
+        let first = RdbDatabase {
+            key_vals: HashMap::from(key_vals),
+            key_expires: HashMap::from([("list".to_owned(), deadline)]),
+        };
+        let second = RdbDatabase {
+            key_vals: HashMap::from([("k".to_owned(), Arc::new(Value::String("v".to_owned())))]),
+            key_expires: HashMap::new(),
+        };
+        let file = RdbFile {
+            version: RDB_VERSION as u32,
+            aux: vec![("redis-bits".to_owned(), "64".to_owned())],
+            databases: BTreeMap::from([(0, first), (5, second)]),
+            functions: vec!["#!lua name=lib\n".to_owned()],
+        };
+        let mut data = vec![];
+        write_rdb(&file, &mut data).unwrap();
+        assert_eq!(parse(data.as_slice()).unwrap(), file);
+
+        let last = data.len() - 9;
+        data[last] ^= 1;
+        assert!(parse(data.as_slice()).is_err());
+    }
 
     #[test]
     fn should_fail_with_wrong_magic() {
@@ -0,0 +1,673 @@
+//! Master side of replication: the stream of writes sent to the attached
+//! replicas after their initial snapshot, and the backlog that lets a replica
+//! resume the stream after losing its connection. Also records the master
+//! this server replicates, if any.
+
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    fs::File,
+    hash::BuildHasher,
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+};
+
+use crate::{
+    parser::RedisValue,
+    request::{serialize_effects, WriteEffect},
+};
+
+pub const DEFAULT_REPL_BACKLOG_SIZE: u64 = 1 << 20;
+
+/// Replication ID of no stream, reported before this server had a previous
+/// master.
+const NO_REPLID: &str = "0000000000000000000000000000000000000000";
+
+pub struct Replication {
+    stream: Mutex<ReplicationStream>,
+    /// Signaled whenever a replica acknowledges an offset.
+    acked: Notify,
+    master: Mutex<Option<MasterLink>>,
+    /// Whether clients other than the master link are refused writes while
+    /// this server is a replica.
+    read_only: AtomicBool,
+}
+
+/// The master this server replicates, and the task following its stream.
+pub struct MasterLink {
+    pub ip: String,
+    pub port: String,
+    pub task: JoinHandle<()>,
+    pub status: Arc<Mutex<LinkStatus>>,
+}
+
+/// Progress of the link to the master, which is connected again whenever it
+/// drops.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LinkState {
+    #[default]
+    Connect,
+    Handshake,
+    /// Receiving the snapshot of a full synchronization.
+    Transfer,
+    /// Applying the master's stream.
+    Connected,
+}
+
+#[derive(Debug, Default)]
+pub struct LinkStatus {
+    pub state: LinkState,
+    /// When bytes were last received from the master.
+    pub last_io: Option<Instant>,
+}
+
+struct ReplicationStream {
+    replid: String,
+    /// Number of bytes in the stream `replid`, the replication offset.
+    offset: u64,
+    /// The stream this server followed before it was promoted to master,
+    /// which its replicas can continue up to `second_offset`.
+    replid2: String,
+    second_offset: i64,
+    /// Set while the stream is the one received from a master, which is
+    /// passed on to the replicas of this server as it is.
+    following: bool,
+    backlog_size: usize,
+    /// The last bytes of the stream, up to `offset`. Only kept once a
+    /// replica attached.
+    backlog: Option<VecDeque<u8>>,
+    replicas: Vec<Replica>,
+    next_replica_id: usize,
+    /// Database the stream last selected. Unknown once a replica attaches,
+    /// so the next write selects its database again.
+    selected_db: Option<usize>,
+}
+
+struct Replica {
+    id: usize,
+    ip: String,
+    /// Port the replica listens on, announced with REPLCONF listening-port.
+    port: String,
+    sender: mpsc::UnboundedSender<Bytes>,
+    /// Offset of the stream the replica applied, and of the part it also
+    /// synced to its AOF, as of its last REPLCONF ACK.
+    ack_offset: u64,
+    aof_offset: u64,
+    last_ack: Instant,
+}
+
+/// A replica attached to the stream.
+pub struct ReplicaLink {
+    /// Identifies the replica when it acknowledges offsets.
+    pub id: usize,
+    /// Bytes of the stream to send to the replica.
+    pub writes: mpsc::UnboundedReceiver<Bytes>,
+}
+
+/// Where a replica that attached starts the stream.
+pub enum SyncPoint {
+    /// The replica has to load a snapshot of the dataset, taken at `offset`
+    /// of the stream identified by `replid`.
+    Full { replid: String, offset: u64 },
+    /// The replica already has the stream up to where these bytes, taken
+    /// from the backlog, start.
+    Partial { replid: String, missing: Bytes },
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication {
+            stream: Mutex::new(ReplicationStream {
+                replid: random_replid(),
+                offset: 0,
+                replid2: NO_REPLID.to_owned(),
+                second_offset: -1,
+                following: false,
+                backlog_size: DEFAULT_REPL_BACKLOG_SIZE as usize,
+                backlog: None,
+                replicas: vec![],
+                next_replica_id: 0,
+                selected_db: None,
+            }),
+            acked: Notify::new(),
+            master: Mutex::new(None),
+            read_only: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Replication {
+    /// Registers a replica that asked with PSYNC to continue the stream
+    /// `replid` from `offset`, the first byte it is missing. The replica
+    /// resumes from the backlog when it still has every byte from there, and
+    /// needs a full synchronization otherwise.
+    ///
+    /// A full synchronization has to be started with the keyspace locked,
+    /// right before taking the snapshot sent to the replica, so the stream
+    /// starts exactly where the snapshot ends. Writes are queued without
+    /// limit while the snapshot is transferred.
+    pub fn attach(
+        &self,
+        replid: &str,
+        offset: i64,
+        ip: &str,
+        port: &str,
+    ) -> (SyncPoint, ReplicaLink) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut stream = self.stream.lock().unwrap();
+        let sync_point = match stream.missing_bytes(replid, offset) {
+            Some(missing) => SyncPoint::Partial {
+                replid: stream.replid.clone(),
+                missing,
+            },
+            None => {
+                if stream.backlog.is_none() {
+                    stream.backlog = Some(VecDeque::new());
+                }
+                stream.selected_db = None;
+                SyncPoint::Full {
+                    replid: stream.replid.clone(),
+                    offset: stream.offset,
+                }
+            }
+        };
+        let id = stream.next_replica_id;
+        stream.next_replica_id += 1;
+        stream.replicas.push(Replica {
+            id,
+            ip: ip.to_owned(),
+            port: port.to_owned(),
+            sender,
+            ack_offset: 0,
+            aof_offset: 0,
+            last_ack: Instant::now(),
+        });
+        let link = ReplicaLink {
+            id,
+            writes: receiver,
+        };
+        (sync_point, link)
+    }
+
+    /// Records a REPLCONF ACK of the replica `id`, with the offset it synced
+    /// to its AOF if it has one.
+    pub fn ack(&self, id: usize, offset: u64, aof_offset: Option<u64>) {
+        let mut stream = self.stream.lock().unwrap();
+        if let Some(replica) = stream.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = offset;
+            replica.aof_offset = aof_offset.unwrap_or(0);
+            replica.last_ack = Instant::now();
+        }
+        drop(stream);
+        self.acked.notify_waiters();
+    }
+
+    /// Number of connected replicas that acknowledged the stream up to
+    /// `offset`, in their AOF too with `aof`.
+    pub fn count_acked(&self, offset: u64, aof: bool) -> usize {
+        let stream = self.stream.lock().unwrap();
+        stream
+            .replicas
+            .iter()
+            .filter(|replica| !replica.sender.is_closed())
+            .filter(|replica| {
+                let acked = if aof {
+                    replica.aof_offset
+                } else {
+                    replica.ack_offset
+                };
+                acked >= offset
+            })
+            .count()
+    }
+
+    /// Waits until `needed` replicas acknowledged the stream up to `offset`,
+    /// asking them for an ACK right away, or until the timeout if there is
+    /// one. Returns how many replicas did.
+    pub async fn wait_for_acks(
+        &self,
+        offset: u64,
+        needed: usize,
+        aof: bool,
+        timeout: Option<Duration>,
+    ) -> usize {
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        let mut requested = false;
+        loop {
+            let acked = self.acked.notified();
+            let count = self.count_acked(offset, aof);
+            if count >= needed {
+                return count;
+            }
+            if !requested {
+                self.request_acks();
+                requested = true;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, acked).await.is_err() {
+                        return self.count_acked(offset, aof);
+                    }
+                }
+                None => acked.await,
+            }
+        }
+    }
+
+    /// Sends REPLCONF GETACK through the stream, so replicas acknowledge
+    /// what they applied before it.
+    fn request_acks(&self) {
+        let mut stream = self.stream.lock().unwrap();
+        if stream.following || stream.replicas.is_empty() {
+            return;
+        }
+        let getack = ["REPLCONF", "GETACK", "*"].map(String::from).to_vec();
+        stream.append(&RedisValue::make_bulk_array(getack).serialize());
+    }
+
+    /// Address of the master this server replicates.
+    pub fn get_master(&self) -> Option<(String, String)> {
+        let master = self.master.lock().unwrap();
+        master
+            .as_ref()
+            .map(|master| (master.ip.clone(), master.port.clone()))
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.master.lock().unwrap().is_some()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    /// Replaces the master this server replicates, stopping the task that
+    /// followed the previous one. Returns whether there was one.
+    pub fn set_master(&self, master: Option<MasterLink>) -> bool {
+        let previous = std::mem::replace(&mut *self.master.lock().unwrap(), master);
+        match previous {
+            Some(previous) => {
+                previous.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The replication ID and offset a replica asks its master to continue
+    /// from with PSYNC: the stream it has, whether it followed it as a
+    /// replica or produced it as a master.
+    pub fn get_psync_point(&self) -> (String, i64) {
+        let stream = self.stream.lock().unwrap();
+        if stream.backlog.is_none() {
+            return ("?".to_owned(), -1);
+        }
+        (stream.replid.clone(), stream.offset as i64 + 1)
+    }
+
+    /// Continues the stream of a master, after loading its snapshot taken
+    /// at `offset`. The replicas of this server are disconnected, since
+    /// their data no longer matches.
+    pub fn follow(&self, replid: &str, offset: u64) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.replid = replid.to_owned();
+        stream.offset = offset;
+        stream.replid2 = NO_REPLID.to_owned();
+        stream.second_offset = -1;
+        stream.following = true;
+        stream.backlog = Some(VecDeque::new());
+        stream.replicas.clear();
+    }
+
+    /// Continues the stream of a master that accepted a partial
+    /// synchronization. A master promoted since it produced the stream gives
+    /// it a new ID, which is adopted like in [`Replication::promote`].
+    pub fn resume(&self, replid: &str) {
+        let mut stream = self.stream.lock().unwrap();
+        if stream.replid != replid {
+            stream.replid2 = std::mem::replace(&mut stream.replid, replid.to_owned());
+            stream.second_offset = stream.offset as i64 + 1;
+        }
+        stream.following = true;
+    }
+
+    /// Passes on bytes of the master's stream, once they are applied. Called
+    /// with the keyspace locked.
+    pub fn feed(&self, bytes: &[u8]) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.append(bytes);
+    }
+
+    /// Starts a new stream when this replica becomes a master. Its replicas,
+    /// which followed the same master, can still continue the previous
+    /// stream up to where it ends.
+    pub fn promote(&self) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.replid2 = std::mem::replace(&mut stream.replid, random_replid());
+        stream.second_offset = stream.offset as i64 + 1;
+        stream.following = false;
+        stream.selected_db = None;
+    }
+
+    /// Offset of the stream applied so far.
+    pub fn get_offset(&self) -> u64 {
+        self.stream.lock().unwrap().offset
+    }
+
+    pub fn replica_count(&self) -> usize {
+        let mut stream = self.stream.lock().unwrap();
+        stream
+            .replicas
+            .retain(|replica| !replica.sender.is_closed());
+        stream.replicas.len()
+    }
+
+    pub fn get_backlog_size(&self) -> u64 {
+        self.stream.lock().unwrap().backlog_size as u64
+    }
+
+    /// Resizes the backlog, keeping its most recent bytes.
+    pub fn set_backlog_size(&self, size: u64) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.backlog_size = size as usize;
+        stream.trim_backlog();
+    }
+
+    /// The replication section of INFO.
+    pub fn info(&self) -> String {
+        let mut lines = match self.master.lock().unwrap().as_ref() {
+            Some(master) => {
+                let status = master.status.lock().unwrap();
+                let up = status.state == LinkState::Connected;
+                let last_io = status
+                    .last_io
+                    .map_or(-1, |last_io| last_io.elapsed().as_secs() as i64);
+                vec![
+                    "role:slave".to_owned(),
+                    format!("master_host:{}", master.ip),
+                    format!("master_port:{}", master.port),
+                    format!("master_link_status:{}", if up { "up" } else { "down" }),
+                    format!("master_last_io_seconds_ago:{last_io}"),
+                    format!(
+                        "master_sync_in_progress:{}",
+                        (status.state == LinkState::Transfer) as u8
+                    ),
+                ]
+            }
+            None => vec!["role:master".to_owned()],
+        };
+        let mut stream = self.stream.lock().unwrap();
+        stream
+            .replicas
+            .retain(|replica| !replica.sender.is_closed());
+        lines.push(format!("connected_slaves:{}", stream.replicas.len()));
+        for (index, replica) in stream.replicas.iter().enumerate() {
+            lines.push(format!(
+                "slave{index}:ip={},port={},state=online,offset={},lag={}",
+                replica.ip,
+                replica.port,
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs()
+            ));
+        }
+        let histlen = stream.backlog.as_ref().map_or(0, |backlog| backlog.len());
+        lines.extend([
+            format!("master_replid:{}", stream.replid),
+            format!("master_replid2:{}", stream.replid2),
+            format!("master_repl_offset:{}", stream.offset),
+            format!("second_repl_offset:{}", stream.second_offset),
+            format!("repl_backlog_active:{}", stream.backlog.is_some() as u8),
+            format!("repl_backlog_size:{}", stream.backlog_size),
+            format!(
+                "repl_backlog_first_byte_offset:{}",
+                stream.offset - histlen as u64 + 1
+            ),
+            format!("repl_backlog_histlen:{histlen}"),
+        ]);
+        lines.join("\n")
+    }
+
+    /// Sends the writes of one request to the replicas. Called with the
+    /// keyspace locked, like the AOF append, so replicas apply writes in the
+    /// order the master did. A replica only passes on its master's stream.
+    pub fn propagate(&self, effects: &[WriteEffect]) {
+        let mut stream = self.stream.lock().unwrap();
+        if stream.backlog.is_none() || stream.following || effects.is_empty() {
+            return;
+        }
+        let mut buf = vec![];
+        serialize_effects(effects, &mut stream.selected_db, &mut buf);
+        stream.append(&buf);
+    }
+}
+
+/// A new replication ID: 40 hex characters read from the OS random source,
+/// or from [`hashed_random_bytes`] where there is none.
+fn random_replid() -> String {
+    let mut bytes = [0; 20];
+    let read = File::open("/dev/urandom").and_then(|mut urandom| urandom.read_exact(&mut bytes));
+    if read.is_err() {
+        bytes = hashed_random_bytes();
+    }
+    hex::encode(bytes)
+}
+
+/// Random bytes from the randomly keyed hasher of the standard library,
+/// mixing in the time and process ID.
+fn hashed_random_bytes() -> [u8; 20] {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let mut bytes = [0; 20];
+    for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+        let hash = RandomState::new().hash_one((nanos, std::process::id(), i));
+        chunk.copy_from_slice(&hash.to_le_bytes()[..chunk.len()]);
+    }
+    bytes
+}
+
+impl ReplicationStream {
+    fn append(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() || self.backlog.is_none() {
+            return;
+        }
+        self.offset += bytes.len() as u64;
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.extend(bytes);
+        }
+        self.trim_backlog();
+        let bytes = Bytes::copy_from_slice(bytes);
+        self.replicas
+            .retain(|replica| replica.sender.send(bytes.clone()).is_ok());
+    }
+
+    fn trim_backlog(&mut self) {
+        let size = self.backlog_size;
+        if let Some(backlog) = self.backlog.as_mut() {
+            let excess = backlog.len().saturating_sub(size);
+            backlog.drain(..excess);
+        }
+    }
+
+    /// The bytes of the stream from `offset` on, if the backlog still has
+    /// all of them.
+    fn missing_bytes(&self, replid: &str, offset: i64) -> Option<Bytes> {
+        let backlog = self.backlog.as_ref()?;
+        let continues =
+            replid == self.replid || (replid == self.replid2 && offset <= self.second_offset);
+        if !continues || offset < 1 {
+            return None;
+        }
+        let first_byte_offset = self.offset - backlog.len() as u64 + 1;
+        let skipped = (offset as u64).checked_sub(first_byte_offset)?;
+        if skipped > backlog.len() as u64 {
+            return None;
+        }
+        Some(backlog.range(skipped as usize..).copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        parser::RedisValue,
+        replication::{hashed_random_bytes, random_replid, Replication, SyncPoint},
+        request::Request,
+    };
+
+    fn command(parts: &[&str]) -> Vec<u8> {
+        RedisValue::make_bulk_array(parts.iter().map(|part| part.to_string()).collect()).serialize()
+    }
+
+    fn set(key: &str) -> Request {
+        Request::Set(key.to_owned(), "1".to_owned(), None)
+    }
+
+    #[test]
+    fn should_select_the_database_again_for_new_replicas() {
+        let replication = Replication::default();
+        replication.propagate(&[(2, set("dropped"))]);
+
+        let (_, mut first) = replication.attach("?", -1, "127.0.0.1", "6380");
+        replication.propagate(&[(2, set("a"))]);
+        let (_, mut second) = replication.attach("?", -1, "127.0.0.1", "6380");
+        replication.propagate(&[(2, set("b")), (0, set("c"))]);
+        drop(first.writes.try_recv());
+
+        let mut expected = command(&["MULTI"]);
+        expected.extend(command(&["SELECT", "2"]));
+        expected.extend(command(&["SET", "b", "1"]));
+        expected.extend(command(&["SELECT", "0"]));
+        expected.extend(command(&["SET", "c", "1"]));
+        expected.extend(command(&["EXEC"]));
+        assert_eq!(first.writes.try_recv().unwrap(), expected);
+        assert_eq!(second.writes.try_recv().unwrap(), expected);
+        assert!(second.writes.try_recv().is_err());
+
+        drop(first);
+        assert_eq!(replication.replica_count(), 1);
+    }
+
+    #[test]
+    fn should_continue_from_the_backlog() {
+        let replication = Replication::default();
+        let (replid, start) = match replication.attach("?", -1, "127.0.0.1", "6380") {
+            (SyncPoint::Full { replid, offset }, _) => (replid, offset),
+            _ => panic!("a new replica needs a full synchronization"),
+        };
+        assert_eq!(start, 0);
+        replication.propagate(&[(0, set("a"))]);
+        let received = [command(&["SELECT", "0"]), command(&["SET", "a", "1"])].concat();
+        replication.propagate(&[(0, set("b"))]);
+        let offset = received.len() + command(&["SET", "b", "1"]).len();
+        assert!(replication
+            .info()
+            .contains(&format!("master_repl_offset:{offset}\n")));
+
+        let received = received.len() as i64;
+        match replication.attach(&replid, received + 1, "127.0.0.1", "6380") {
+            (SyncPoint::Partial { missing, .. }, _) => {
+                assert_eq!(missing, command(&["SET", "b", "1"]))
+            }
+            _ => panic!("the backlog has the missing writes"),
+        }
+        assert!(matches!(
+            replication.attach("another", received + 1, "127.0.0.1", "6380"),
+            (SyncPoint::Full { .. }, _)
+        ));
+
+        // Once the backlog is too small, only a full synchronization works.
+        replication.set_backlog_size(4);
+        assert!(matches!(
+            replication.attach(&replid, received + 1, "127.0.0.1", "6380"),
+            (SyncPoint::Full { .. }, _)
+        ));
+    }
+
+    #[test]
+    fn should_let_siblings_continue_after_a_promotion() {
+        let replication = Replication::default();
+        let master_replid = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+        replication.follow(master_replid, 100);
+        // Writes made on a replica are not its master's stream.
+        replication.propagate(&[(0, set("local"))]);
+        replication.feed(&command(&["SET", "a", "1"]));
+        let offset = replication.get_offset() as i64;
+
+        replication.promote();
+        replication.propagate(&[(0, set("b"))]);
+        let info = replication.info();
+        assert!(info.contains(&format!("master_replid2:{master_replid}\n")));
+        assert!(info.contains(&format!("second_repl_offset:{}\n", offset + 1)));
+        assert!(!info.contains(&format!("master_replid:{master_replid}")));
+
+        match replication.attach(master_replid, offset + 1, "127.0.0.1", "6380") {
+            (SyncPoint::Partial { missing, .. }, _) => assert_eq!(
+                missing,
+                [command(&["SELECT", "0"]), command(&["SET", "b", "1"])].concat()
+            ),
+            _ => panic!("a sibling can continue from where the old stream ended"),
+        }
+        assert!(matches!(
+            replication.attach(master_replid, offset + 2, "127.0.0.1", "6380"),
+            (SyncPoint::Full { .. }, _)
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_wait_for_replicas_to_acknowledge_the_stream() {
+        let replication = Arc::new(Replication::default());
+        let (_, mut first) = replication.attach("?", -1, "127.0.0.1", "6380");
+        let (_, second) = replication.attach("?", -1, "127.0.0.1", "6381");
+        replication.propagate(&[(0, set("a"))]);
+        let offset = replication.get_offset();
+        assert_eq!(replication.count_acked(offset, false), 0);
+
+        let timeout = Some(Duration::from_millis(10));
+        assert_eq!(
+            replication.wait_for_acks(offset, 1, false, timeout).await,
+            0
+        );
+        // Waiting asked the replicas for an ACK through the stream.
+        first.writes.try_recv().unwrap();
+        assert_eq!(
+            first.writes.try_recv().unwrap(),
+            command(&["REPLCONF", "GETACK", "*"])
+        );
+
+        let acking = replication.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            acking.ack(second.id, offset, None);
+        });
+        assert_eq!(replication.wait_for_acks(offset, 1, false, None).await, 1);
+        assert_eq!(replication.count_acked(offset, true), 0);
+        assert!(replication
+            .info()
+            .contains("connected_slaves:2\nslave0:ip=127.0.0.1,port=6380"));
+    }
+
+    #[test]
+    fn should_generate_random_replids() {
+        let (a, b) = (random_replid(), random_replid());
+        assert_eq!(a.len(), 40);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+        assert_ne!(hashed_random_bytes(), hashed_random_bytes());
+    }
+}
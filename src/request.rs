@@ -1,5 +1,19 @@
-use crate::{config::SystemConfigArc, store::StoreArc};
-use std::{collections::VecDeque, time::Duration};
+use crate::{
+    aof::parse_append_fsync,
+    config::{parse_memory, parse_yes_no, yes_no, SystemConfigArc},
+    functions::RestorePolicy,
+    pubsub::Subscriber,
+    rdb,
+    scripting::{run_function, run_script},
+    slave::{replicate, stop_replicating},
+    store::{Keyspace, StoreArc},
+    value::Entry,
+};
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::runtime::{Handle, RuntimeFlavor};
 
 use anyhow::{anyhow, Ok, Result};
 
@@ -9,70 +23,1243 @@ use crate::parser::RedisValue;
 pub enum Request {
     Ping,
     Echo(String),
-    Set(String, String, Option<Duration>),
+    Set(String, String, Option<Expiry>),
     Get(String),
     ConfigGet(String),
+    ConfigSet(String, String),
     KEYS(String),
     INFO,
-    REPLCONF,
-    PSYNC,
+    /// Option names, lowercased, with their values.
+    REPLCONF(Vec<(String, String)>),
+    /// Replication ID and offset of the first byte the replica is missing.
+    PSYNC(String, i64),
+    Publish(String, String),
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    PSubscribe(Vec<String>),
+    PUnsubscribe(Vec<String>),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<String>),
+    Unwatch,
+    Eval(String, Vec<String>, Vec<String>),
+    EvalSha(String, Vec<String>, Vec<String>),
+    ScriptLoad(String),
+    ScriptExists(Vec<String>),
+    ScriptFlush,
+    FunctionLoad(String, bool),
+    FunctionDelete(String),
+    FunctionList(Option<String>, bool),
+    FunctionDump,
+    FunctionRestore(Vec<u8>, RestorePolicy),
+    FunctionFlush,
+    FCall(String, Vec<String>, Vec<String>),
+    FCallRo(String, Vec<String>, Vec<String>),
+    Del(Vec<String>),
+    Unlink(Vec<String>),
+    Exists(Vec<String>),
+    Type(String),
+    Rename(String, String),
+    RenameNx(String, String),
+    /// Source, destination, destination database and whether to replace.
+    Copy(String, String, Option<usize>, bool),
+    Touch(Vec<String>),
+    RandomKey,
+    DbSize,
+    ObjectEncoding(String),
+    ObjectIdleTime(String),
+    ObjectFreq(String),
+    ObjectRefCount(String),
+    Dump(String),
+    /// Key, TTL in milliseconds (0 for none), DUMP payload and options.
+    Restore(String, u64, Vec<u8>, RestoreOptions),
+    Select(usize),
+    SwapDb(usize, usize),
+    Move(String, usize),
+    /// Flushes the selected database, freeing memory in the background when
+    /// the flag is set.
+    FlushDb(bool),
+    FlushAll(bool),
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    /// Number of replicas and timeout in milliseconds, 0 to block forever.
+    Wait(usize, u64),
+    /// Number of local AOFs and of replicas, and timeout in milliseconds.
+    WaitAof(usize, usize, u64),
+    /// Host and port of the master to replicate, or none to become a master.
+    ReplicaOf(Option<(String, String)>),
+}
+
+/// Expiration given to SET.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    /// Time to live, from EX or PX.
+    In(Duration),
+    /// Deadline, from EXAT or PXAT. Deadlines already passed expire the key
+    /// right away.
+    At(SystemTime),
+}
+
+/// Modifiers of RESTORE.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RestoreOptions {
+    pub replace: bool,
+    /// Whether the TTL is a Unix time in milliseconds rather than relative.
+    pub absttl: bool,
+    /// Seconds since the last access, for the LRU clock.
+    pub idle_time: Option<u64>,
+    /// LFU counter of the key.
+    pub freq: Option<u8>,
+}
+
+impl Request {
+    /// Whether the command modifies the dataset and has to reach replicas.
+    /// Every command is listed so new ones have to be classified. Scripts
+    /// are reads here: the commands they call are classified on their own.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set(_, _, _)
+            | Request::FunctionLoad(_, _)
+            | Request::FunctionDelete(_)
+            | Request::FunctionRestore(_, _)
+            | Request::FunctionFlush
+            | Request::Del(_)
+            | Request::Unlink(_)
+            | Request::Rename(_, _)
+            | Request::RenameNx(_, _)
+            | Request::Copy(_, _, _, _)
+            | Request::Restore(_, _, _, _)
+            | Request::SwapDb(_, _)
+            | Request::Move(_, _)
+            | Request::FlushDb(_)
+            | Request::FlushAll(_) => true,
+            Request::Ping
+            | Request::Echo(_)
+            | Request::Get(_)
+            | Request::ConfigGet(_)
+            | Request::ConfigSet(_, _)
+            | Request::KEYS(_)
+            | Request::INFO
+            | Request::REPLCONF(_)
+            | Request::PSYNC(_, _)
+            | Request::Publish(_, _)
+            | Request::Subscribe(_)
+            | Request::Unsubscribe(_)
+            | Request::PSubscribe(_)
+            | Request::PUnsubscribe(_)
+            | Request::Multi
+            | Request::Exec
+            | Request::Discard
+            | Request::Watch(_)
+            | Request::Unwatch
+            | Request::Eval(_, _, _)
+            | Request::EvalSha(_, _, _)
+            | Request::ScriptLoad(_)
+            | Request::ScriptExists(_)
+            | Request::ScriptFlush
+            | Request::FunctionList(_, _)
+            | Request::FunctionDump
+            | Request::FCall(_, _, _)
+            | Request::FCallRo(_, _, _)
+            | Request::Exists(_)
+            | Request::Type(_)
+            | Request::Touch(_)
+            | Request::RandomKey
+            | Request::DbSize
+            | Request::ObjectEncoding(_)
+            | Request::ObjectIdleTime(_)
+            | Request::ObjectFreq(_)
+            | Request::ObjectRefCount(_)
+            | Request::Dump(_)
+            | Request::Select(_)
+            | Request::Save
+            | Request::BgSave
+            | Request::LastSave
+            | Request::BgRewriteAof
+            | Request::Wait(_, _)
+            | Request::WaitAof(_, _, _)
+            | Request::ReplicaOf(_) => false,
+        }
+    }
+
+    /// Commands that manage the connection or could break a script's
+    /// atomicity are rejected from `redis.call`.
+    fn is_allowed_in_script(&self) -> bool {
+        !self.is_subscription()
+            && !matches!(
+                self,
+                Request::Multi
+                    | Request::Exec
+                    | Request::Discard
+                    | Request::Watch(_)
+                    | Request::Unwatch
+                    | Request::Eval(_, _, _)
+                    | Request::EvalSha(_, _, _)
+                    | Request::ScriptLoad(_)
+                    | Request::ScriptExists(_)
+                    | Request::ScriptFlush
+                    | Request::FunctionLoad(_, _)
+                    | Request::FunctionDelete(_)
+                    | Request::FunctionList(_, _)
+                    | Request::FunctionDump
+                    | Request::FunctionRestore(_, _)
+                    | Request::FunctionFlush
+                    | Request::FCall(_, _, _)
+                    | Request::FCallRo(_, _, _)
+                    | Request::REPLCONF(_)
+                    | Request::PSYNC(_, _)
+                    | Request::Save
+                    | Request::BgSave
+                    | Request::BgRewriteAof
+                    | Request::Wait(_, _)
+                    | Request::WaitAof(_, _, _)
+                    | Request::ReplicaOf(_)
+            )
+    }
+
+    /// The command that reproduces a write when replayed from the AOF or
+    /// applied by a replica. Relative expirations become absolute so they do
+    /// not restart on replay.
+    pub fn to_command(&self) -> Option<RedisValue> {
+        let bulk = |parts: &[&str]| {
+            RedisValue::make_bulk_array(parts.iter().map(|part| part.to_string()).collect())
+        };
+        let command = match self {
+            Request::Set(key, val, None) => bulk(&["SET", key, val]),
+            Request::Set(key, val, Some(expiry)) => {
+                let deadline = match expiry {
+                    Expiry::In(ttl) => deadline_after(*ttl),
+                    Expiry::At(deadline) => *deadline,
+                };
+                let millis = deadline.duration_since(UNIX_EPOCH).ok()?.as_millis();
+                bulk(&["SET", key, val, "PXAT", &millis.to_string()])
+            }
+            Request::FunctionLoad(code, true) => bulk(&["FUNCTION", "LOAD", "REPLACE", code]),
+            Request::FunctionLoad(code, false) => bulk(&["FUNCTION", "LOAD", code]),
+            Request::FunctionDelete(name) => bulk(&["FUNCTION", "DELETE", name]),
+            Request::FunctionFlush => bulk(&["FUNCTION", "FLUSH"]),
+            Request::FunctionRestore(payload, policy) => RedisValue::Array(vec![
+                RedisValue::BulkString("FUNCTION".to_owned()),
+                RedisValue::BulkString("RESTORE".to_owned()),
+                RedisValue::BulkBytes(payload.clone()),
+                RedisValue::BulkString(
+                    match policy {
+                        RestorePolicy::Append => "APPEND",
+                        RestorePolicy::Replace => "REPLACE",
+                        RestorePolicy::Flush => "FLUSH",
+                    }
+                    .to_owned(),
+                ),
+            ]),
+            Request::Del(keys) => RedisValue::make_bulk_array(
+                std::iter::once("DEL".to_owned())
+                    .chain(keys.iter().cloned())
+                    .collect(),
+            ),
+            Request::Unlink(keys) => RedisValue::make_bulk_array(
+                std::iter::once("UNLINK".to_owned())
+                    .chain(keys.iter().cloned())
+                    .collect(),
+            ),
+            Request::Rename(from, to) => bulk(&["RENAME", from, to]),
+            Request::RenameNx(from, to) => bulk(&["RENAMENX", from, to]),
+            Request::Copy(source, destination, db, replace) => {
+                let mut command = vec!["COPY".to_owned(), source.clone(), destination.clone()];
+                if let Some(db) = db {
+                    command.push("DB".to_owned());
+                    command.push(db.to_string());
+                }
+                if *replace {
+                    command.push("REPLACE".to_owned());
+                }
+                RedisValue::make_bulk_array(command)
+            }
+            Request::Restore(key, ttl, payload, options) => {
+                let ttl = match ttl {
+                    0 => 0,
+                    ttl if options.absttl => *ttl,
+                    ttl => {
+                        let deadline = SystemTime::now() + Duration::from_millis(*ttl);
+                        deadline.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64
+                    }
+                };
+                let mut command = vec![
+                    RedisValue::BulkString("RESTORE".to_owned()),
+                    RedisValue::BulkString(key.clone()),
+                    RedisValue::BulkString(ttl.to_string()),
+                    RedisValue::BulkBytes(payload.clone()),
+                ];
+                let mut modifiers = vec![];
+                if options.replace {
+                    modifiers.push("REPLACE".to_owned());
+                }
+                if ttl > 0 {
+                    modifiers.push("ABSTTL".to_owned());
+                }
+                if let Some(idle_time) = options.idle_time {
+                    modifiers.extend(["IDLETIME".to_owned(), idle_time.to_string()]);
+                }
+                if let Some(freq) = options.freq {
+                    modifiers.extend(["FREQ".to_owned(), freq.to_string()]);
+                }
+                command.extend(modifiers.into_iter().map(RedisValue::BulkString));
+                RedisValue::Array(command)
+            }
+            Request::SwapDb(a, b) => bulk(&["SWAPDB", &a.to_string(), &b.to_string()]),
+            Request::Move(key, db) => bulk(&["MOVE", key, &db.to_string()]),
+            Request::FlushDb(true) => bulk(&["FLUSHDB", "ASYNC"]),
+            Request::FlushDb(false) => bulk(&["FLUSHDB"]),
+            Request::FlushAll(true) => bulk(&["FLUSHALL", "ASYNC"]),
+            Request::FlushAll(false) => bulk(&["FLUSHALL"]),
+            _ => return None,
+        };
+        Some(command)
+    }
+
+    /// Turns relative expirations into deadlines before the command runs, so
+    /// the key expires at the same millisecond here, in the AOF and on the
+    /// replicas.
+    fn with_absolute_expiry(self) -> Request {
+        match self {
+            Request::Set(key, val, Some(Expiry::In(ttl))) => {
+                Request::Set(key, val, Some(Expiry::At(deadline_after(ttl))))
+            }
+            Request::Restore(key, ttl, payload, mut options) if ttl > 0 && !options.absttl => {
+                let deadline = deadline_after(Duration::from_millis(ttl));
+                let ttl = deadline
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64);
+                options.absttl = true;
+                Request::Restore(key, ttl, payload, options)
+            }
+            req => req,
+        }
+    }
+
+    pub fn is_subscription(&self) -> bool {
+        matches!(
+            self,
+            Request::Subscribe(_)
+                | Request::Unsubscribe(_)
+                | Request::PSubscribe(_)
+                | Request::PUnsubscribe(_)
+        )
+    }
+}
+
+/// The deadline `ttl` from now, truncated to the millisecond precision of
+/// PXAT.
+fn deadline_after(ttl: Duration) -> SystemTime {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    UNIX_EPOCH + Duration::from_millis((now + ttl).as_millis() as u64)
+}
+
+/// Runs a script without stalling the other tasks of the worker thread.
+/// The single threaded runtime of tests cannot hand its tasks over, so the
+/// script runs in place there.
+fn run_blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Result::Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+fn read_only_error() -> RedisValue {
+    RedisValue::Error("READONLY You can't write against a read only replica.".to_owned())
+}
+
+/// A write command with the database it was applied to.
+pub type WriteEffect = (usize, Request);
+
+/// Appends the commands that reproduce the writes of one request to `buf`,
+/// as logged in the AOF and streamed to replicas. A SELECT precedes any
+/// command for another database than `selected_db`, which is updated, and
+/// several writes are wrapped in MULTI/EXEC so they apply all or none.
+pub fn serialize_effects(
+    effects: &[WriteEffect],
+    selected_db: &mut Option<usize>,
+    buf: &mut Vec<u8>,
+) {
+    let commands: Vec<_> = effects
+        .iter()
+        .filter_map(|(db, req)| Some((*db, req.to_command()?)))
+        .collect();
+    let transaction = commands.len() > 1;
+    if transaction {
+        buf.extend(RedisValue::make_bulk_array(vec!["MULTI".to_owned()]).serialize());
+    }
+    for (db, command) in commands {
+        if *selected_db != Some(db) {
+            let select = RedisValue::make_bulk_array(vec!["SELECT".to_owned(), db.to_string()]);
+            buf.extend(select.serialize());
+            *selected_db = Some(db);
+        }
+        buf.extend(command.serialize());
+    }
+    if transaction {
+        buf.extend(RedisValue::make_bulk_array(vec!["EXEC".to_owned()]).serialize());
+    }
+}
+
+/// Commands queued between MULTI and EXEC.
+#[derive(Default)]
+struct Transaction {
+    queued: Vec<Request>,
+    /// Set when a command failed to queue, which makes EXEC abort.
+    aborted: bool,
 }
 
 pub struct RequestHandler {
     store: StoreArc,
     config: SystemConfigArc,
+    subscriber: Subscriber,
+    transaction: Option<Transaction>,
+    /// Database selected with SELECT.
+    db: usize,
+    /// Keys watched by this connection with their database and the version
+    /// seen at WATCH time.
+    watched: Vec<(usize, String, u64)>,
+    /// Write commands applied by the last request, including the ones run
+    /// by EXEC and scripts, in the order they modified the dataset.
+    write_effects: Vec<WriteEffect>,
+    /// Number of `write_effects` already logged and replicated.
+    propagated: usize,
+    /// Bytes of the master's stream read by this replication link and not
+    /// yet applied, passed on to this server's replicas once they are.
+    master_stream: Vec<u8>,
+    /// Replication offset right after the last write of this connection,
+    /// which WAIT waits for replicas to reach.
+    write_offset: u64,
+    /// Port announced with REPLCONF listening-port by a replica.
+    listening_port: Option<String>,
+    /// Whether this connection applies the master's stream, which writes
+    /// even when replicas are read-only.
+    master_link: bool,
+    /// Number of commands that changed the dataset. Writes that leave it as
+    /// it was, like DEL of a missing key, are neither logged nor replicated.
+    dirty: u64,
 }
 impl RequestHandler {
     pub fn new(store: StoreArc, config: SystemConfigArc) -> Self {
-        RequestHandler { store, config }
+        let subscriber = Subscriber::new(store.get_pubsub());
+        RequestHandler {
+            store,
+            config,
+            subscriber,
+            transaction: None,
+            db: 0,
+            watched: vec![],
+            write_effects: vec![],
+            propagated: 0,
+            master_stream: vec![],
+            write_offset: 0,
+            listening_port: None,
+            master_link: false,
+            dirty: 0,
+        }
+    }
+
+    /// A handler for the stream of the master this server replicates.
+    pub fn for_master_link(store: StoreArc, config: SystemConfigArc) -> Self {
+        let mut handler = RequestHandler::new(store, config);
+        handler.master_link = true;
+        handler
+    }
+
+    /// Returns the writes performed by the last request so they can be
+    /// propagated to replicas.
+    pub fn take_write_effects(&mut self) -> Vec<WriteEffect> {
+        self.propagated = 0;
+        std::mem::take(&mut self.write_effects)
+    }
+
+    /// Port the replica on this connection listens on, if it announced it.
+    pub fn get_listening_port(&self) -> Option<String> {
+        self.listening_port.clone()
+    }
+
+    /// Drops what was read of a master's stream that was cut off, with the
+    /// transaction it left open. The selected database stays, since a
+    /// partial synchronization continues the stream where it was applied.
+    pub fn discard_master_stream(&mut self) {
+        self.master_stream.clear();
+        self.transaction = None;
+    }
+
+    /// Records the bytes of the master's stream that the next request was
+    /// read from.
+    pub fn record_master_stream(&mut self, bytes: &[u8]) {
+        self.master_stream.extend_from_slice(bytes);
+    }
+
+    /// Replies to a command that could not be parsed, aborting the open
+    /// transaction if there is one.
+    pub fn handle_invalid_request(&mut self, err: anyhow::Error) -> RedisValue {
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.aborted = true;
+        }
+        RedisValue::Error(format!("ERR {err}"))
+    }
+
+    /// Subscription commands reply with one message per channel, so they are
+    /// handled separately from [`RequestHandler::handle_request`].
+    pub fn handle_subscription(&mut self, req: Request) -> Vec<RedisValue> {
+        if self.transaction.is_some() {
+            return vec![
+                self.handle_invalid_request(anyhow!("Command not allowed inside a transaction"))
+            ];
+        }
+        match req {
+            Request::Subscribe(channels) => self.subscriber.subscribe(channels),
+            Request::Unsubscribe(channels) => self.subscriber.unsubscribe(channels),
+            Request::PSubscribe(patterns) => self.subscriber.psubscribe(patterns),
+            Request::PUnsubscribe(patterns) => self.subscriber.punsubscribe(patterns),
+            _ => vec![RedisValue::Error(
+                "ERR not a subscription command".to_owned(),
+            )],
+        }
+    }
+
+    /// Waits for the next message published to this connection's subscriptions.
+    pub async fn next_message(&mut self) -> Option<RedisValue> {
+        self.subscriber.recv().await
     }
 
     pub async fn handle_request(&mut self, req: Request) -> RedisValue {
+        self.write_effects.clear();
+        self.propagated = 0;
+        let reply = self.dispatch(req).await;
+        if !self.write_effects.is_empty() {
+            if let Err(e) = self.store.get_aof().flush() {
+                eprintln!("failed to write to the append only file: {e}");
+            }
+        }
+        reply
+    }
+
+    async fn dispatch(&mut self, req: Request) -> RedisValue {
+        if self.subscriber.is_subscribed() && !matches!(req, Request::Ping) {
+            return RedisValue::Error(
+                "ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context"
+                    .to_owned(),
+            );
+        }
+        match req {
+            Request::Multi => {
+                if self.transaction.is_some() {
+                    return RedisValue::Error("ERR MULTI calls can not be nested".to_owned());
+                }
+                self.transaction = Some(Transaction::default());
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::Exec => self.exec().await,
+            Request::Wait(replicas, timeout) if self.transaction.is_none() => {
+                if self.store.get_replication().is_replica() {
+                    return RedisValue::Error(
+                        "ERR WAIT cannot be used with replica instances.".to_owned(),
+                    );
+                }
+                let timeout = (timeout > 0).then(|| Duration::from_millis(timeout));
+                let acked = self
+                    .store
+                    .get_replication()
+                    .wait_for_acks(self.write_offset, replicas, false, timeout)
+                    .await;
+                RedisValue::Integer(acked as i64)
+            }
+            Request::WaitAof(local, replicas, timeout) if self.transaction.is_none() => {
+                if self.store.get_replication().is_replica() && replicas > 0 {
+                    return RedisValue::Error(
+                        "ERR WAITAOF cannot be used with replica instances.".to_owned(),
+                    );
+                }
+                let local = match self.sync_aof(local) {
+                    Result::Ok(local) => local,
+                    Err(e) => return RedisValue::Error(format!("ERR {e}")),
+                };
+                let timeout = (timeout > 0).then(|| Duration::from_millis(timeout));
+                let acked = self
+                    .store
+                    .get_replication()
+                    .wait_for_acks(self.write_offset, replicas, true, timeout)
+                    .await;
+                RedisValue::Array(vec![
+                    RedisValue::Integer(local as i64),
+                    RedisValue::Integer(acked as i64),
+                ])
+            }
+            Request::Discard => {
+                if self.transaction.take().is_none() {
+                    return RedisValue::Error("ERR DISCARD without MULTI".to_owned());
+                }
+                self.unwatch_all(&mut *self.store.clone().lock().await);
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::Watch(keys) => {
+                if self.transaction.is_some() {
+                    return RedisValue::Error("ERR WATCH inside MULTI is not allowed".to_owned());
+                }
+                let store = self.store.clone();
+                let mut keyspace = store.lock().await;
+                for key in keys {
+                    let version = keyspace.db(self.db).watch(&key);
+                    self.watched.push((self.db, key, version));
+                }
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::Unwatch => {
+                self.unwatch_all(&mut *self.store.clone().lock().await);
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            req => {
+                if self.is_read_only_for(&req) {
+                    if let Some(transaction) = self.transaction.as_mut() {
+                        transaction.aborted = true;
+                    }
+                    return read_only_error();
+                }
+                if let Some(transaction) = self.transaction.as_mut() {
+                    transaction.queued.push(req);
+                    return RedisValue::SimpleString("QUEUED".to_owned());
+                }
+                let store = self.store.clone();
+                let mut keyspace = store.lock().await;
+                let reply = self.execute(&mut keyspace, req);
+                self.propagate();
+                reply
+            }
+        }
+    }
+
+    /// Runs the queued commands while holding the store lock, so no other
+    /// client can observe or modify the keyspace halfway through.
+    async fn exec(&mut self) -> RedisValue {
+        let Some(transaction) = self.transaction.take() else {
+            return RedisValue::Error("ERR EXEC without MULTI".to_owned());
+        };
+        let store = self.store.clone();
+        let mut keyspace = store.lock().await;
+        let watch_failed = self
+            .watched
+            .iter()
+            .any(|(db, key, version)| keyspace.db(*db).is_modified_since(key, *version));
+        self.unwatch_all(&mut keyspace);
+        if transaction.aborted {
+            return RedisValue::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_owned(),
+            );
+        }
+        if watch_failed {
+            return RedisValue::NullArray;
+        }
+        let results = transaction
+            .queued
+            .into_iter()
+            .map(|req| self.execute(&mut keyspace, req))
+            .collect();
+        self.propagate();
+        RedisValue::Array(results)
+    }
+
+    /// Logs and replicates the writes applied since the last call. Called
+    /// with the keyspace still locked, so a snapshot never includes writes
+    /// missing from the stream, or the other way around.
+    fn propagate(&mut self) {
+        let pending = &self.write_effects[self.propagated..];
+        self.store.propagate(pending);
+        if !pending.is_empty() {
+            self.write_offset = self.store.get_replication().get_offset();
+        }
+        self.propagated = self.write_effects.len();
+        if !self.master_stream.is_empty() {
+            let bytes = std::mem::take(&mut self.master_stream);
+            self.store.get_replication().feed(&bytes);
+        }
+    }
+
+    /// Syncs the AOF to disk for WAITAOF, returning 1 if this server has
+    /// one. `local` AOFs can only be waited for with appendonly enabled.
+    fn sync_aof(&self, local: usize) -> Result<usize> {
+        let aof = self.store.get_aof();
+        if !aof.is_enabled() {
+            if local > 0 {
+                return Err(anyhow!(
+                    "WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+                ));
+            }
+            return Ok(0);
+        }
+        aof.flush()?;
+        aof.sync()?;
+        Ok(1)
+    }
+
+    fn unwatch_all(&mut self, keyspace: &mut Keyspace) {
+        for (db, key, _) in self.watched.drain(..) {
+            keyspace.db(db).unwatch(&key);
+        }
+    }
+
+    fn execute(&mut self, keyspace: &mut Keyspace, req: Request) -> RedisValue {
+        let req = req.with_absolute_expiry();
+        let effect = req.is_write().then(|| (self.db, req.clone()));
+        let dirty = self.dirty;
+        let reply = self.execute_command(keyspace, req);
+        // Keys the command found expired are deleted before its own write.
+        self.write_effects.extend(keyspace.take_expired());
+        if let Some(effect) = effect {
+            if self.dirty > dirty {
+                self.store.get_persistence().add_changes(1);
+                self.write_effects.push(effect);
+            }
+        }
+        reply
+    }
+
+    fn eval(
+        &mut self,
+        keyspace: &mut Keyspace,
+        script: &str,
+        keys: Vec<String>,
+        args: Vec<String>,
+    ) -> RedisValue {
+        // SELECT inside a script only affects the script.
+        let db = self.db;
+        let time_limit = self.store.get_script_time_limit();
+        let reply = run_blocking(|| {
+            run_script(script, keys, args, time_limit, |command| {
+                self.call_from_script(keyspace, command, false)
+            })
+        });
+        self.db = db;
+        reply
+    }
+
+    fn fcall(
+        &mut self,
+        keyspace: &mut Keyspace,
+        name: &str,
+        keys: Vec<String>,
+        args: Vec<String>,
+        read_only_call: bool,
+    ) -> RedisValue {
+        let Some((code, flags)) = self.store.get_functions().find(name) else {
+            return RedisValue::Error("ERR Function not found".to_owned());
+        };
+        let no_writes = flags.iter().any(|flag| flag == "no-writes");
+        if read_only_call && !no_writes {
+            return RedisValue::Error(
+                "ERR Can not execute a script with write flag using *_ro command.".to_owned(),
+            );
+        }
+        let db = self.db;
+        let time_limit = self.store.get_script_time_limit();
+        let reply = run_blocking(|| {
+            run_function(&code, name, keys, args, time_limit, |command| {
+                self.call_from_script(keyspace, command, no_writes)
+            })
+        });
+        self.db = db;
+        reply
+    }
+
+    /// Runs a `redis.call` from a script or function against the keyspace the
+    /// caller already locked.
+    fn call_from_script(
+        &mut self,
+        keyspace: &mut Keyspace,
+        command: Vec<String>,
+        read_only: bool,
+    ) -> RedisValue {
+        let request = match get_request(RedisValue::make_bulk_array(command)) {
+            Result::Ok(request) => request,
+            Err(e) => return RedisValue::Error(format!("ERR {e}")),
+        };
+        if !request.is_allowed_in_script() {
+            return RedisValue::Error(
+                "ERR This Redis command is not allowed from script".to_owned(),
+            );
+        }
+        if read_only && request.is_write() {
+            return RedisValue::Error(
+                "ERR Write commands are not allowed from read-only scripts.".to_owned(),
+            );
+        }
+        if self.is_read_only_for(&request) {
+            return read_only_error();
+        }
+        self.execute(keyspace, request)
+    }
+
+    /// Whether the request is a write this connection may not perform
+    /// because the server is a read-only replica.
+    fn is_read_only_for(&self, req: &Request) -> bool {
+        let replication = self.store.get_replication();
+        req.is_write()
+            && !self.master_link
+            && replication.is_replica()
+            && replication.is_read_only()
+    }
+
+    fn execute_command(&mut self, keyspace: &mut Keyspace, req: Request) -> RedisValue {
         match req {
             Request::Ping => RedisValue::SimpleString("PONG".to_string()),
             Request::Echo(s) => RedisValue::BulkString(s),
             Request::Set(key, value, None) => {
-                self.store.set(key, value).await;
+                keyspace.db(self.db).set(key, value);
+                self.dirty += 1;
                 RedisValue::SimpleString("OK".to_string())
             }
 
-            Request::Set(key, value, Some(expire)) => {
-                self.store.set_with_expire(key, value, expire).await;
+            Request::Set(key, value, Some(Expiry::In(ttl))) => {
+                keyspace.db(self.db).set_with_expire(key, value, ttl);
+                self.dirty += 1;
                 RedisValue::SimpleString("OK".to_string())
             }
-
-            Request::Get(key) => {
-                let val = self.store.get(key).await.unwrap_or(String::new());
-                RedisValue::BulkString(val)
+            Request::Set(key, value, Some(Expiry::At(deadline))) => {
+                keyspace.db(self.db).set_with_deadline(key, value, deadline);
+                self.dirty += 1;
+                RedisValue::SimpleString("OK".to_string())
             }
-            Request::ConfigGet(key) => {
-                let val = self.config.get_config(&key).unwrap_or(String::new());
-                RedisValue::make_bulk_array(vec![key,val])
+
+            Request::Get(key) => match keyspace.db(self.db).get(&key) {
+                Result::Ok(value) => {
+                    value.map_or(RedisValue::NullBulkString, RedisValue::BulkString)
+                }
+                Err(e) => RedisValue::Error(e.to_string()),
+            },
+            Request::ConfigGet(key) => match self.get_config(&key) {
+                Some(val) => RedisValue::make_bulk_array(vec![key, val]),
+                None => RedisValue::Array(vec![]),
+            },
+            Request::ConfigSet(key, value) => {
+                if let Err(e) = self.set_config(&key, &value) {
+                    return RedisValue::Error(format!("ERR {e}"));
+                }
+                RedisValue::SimpleString("OK".to_owned())
             }
             Request::KEYS(pattern) => {
-                assert!(pattern == "*");
-                let key = self.store.get_matching_keys(pattern).await;
+                let key = keyspace.db(self.db).get_matching_keys(&pattern);
                 RedisValue::make_bulk_array(key)
             }
-            Request::INFO => {
-                let replicatioin_config = self.config.get_replication_config().to_string();
-                RedisValue::BulkString(replicatioin_config)
-            }
-            Request::REPLCONF => RedisValue::SimpleString("OK".to_owned()),
-            Request::PSYNC => {
-                let replication_config = self.config.get_replication_config();
-                let resp = format!(
-                    "FULLRESYNC {} {}",
-                    replication_config.get_id(),
-                    replication_config.get_offset()
-                );
-                RedisValue::SimpleString(resp)
+            Request::INFO => RedisValue::BulkString(self.store.get_replication().info()),
+            Request::REPLCONF(options) => {
+                for (option, value) in options {
+                    match option.as_str() {
+                        "listening-port" => self.listening_port = Some(value),
+                        // Capabilities only matter for the RDB transfer, which
+                        // is always sent with its length. ACKs only come on
+                        // replication links, and GETACK is answered by the
+                        // replica's link to its master.
+                        "capa" | "ack" | "fack" | "getack" => {}
+                        _ => {
+                            return RedisValue::Error(format!(
+                                "ERR Unrecognized REPLCONF option: {option}"
+                            ))
+                        }
+                    }
+                }
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::ReplicaOf(None) => {
+                stop_replicating(&self.store);
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::ReplicaOf(Some((ip, port))) => {
+                if self.store.get_replication().get_master() == Some((ip.clone(), port.clone())) {
+                    return RedisValue::SimpleString(
+                        "OK Already connected to specified master".to_owned(),
+                    );
+                }
+                replicate(&self.store, self.config.clone(), ip, port);
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            // Inside a transaction WAIT does not block, like in Redis.
+            Request::Wait(_, _) => {
+                let acked = self
+                    .store
+                    .get_replication()
+                    .count_acked(self.write_offset, false);
+                RedisValue::Integer(acked as i64)
+            }
+            Request::WaitAof(_, _, _) => {
+                let acked = self
+                    .store
+                    .get_replication()
+                    .count_acked(self.write_offset, true);
+                let local = self.store.get_aof().is_enabled() as i64;
+                RedisValue::Array(vec![
+                    RedisValue::Integer(local),
+                    RedisValue::Integer(acked as i64),
+                ])
+            }
+            // Connections serve PSYNC themselves, since it turns them into
+            // a replication link.
+            Request::PSYNC(_, _) => {
+                RedisValue::Error("ERR Command not allowed inside a transaction".to_owned())
+            }
+            Request::Publish(channel, message) => {
+                let receivers = self.store.get_pubsub().publish(&channel, &message);
+                RedisValue::Integer(receivers as i64)
+            }
+            Request::Subscribe(_)
+            | Request::Unsubscribe(_)
+            | Request::PSubscribe(_)
+            | Request::PUnsubscribe(_) => RedisValue::Error(
+                "ERR subscriptions are only allowed on client connections".to_owned(),
+            ),
+            Request::Multi => RedisValue::Error("ERR MULTI calls can not be nested".to_owned()),
+            Request::Exec | Request::Discard => RedisValue::Error(
+                "ERR EXEC and DISCARD are not allowed in a transaction".to_owned(),
+            ),
+            Request::Watch(_) => {
+                RedisValue::Error("ERR WATCH inside MULTI is not allowed".to_owned())
+            }
+            Request::Unwatch => RedisValue::SimpleString("OK".to_owned()),
+            Request::Eval(script, keys, args) => {
+                self.store.get_scripts().load(&script);
+                self.eval(keyspace, &script, keys, args)
+            }
+            Request::EvalSha(sha, keys, args) => match self.store.get_scripts().get(&sha) {
+                Some(script) => self.eval(keyspace, &script, keys, args),
+                None => {
+                    RedisValue::Error("NOSCRIPT No matching script. Please use EVAL.".to_owned())
+                }
+            },
+            Request::ScriptLoad(script) => {
+                RedisValue::BulkString(self.store.get_scripts().load(&script))
+            }
+            Request::ScriptExists(shas) => RedisValue::Array(
+                shas.iter()
+                    .map(|sha| RedisValue::Integer(self.store.get_scripts().exists(sha) as i64))
+                    .collect(),
+            ),
+            Request::ScriptFlush => {
+                self.store.get_scripts().flush();
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::FunctionLoad(code, replace) => {
+                match self.store.get_functions().load(&code, replace) {
+                    Result::Ok(name) => {
+                        self.dirty += 1;
+                        RedisValue::BulkString(name)
+                    }
+                    Err(e) => RedisValue::Error(format!("ERR {e}")),
+                }
+            }
+            Request::FunctionDelete(name) => match self.store.get_functions().delete(&name) {
+                Result::Ok(()) => {
+                    self.dirty += 1;
+                    RedisValue::SimpleString("OK".to_owned())
+                }
+                Err(e) => RedisValue::Error(format!("ERR {e}")),
+            },
+            Request::FunctionList(pattern, with_code) => self
+                .store
+                .get_functions()
+                .list(pattern.as_deref(), with_code),
+            Request::FunctionDump => RedisValue::BulkBytes(self.store.get_functions().dump()),
+            Request::FunctionRestore(payload, policy) => {
+                match self.store.get_functions().restore(&payload, policy) {
+                    Result::Ok(()) => {
+                        self.dirty += 1;
+                        RedisValue::SimpleString("OK".to_owned())
+                    }
+                    Err(e) => RedisValue::Error(format!("ERR {e}")),
+                }
+            }
+            Request::FunctionFlush => {
+                self.store.get_functions().flush();
+                self.dirty += 1;
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::FCall(name, keys, args) => self.fcall(keyspace, &name, keys, args, false),
+            Request::FCallRo(name, keys, args) => self.fcall(keyspace, &name, keys, args, true),
+            Request::Del(keys) | Request::Unlink(keys) => {
+                let deleted = keyspace.db(self.db).delete(&keys);
+                self.dirty += (deleted > 0) as u64;
+                RedisValue::Integer(deleted as i64)
+            }
+            Request::Exists(keys) => RedisValue::Integer(keyspace.db(self.db).exists(&keys) as i64),
+            Request::Type(key) => {
+                RedisValue::SimpleString(keyspace.db(self.db).key_type(&key).to_owned())
+            }
+            Request::Rename(from, to) => match keyspace.db(self.db).rename(&from, &to, false) {
+                Result::Ok(_) => {
+                    self.dirty += 1;
+                    RedisValue::SimpleString("OK".to_owned())
+                }
+                Err(e) => RedisValue::Error(format!("ERR {e}")),
+            },
+            Request::RenameNx(from, to) => match keyspace.db(self.db).rename(&from, &to, true) {
+                Result::Ok(renamed) => {
+                    self.dirty += renamed as u64;
+                    RedisValue::Integer(renamed as i64)
+                }
+                Err(e) => RedisValue::Error(format!("ERR {e}")),
+            },
+            Request::Copy(source, destination, db, replace) => {
+                let to = db.unwrap_or(self.db);
+                if to >= keyspace.count() {
+                    return RedisValue::Error("ERR DB index is out of range".to_owned());
+                }
+                if source == destination && to == self.db {
+                    return RedisValue::Error(
+                        "ERR source and destination objects are the same".to_owned(),
+                    );
+                }
+                let copied = keyspace.copy(&source, self.db, &destination, to, replace);
+                self.dirty += copied as u64;
+                RedisValue::Integer(copied as i64)
+            }
+            Request::Touch(keys) => RedisValue::Integer(keyspace.db(self.db).touch(&keys) as i64),
+            Request::RandomKey => keyspace
+                .db(self.db)
+                .random_key()
+                .map_or(RedisValue::NullBulkString, RedisValue::BulkString),
+            Request::DbSize => RedisValue::Integer(keyspace.db(self.db).len() as i64),
+            Request::ObjectEncoding(key) => keyspace
+                .db(self.db)
+                .peek(&key)
+                .map_or(RedisValue::NullBulkString, |entry| {
+                    RedisValue::BulkString(entry.value.encoding().to_owned())
+                }),
+            Request::ObjectIdleTime(key) => keyspace
+                .db(self.db)
+                .peek(&key)
+                .map_or(RedisValue::NullBulkString, |entry| {
+                    RedisValue::Integer(entry.idle_time().as_secs() as i64)
+                }),
+            Request::ObjectFreq(key) => keyspace
+                .db(self.db)
+                .peek(&key)
+                .map_or(RedisValue::NullBulkString, |entry| {
+                    RedisValue::Integer(entry.freq() as i64)
+                }),
+            // Values are never shared between keys, so there is always one
+            // reference.
+            Request::ObjectRefCount(key) => keyspace
+                .db(self.db)
+                .peek(&key)
+                .map_or(RedisValue::NullBulkString, |_| RedisValue::Integer(1)),
+            Request::Dump(key) => keyspace
+                .db(self.db)
+                .get_value(&key)
+                .map_or(RedisValue::NullBulkString, |value| {
+                    RedisValue::BulkBytes(rdb::dump_value(&value))
+                }),
+            Request::Restore(key, ttl, payload, options) => {
+                let value = match rdb::restore_value(&payload) {
+                    Result::Ok(value) => value,
+                    Err(e) => return RedisValue::Error(format!("ERR {e}")),
+                };
+                let mut entry = Entry::new(value);
+                if let Some(idle_time) = options.idle_time {
+                    entry.set_idle_time(Duration::from_secs(idle_time));
+                }
+                if let Some(freq) = options.freq {
+                    entry.set_freq(freq);
+                }
+                let deadline = match ttl {
+                    0 => None,
+                    ttl if options.absttl => Some(UNIX_EPOCH + Duration::from_millis(ttl)),
+                    ttl => Some(SystemTime::now() + Duration::from_millis(ttl)),
+                };
+                match keyspace
+                    .db(self.db)
+                    .restore(&key, entry, deadline, options.replace)
+                {
+                    Result::Ok(()) => {
+                        self.dirty += 1;
+                        RedisValue::SimpleString("OK".to_owned())
+                    }
+                    Err(e) => RedisValue::Error(e.to_string()),
+                }
+            }
+            Request::Select(db) => {
+                if db >= keyspace.count() {
+                    return RedisValue::Error("ERR DB index is out of range".to_owned());
+                }
+                self.db = db;
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::SwapDb(a, b) => {
+                if a >= keyspace.count() || b >= keyspace.count() {
+                    return RedisValue::Error("ERR DB index is out of range".to_owned());
+                }
+                keyspace.swap(a, b);
+                self.dirty += 1;
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::Move(key, db) => {
+                if db >= keyspace.count() {
+                    return RedisValue::Error("ERR DB index is out of range".to_owned());
+                }
+                if db == self.db {
+                    return RedisValue::Error(
+                        "ERR source and destination objects are the same".to_owned(),
+                    );
+                }
+                let moved = keyspace.move_key(&key, self.db, db);
+                self.dirty += moved as u64;
+                RedisValue::Integer(moved as i64)
+            }
+            Request::FlushDb(lazy) => {
+                self.dirty += !keyspace.db(self.db).is_empty() as u64;
+                keyspace.flush_db(self.db, lazy);
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::FlushAll(lazy) => {
+                let count = keyspace.count();
+                self.dirty += (0..count).any(|db| !keyspace.db(db).is_empty()) as u64;
+                keyspace.flush_all(lazy);
+                RedisValue::SimpleString("OK".to_owned())
+            }
+            Request::Save => {
+                let file = self.store.snapshot(keyspace);
+                let persistence = self.store.get_persistence();
+                match persistence.save(&file, &self.config.get_rdb_path()) {
+                    Result::Ok(()) => RedisValue::SimpleString("OK".to_owned()),
+                    Err(e) => RedisValue::Error(format!("ERR {e}")),
+                }
+            }
+            Request::BgSave => {
+                let persistence = self.store.get_persistence();
+                if persistence.is_bgsave_in_progress() {
+                    return RedisValue::Error("ERR Background save already in progress".to_owned());
+                }
+                let file = self.store.snapshot(keyspace);
+                match persistence.background_save(file, self.config.get_rdb_path()) {
+                    Result::Ok(()) => {
+                        RedisValue::SimpleString("Background saving started".to_owned())
+                    }
+                    Err(e) => RedisValue::Error(format!("ERR {e}")),
+                }
+            }
+            Request::LastSave => {
+                RedisValue::Integer(self.store.get_persistence().get_last_save() as i64)
+            }
+            Request::BgRewriteAof => {
+                // The new base holds the writes queued before in the
+                // transaction, so they go to the incremental file it
+                // replaces rather than the one it starts.
+                self.propagate();
+                let file = self.store.snapshot(keyspace);
+                match self.store.get_aof().background_rewrite(file) {
+                    Result::Ok(()) => RedisValue::SimpleString(
+                        "Background append only file rewriting started".to_owned(),
+                    ),
+                    Err(e) => RedisValue::Error(format!("ERR {e}")),
+                }
             }
         }
     }
+
+    fn get_config(&self, key: &str) -> Option<String> {
+        match key {
+            "notify-keyspace-events" => Some(self.store.get_notify_keyspace_events()),
+            "save" => Some(self.store.get_persistence().get_save_rules()),
+            "appendfsync" => Some(self.store.get_aof().get_settings().fsync.to_string()),
+            "aof-use-rdb-preamble" => {
+                Some(yes_no(self.store.get_aof().get_settings().use_rdb_preamble))
+            }
+            "auto-aof-rewrite-percentage" => Some(
+                self.store
+                    .get_aof()
+                    .get_settings()
+                    .rewrite_percentage
+                    .to_string(),
+            ),
+            "auto-aof-rewrite-min-size" => Some(
+                self.store
+                    .get_aof()
+                    .get_settings()
+                    .rewrite_min_size
+                    .to_string(),
+            ),
+            "repl-backlog-size" => {
+                Some(self.store.get_replication().get_backlog_size().to_string())
+            }
+            "replica-read-only" | "slave-read-only" => {
+                Some(yes_no(self.store.get_replication().is_read_only()))
+            }
+            "busy-reply-threshold" | "lua-time-limit" => {
+                Some(self.store.get_busy_reply_threshold().to_string())
+            }
+            _ => self.config.get_config(key),
+        }
+    }
+
+    fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "notify-keyspace-events" => self.store.set_notify_keyspace_events(value),
+            "save" => self.store.get_persistence().set_save_rules(value),
+            "appendfsync"
+            | "aof-use-rdb-preamble"
+            | "auto-aof-rewrite-percentage"
+            | "auto-aof-rewrite-min-size" => {
+                let aof = self.store.get_aof();
+                let mut settings = aof.get_settings();
+                match key {
+                    "appendfsync" => settings.fsync = parse_append_fsync(value)?,
+                    "aof-use-rdb-preamble" => settings.use_rdb_preamble = parse_yes_no(value)?,
+                    "auto-aof-rewrite-percentage" => {
+                        settings.rewrite_percentage = value
+                            .parse()
+                            .map_err(|_| anyhow!("argument must be a number"))?
+                    }
+                    _ => settings.rewrite_min_size = parse_memory(value)?,
+                }
+                aof.set_settings(settings);
+                Ok(())
+            }
+            "repl-backlog-size" => {
+                let size = parse_memory(value)?;
+                self.store.get_replication().set_backlog_size(size);
+                Ok(())
+            }
+            "replica-read-only" | "slave-read-only" => {
+                let read_only = parse_yes_no(value)?;
+                self.store.get_replication().set_read_only(read_only);
+                Ok(())
+            }
+            "busy-reply-threshold" | "lua-time-limit" => {
+                let millis = value
+                    .parse()
+                    .map_err(|_| anyhow!("argument must be a number"))?;
+                self.store.set_busy_reply_threshold(millis);
+                Ok(())
+            }
+            _ => Err(anyhow!("Unsupported CONFIG parameter: {key}")),
+        }
+    }
+}
+
+impl Drop for RequestHandler {
+    fn drop(&mut self) {
+        if self.watched.is_empty() {
+            return;
+        }
+        let store = self.store.clone();
+        let watched = std::mem::take(&mut self.watched);
+        tokio::spawn(async move {
+            let mut keyspace = store.lock().await;
+            for (db, key, _) in watched {
+                keyspace.db(db).unwatch(&key);
+            }
+        });
+    }
 }
 
 pub fn get_request(value: RedisValue) -> Result<Request> {
+    if let Some(request) = get_binary_request(&value)? {
+        return Ok(request);
+    }
     let (command, mut args) = get_command_and_args(value)?;
     match command.as_str() {
         "ping" => Ok(Request::Ping),
@@ -97,8 +1284,165 @@ pub fn get_request(value: RedisValue) -> Result<Request> {
             Ok(Request::KEYS(pattern))
         }
         "info" => Ok(Request::INFO),
-        "replconf" => Ok(Request::REPLCONF),
-        "psync" => Ok(Request::PSYNC),
+        "replconf" => {
+            if args.len() % 2 != 0 {
+                return Err(anyhow!("syntax error"));
+            }
+            let mut options = vec![];
+            while let (Some(option), Some(value)) = (args.pop_front(), args.pop_front()) {
+                options.push((option.to_lowercase(), value));
+            }
+            Ok(Request::REPLCONF(options))
+        }
+        "psync" => {
+            let (Some(replid), Some(offset)) = (args.pop_front(), args.pop_front()) else {
+                return Err(anyhow!("psync needs 2 arguments"));
+            };
+            let offset = offset
+                .parse()
+                .map_err(|_| anyhow!("value is not an integer or out of range"))?;
+            Ok(Request::PSYNC(replid, offset))
+        }
+        "multi" => Ok(Request::Multi),
+        "exec" => Ok(Request::Exec),
+        "discard" => Ok(Request::Discard),
+        "watch" => {
+            if args.is_empty() {
+                return Err(anyhow!("watch needs at least 1 argument"));
+            }
+            Ok(Request::Watch(args.into()))
+        }
+        "unwatch" => Ok(Request::Unwatch),
+        "eval" => {
+            let (script, keys, args) = parse_script_args(&command, &mut args)?;
+            Ok(Request::Eval(script, keys, args))
+        }
+        "evalsha" => {
+            let (sha, keys, args) = parse_script_args(&command, &mut args)?;
+            Ok(Request::EvalSha(sha, keys, args))
+        }
+        "script" => make_script_request(&mut args),
+        "function" => make_function_request(&mut args),
+        "fcall" => {
+            let (name, keys, args) = parse_script_args(&command, &mut args)?;
+            Ok(Request::FCall(name, keys, args))
+        }
+        "fcall_ro" => {
+            let (name, keys, args) = parse_script_args(&command, &mut args)?;
+            Ok(Request::FCallRo(name, keys, args))
+        }
+        "publish" => {
+            let channel = args
+                .pop_front()
+                .ok_or(anyhow!("publish needs 2 arguments"))?;
+            let message = args
+                .pop_front()
+                .ok_or(anyhow!("publish needs 2 arguments"))?;
+            Ok(Request::Publish(channel, message))
+        }
+        "subscribe" => {
+            if args.is_empty() {
+                return Err(anyhow!("subscribe needs at least 1 argument"));
+            }
+            Ok(Request::Subscribe(args.into()))
+        }
+        "psubscribe" => {
+            if args.is_empty() {
+                return Err(anyhow!("psubscribe needs at least 1 argument"));
+            }
+            Ok(Request::PSubscribe(args.into()))
+        }
+        "del" | "unlink" | "exists" | "touch" => {
+            if args.is_empty() {
+                return Err(anyhow!("{command} needs at least 1 argument"));
+            }
+            let keys = args.into();
+            Ok(match command.as_str() {
+                "del" => Request::Del(keys),
+                "unlink" => Request::Unlink(keys),
+                "exists" => Request::Exists(keys),
+                _ => Request::Touch(keys),
+            })
+        }
+        "type" => {
+            let key = args.pop_front().ok_or(anyhow!("type needs 1 argument"))?;
+            Ok(Request::Type(key))
+        }
+        "dump" => {
+            let key = args.pop_front().ok_or(anyhow!("dump needs 1 argument"))?;
+            Ok(Request::Dump(key))
+        }
+        "rename" | "renamenx" => {
+            let (Some(from), Some(to)) = (args.pop_front(), args.pop_front()) else {
+                return Err(anyhow!("{command} needs 2 arguments"));
+            };
+            if command == "rename" {
+                Ok(Request::Rename(from, to))
+            } else {
+                Ok(Request::RenameNx(from, to))
+            }
+        }
+        "copy" => make_copy_request(&mut args),
+        "randomkey" => Ok(Request::RandomKey),
+        "select" => {
+            let index = args.pop_front().ok_or(anyhow!("select needs 1 argument"))?;
+            Ok(Request::Select(parse_db_index(&index)?))
+        }
+        "swapdb" => {
+            let (Some(a), Some(b)) = (args.pop_front(), args.pop_front()) else {
+                return Err(anyhow!("swapdb needs 2 arguments"));
+            };
+            Ok(Request::SwapDb(parse_db_index(&a)?, parse_db_index(&b)?))
+        }
+        "move" => {
+            let (Some(key), Some(db)) = (args.pop_front(), args.pop_front()) else {
+                return Err(anyhow!("move needs 2 arguments"));
+            };
+            Ok(Request::Move(key, parse_db_index(&db)?))
+        }
+        "flushdb" => Ok(Request::FlushDb(parse_flush_mode(&mut args)?)),
+        "flushall" => Ok(Request::FlushAll(parse_flush_mode(&mut args)?)),
+        "dbsize" => Ok(Request::DbSize),
+        "save" => Ok(Request::Save),
+        "bgsave" => Ok(Request::BgSave),
+        "lastsave" => Ok(Request::LastSave),
+        "bgrewriteaof" => Ok(Request::BgRewriteAof),
+        "replicaof" | "slaveof" => {
+            let (Some(host), Some(port)) = (args.pop_front(), args.pop_front()) else {
+                return Err(anyhow!("{command} needs 2 arguments"));
+            };
+            if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                return Ok(Request::ReplicaOf(None));
+            }
+            if port.parse::<u16>().is_err() {
+                return Err(anyhow!("Invalid master port"));
+            }
+            Ok(Request::ReplicaOf(Some((host, port))))
+        }
+        "wait" => {
+            let (Some(replicas), Some(timeout)) = (args.pop_front(), args.pop_front()) else {
+                return Err(anyhow!("wait needs 2 arguments"));
+            };
+            Ok(Request::Wait(
+                parse_count(&replicas)?,
+                parse_timeout(&timeout)?,
+            ))
+        }
+        "waitaof" => {
+            let (Some(local), Some(replicas), Some(timeout)) =
+                (args.pop_front(), args.pop_front(), args.pop_front())
+            else {
+                return Err(anyhow!("waitaof needs 3 arguments"));
+            };
+            Ok(Request::WaitAof(
+                parse_count(&local)?,
+                parse_count(&replicas)?,
+                parse_timeout(&timeout)?,
+            ))
+        }
+        "object" => make_object_request(&mut args),
+        "unsubscribe" => Ok(Request::Unsubscribe(args.into())),
+        "punsubscribe" => Ok(Request::PUnsubscribe(args.into())),
         x => Err(anyhow!("unsupported command: {x}")),
     }
 }
@@ -112,10 +1456,240 @@ fn make_config_request(args: &mut VecDeque<String>) -> Result<Request> {
                 .ok_or(anyhow!("config get needs at least 1 argument"))?;
             Ok(Request::ConfigGet(key))
         }
+        "set" => {
+            let key = args
+                .pop_front()
+                .ok_or(anyhow!("config set needs 2 arguments"))?;
+            let value = args
+                .pop_front()
+                .ok_or(anyhow!("config set needs 2 arguments"))?;
+            Ok(Request::ConfigSet(key.to_lowercase(), value))
+        }
         _ => Err(anyhow!("config {} is not supported", sub_command)),
     }
 }
 
+/// Splits `<script> <numkeys> [key ...] [arg ...]` into its parts.
+fn parse_script_args(
+    command: &str,
+    args: &mut VecDeque<String>,
+) -> Result<(String, Vec<String>, Vec<String>)> {
+    let script = args
+        .pop_front()
+        .ok_or(anyhow!("{command} needs at least 2 arguments"))?;
+    let num_keys = args
+        .pop_front()
+        .ok_or(anyhow!("{command} needs at least 2 arguments"))?
+        .parse::<usize>()
+        .map_err(|_| anyhow!("value is not an integer or out of range"))?;
+    if num_keys > args.len() {
+        return Err(anyhow!(
+            "Number of keys can't be greater than number of args"
+        ));
+    }
+    let keys = args.drain(..num_keys).collect();
+    Ok((script, keys, args.drain(..).collect()))
+}
+
+fn make_script_request(args: &mut VecDeque<String>) -> Result<Request> {
+    let sub_command = args.pop_front().ok_or(anyhow!("script needs subcommand"))?;
+    match sub_command.to_lowercase().as_str() {
+        "load" => {
+            let script = args
+                .pop_front()
+                .ok_or(anyhow!("script load needs 1 argument"))?;
+            Ok(Request::ScriptLoad(script))
+        }
+        "exists" => {
+            if args.is_empty() {
+                return Err(anyhow!("script exists needs at least 1 argument"));
+            }
+            Ok(Request::ScriptExists(args.drain(..).collect()))
+        }
+        "flush" => Ok(Request::ScriptFlush),
+        _ => Err(anyhow!("script {} is not supported", sub_command)),
+    }
+}
+
+fn make_function_request(args: &mut VecDeque<String>) -> Result<Request> {
+    let sub_command = args
+        .pop_front()
+        .ok_or(anyhow!("function needs subcommand"))?;
+    match sub_command.to_lowercase().as_str() {
+        "load" => {
+            let mut replace = false;
+            if args.len() > 1 && args[0].to_lowercase() == "replace" {
+                args.pop_front();
+                replace = true;
+            }
+            let code = args
+                .pop_front()
+                .ok_or(anyhow!("function load needs 1 argument"))?;
+            Ok(Request::FunctionLoad(code, replace))
+        }
+        "delete" => {
+            let name = args
+                .pop_front()
+                .ok_or(anyhow!("function delete needs 1 argument"))?;
+            Ok(Request::FunctionDelete(name))
+        }
+        "list" => {
+            let (mut pattern, mut with_code) = (None, false);
+            while let Some(arg) = args.pop_front() {
+                match arg.to_lowercase().as_str() {
+                    "withcode" => with_code = true,
+                    "libraryname" => {
+                        pattern = Some(
+                            args.pop_front()
+                                .ok_or(anyhow!("libraryname needs argument"))?,
+                        )
+                    }
+                    _ => return Err(anyhow!("Unknown argument {arg}")),
+                }
+            }
+            Ok(Request::FunctionList(pattern, with_code))
+        }
+        "dump" => Ok(Request::FunctionDump),
+        "flush" => Ok(Request::FunctionFlush),
+        _ => Err(anyhow!("function {} is not supported", sub_command)),
+    }
+}
+
+/// Parses commands whose payload is binary and cannot go through the
+/// string based argument parsing.
+fn get_binary_request(value: &RedisValue) -> Result<Option<Request>> {
+    let RedisValue::Array(vals) = value else {
+        return Ok(None);
+    };
+    let names: Vec<String> = vals
+        .iter()
+        .take(2)
+        .map(|val| val.get_bulk_string().unwrap_or_default().to_lowercase())
+        .collect();
+    if names.first().is_some_and(|name| name == "restore") {
+        return make_restore_request(&vals[1..]).map(Some);
+    }
+    if names != ["function", "restore"] {
+        return Ok(None);
+    }
+    let payload = vals
+        .get(2)
+        .ok_or(anyhow!("function restore needs a payload"))?
+        .get_bytes()?;
+    let policy = match vals.get(3) {
+        None => RestorePolicy::Append,
+        Some(policy) => match policy.get_bulk_string()?.to_lowercase().as_str() {
+            "append" => RestorePolicy::Append,
+            "replace" => RestorePolicy::Replace,
+            "flush" => RestorePolicy::Flush,
+            _ => return Err(anyhow!("Wrong restore policy given")),
+        },
+    };
+    Ok(Some(Request::FunctionRestore(payload, policy)))
+}
+
+/// Parses `RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds]
+/// [FREQ frequency]`.
+fn make_restore_request(vals: &[RedisValue]) -> Result<Request> {
+    let [key, ttl, payload, modifiers @ ..] = vals else {
+        return Err(anyhow!("restore needs at least 3 arguments"));
+    };
+    let key = key.get_bulk_string()?;
+    let ttl = ttl
+        .get_bulk_string()?
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Invalid TTL value, must be >= 0"))?;
+    let payload = payload.get_bytes()?;
+    let mut options = RestoreOptions::default();
+    let mut modifiers = modifiers
+        .iter()
+        .map(|modifier| modifier.get_bulk_string())
+        .collect::<Result<VecDeque<_>>>()?;
+    while let Some(modifier) = modifiers.pop_front() {
+        match modifier.to_lowercase().as_str() {
+            "replace" => options.replace = true,
+            "absttl" => options.absttl = true,
+            "idletime" => {
+                let idle_time = modifiers.pop_front().ok_or(anyhow!("syntax error"))?;
+                options.idle_time = Some(
+                    idle_time
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid IDLETIME value, must be >= 0"))?,
+                );
+            }
+            "freq" => {
+                let freq = modifiers.pop_front().ok_or(anyhow!("syntax error"))?;
+                options.freq = Some(
+                    freq.parse()
+                        .map_err(|_| anyhow!("Invalid FREQ value, must be >= 0 and <= 255"))?,
+                );
+            }
+            _ => return Err(anyhow!("syntax error")),
+        }
+    }
+    Ok(Request::Restore(key, ttl, payload, options))
+}
+
+fn make_copy_request(args: &mut VecDeque<String>) -> Result<Request> {
+    let (Some(source), Some(destination)) = (args.pop_front(), args.pop_front()) else {
+        return Err(anyhow!("copy needs at least 2 arguments"));
+    };
+    let mut replace = false;
+    let mut db = None;
+    while let Some(arg) = args.pop_front() {
+        match arg.to_lowercase().as_str() {
+            "replace" => replace = true,
+            "db" => {
+                let index = args.pop_front().ok_or(anyhow!("syntax error"))?;
+                db = Some(parse_db_index(&index)?);
+            }
+            _ => return Err(anyhow!("syntax error")),
+        }
+    }
+    Ok(Request::Copy(source, destination, db, replace))
+}
+
+fn parse_db_index(index: &str) -> Result<usize> {
+    index
+        .parse::<usize>()
+        .map_err(|_| anyhow!("DB index is out of range"))
+}
+
+/// Parses the optional ASYNC / SYNC flag of FLUSHDB and FLUSHALL.
+fn parse_flush_mode(args: &mut VecDeque<String>) -> Result<bool> {
+    match args.pop_front().map(|arg| arg.to_lowercase()).as_deref() {
+        None | Some("sync") => Ok(false),
+        Some("async") => Ok(true),
+        Some(_) => Err(anyhow!("syntax error")),
+    }
+}
+
+fn make_object_request(args: &mut VecDeque<String>) -> Result<Request> {
+    let sub_command = args.pop_front().unwrap_or_default().to_lowercase();
+    let key = args
+        .pop_front()
+        .ok_or(anyhow!("object {sub_command} needs 1 argument"))?;
+    match sub_command.as_str() {
+        "encoding" => Ok(Request::ObjectEncoding(key)),
+        "idletime" => Ok(Request::ObjectIdleTime(key)),
+        "freq" => Ok(Request::ObjectFreq(key)),
+        "refcount" => Ok(Request::ObjectRefCount(key)),
+        _ => Err(anyhow!("object {} is not supported", sub_command)),
+    }
+}
+
+fn parse_count(value: &str) -> Result<usize> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("value is not an integer or out of range"))
+}
+
+fn parse_timeout(value: &str) -> Result<u64> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("timeout is not an integer or out of range"))
+}
+
 fn make_set_request(args: &mut VecDeque<String>) -> Result<Request> {
     let key = args
         .pop_front()
@@ -125,13 +1699,20 @@ fn make_set_request(args: &mut VecDeque<String>) -> Result<Request> {
         .ok_or(anyhow!("set needs at least 2 argument"))?;
     if !args.is_empty() {
         let arg = args.pop_front().unwrap().to_lowercase();
-        if arg == "px" {
-            let delay = args
-                .pop_front()
-                .ok_or(anyhow!("px needs argument"))?
-                .parse::<u64>()?;
-            return Ok(Request::Set(key, value, Some(Duration::from_millis(delay))));
+        if !matches!(arg.as_str(), "px" | "ex" | "pxat" | "exat") {
+            return Err(anyhow!("syntax error"));
         }
+        let time = args
+            .pop_front()
+            .ok_or(anyhow!("{arg} needs argument"))?
+            .parse::<u64>()?;
+        let expiry = match arg.as_str() {
+            "px" => Expiry::In(Duration::from_millis(time)),
+            "ex" => Expiry::In(Duration::from_secs(time)),
+            "pxat" => Expiry::At(UNIX_EPOCH + Duration::from_millis(time)),
+            _ => Expiry::At(UNIX_EPOCH + Duration::from_secs(time)),
+        };
+        return Ok(Request::Set(key, value, Some(expiry)));
     }
     Ok(Request::Set(key, value, None))
 }
@@ -152,3 +1733,738 @@ fn get_command_and_args(value: RedisValue) -> Result<(String, VecDeque<String>)>
         _ => Err(anyhow!("expects command to be an array")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::UNIX_EPOCH};
+
+    use crate::{
+        config::{parse_args, SystemConfig},
+        functions::RestorePolicy,
+        parser::RedisValue,
+        rdb::{crc64, read_rdb_file, RDB_VERSION},
+        request::{get_request, serialize_effects, Expiry, Request, RequestHandler},
+        store::Store,
+        value::Value,
+    };
+
+    fn handler() -> RequestHandler {
+        RequestHandler::new(Arc::new(Store::new()), Arc::new(SystemConfig::default()))
+    }
+
+    async fn run(handler: &mut RequestHandler, command: &[&str]) -> RedisValue {
+        let value = RedisValue::make_bulk_array(command.iter().map(|s| s.to_string()).collect());
+        match get_request(value) {
+            Ok(request) => handler.handle_request(request).await,
+            Err(e) => handler.handle_invalid_request(e),
+        }
+    }
+
+    fn ok() -> RedisValue {
+        RedisValue::SimpleString("OK".to_owned())
+    }
+
+    fn queued() -> RedisValue {
+        RedisValue::SimpleString("QUEUED".to_owned())
+    }
+
+    #[tokio::test]
+    async fn should_queue_and_exec_transaction() {
+        let mut h = handler();
+        assert_eq!(run(&mut h, &["MULTI"]).await, ok());
+        assert_eq!(run(&mut h, &["SET", "a", "1"]).await, queued());
+        assert_eq!(run(&mut h, &["GET", "a"]).await, queued());
+        assert_eq!(
+            run(&mut h, &["EXEC"]).await,
+            RedisValue::Array(vec![ok(), RedisValue::BulkString("1".to_owned())])
+        );
+    }
+
+    #[tokio::test]
+    async fn should_abort_exec_after_queue_error() {
+        let mut h = handler();
+        run(&mut h, &["MULTI"]).await;
+        assert!(matches!(run(&mut h, &["NOPE"]).await, RedisValue::Error(_)));
+        run(&mut h, &["SET", "a", "1"]).await;
+        assert_eq!(
+            run(&mut h, &["EXEC"]).await,
+            RedisValue::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_owned()
+            )
+        );
+        assert_eq!(run(&mut h, &["GET", "a"]).await, RedisValue::NullBulkString);
+    }
+
+    #[tokio::test]
+    async fn should_fail_exec_when_watched_key_changed() {
+        let store = Arc::new(Store::new());
+        let config = Arc::new(SystemConfig::default());
+        let mut h1 = RequestHandler::new(store.clone(), config.clone());
+        let mut h2 = RequestHandler::new(store, config);
+        assert_eq!(run(&mut h1, &["WATCH", "balance"]).await, ok());
+        run(&mut h2, &["SET", "balance", "10"]).await;
+        run(&mut h1, &["MULTI"]).await;
+        run(&mut h1, &["SET", "balance", "20"]).await;
+        assert_eq!(run(&mut h1, &["EXEC"]).await, RedisValue::NullArray);
+        assert_eq!(
+            run(&mut h1, &["GET", "balance"]).await,
+            RedisValue::BulkString("10".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_fail_exec_when_watched_key_expired() {
+        let mut h = handler();
+        run(&mut h, &["SET", "lock", "1", "PX", "20"]).await;
+        run(&mut h, &["WATCH", "lock"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+        run(&mut h, &["MULTI"]).await;
+        run(&mut h, &["SET", "lock", "2"]).await;
+        assert_eq!(run(&mut h, &["EXEC"]).await, RedisValue::NullArray);
+    }
+
+    #[tokio::test]
+    async fn should_exec_when_watched_key_untouched() {
+        let mut h = handler();
+        run(&mut h, &["SET", "a", "1"]).await;
+        run(&mut h, &["WATCH", "a", "b"]).await;
+        run(&mut h, &["MULTI"]).await;
+        run(&mut h, &["SET", "a", "2"]).await;
+        assert_eq!(run(&mut h, &["EXEC"]).await, RedisValue::Array(vec![ok()]));
+        // EXEC unwatches, so a later transaction is unaffected by this change.
+        run(&mut h, &["SET", "a", "3"]).await;
+        run(&mut h, &["MULTI"]).await;
+        assert_eq!(run(&mut h, &["EXEC"]).await, RedisValue::Array(vec![]));
+    }
+
+    #[tokio::test]
+    async fn should_run_scripts_and_record_their_writes() {
+        let mut h = handler();
+        let script = "redis.call('SET', KEYS[1], ARGV[1]) return redis.call('GET', KEYS[1])";
+        assert_eq!(
+            run(&mut h, &["EVAL", script, "1", "k", "v"]).await,
+            RedisValue::BulkString("v".to_owned())
+        );
+        let effects = h.take_write_effects();
+        assert!(
+            matches!(effects.as_slice(), [(0, Request::Set(k, v, None))] if k == "k" && v == "v")
+        );
+
+        let sha = crate::scripting::sha1_hex(script);
+        assert_eq!(
+            run(&mut h, &["SCRIPT", "EXISTS", &sha, "ffff"]).await,
+            RedisValue::Array(vec![RedisValue::Integer(1), RedisValue::Integer(0)])
+        );
+        assert_eq!(
+            run(&mut h, &["EVALSHA", &sha, "1", "k2", "v2"]).await,
+            RedisValue::BulkString("v2".to_owned())
+        );
+        run(&mut h, &["SCRIPT", "FLUSH"]).await;
+        assert!(matches!(
+            run(&mut h, &["EVALSHA", &sha, "0"]).await,
+            RedisValue::Error(e) if e.starts_with("NOSCRIPT")
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn should_stop_scripts_running_too_long() {
+        let mut h = handler();
+        assert_eq!(
+            run(&mut h, &["CONFIG", "SET", "busy-reply-threshold", "50"]).await,
+            ok()
+        );
+        let script = "redis.call('SET', 'k', 'v') while true do end";
+        assert!(matches!(
+            run(&mut h, &["EVAL", script, "0"]).await,
+            RedisValue::Error(e) if e.starts_with("BUSY")
+        ));
+        // The writes made before the script was stopped stay.
+        assert_eq!(h.take_write_effects().len(), 1);
+        assert_eq!(
+            run(&mut h, &["GET", "k"]).await,
+            RedisValue::BulkString("v".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_load_and_call_functions() {
+        let mut h = handler();
+        let code = "#!lua name=counter
+            redis.register_function('setk', function(keys, args) return redis.call('SET', keys[1], args[1]) end)
+            redis.register_function{function_name='getk', callback=function(keys) return redis.call('GET', keys[1]) end, flags={'no-writes'}}";
+        assert_eq!(
+            run(&mut h, &["FUNCTION", "LOAD", code]).await,
+            RedisValue::BulkString("counter".to_owned())
+        );
+        assert_eq!(run(&mut h, &["FCALL", "setk", "1", "k", "v"]).await, ok());
+        assert_eq!(
+            run(&mut h, &["FCALL_RO", "getk", "1", "k"]).await,
+            RedisValue::BulkString("v".to_owned())
+        );
+        assert!(matches!(
+            run(&mut h, &["FCALL_RO", "setk", "1", "k", "v"]).await,
+            RedisValue::Error(e) if e.contains("write flag")
+        ));
+
+        let dump = run(&mut h, &["FUNCTION", "DUMP"]).await;
+        run(&mut h, &["FUNCTION", "DELETE", "counter"]).await;
+        assert!(matches!(
+            run(&mut h, &["FCALL", "getk", "1", "k"]).await,
+            RedisValue::Error(_)
+        ));
+        let restore = RedisValue::Array(vec![
+            RedisValue::BulkString("FUNCTION".to_owned()),
+            RedisValue::BulkString("RESTORE".to_owned()),
+            RedisValue::BulkBytes(dump.get_bytes().unwrap()),
+        ]);
+        assert_eq!(h.handle_request(get_request(restore).unwrap()).await, ok());
+        assert_eq!(
+            run(&mut h, &["FCALL", "getk", "1", "k"]).await,
+            RedisValue::BulkString("v".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_transactions_inside_scripts() {
+        let mut h = handler();
+        assert!(matches!(
+            run(&mut h, &["EVAL", "return redis.call('MULTI')", "0"]).await,
+            RedisValue::Error(e) if e.contains("not allowed from script")
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_discard_transaction() {
+        let mut h = handler();
+        run(&mut h, &["MULTI"]).await;
+        run(&mut h, &["SET", "a", "1"]).await;
+        assert_eq!(run(&mut h, &["DISCARD"]).await, ok());
+        assert!(matches!(run(&mut h, &["EXEC"]).await, RedisValue::Error(_)));
+        assert_eq!(run(&mut h, &["GET", "a"]).await, RedisValue::NullBulkString);
+    }
+
+    #[tokio::test]
+    async fn should_count_deleted_and_existing_keys() {
+        let mut h = handler();
+        run(&mut h, &["SET", "a", "1"]).await;
+        run(&mut h, &["SET", "b", "2"]).await;
+        assert_eq!(
+            run(&mut h, &["EXISTS", "a", "a", "missing"]).await,
+            RedisValue::Integer(2)
+        );
+        assert_eq!(run(&mut h, &["DBSIZE"]).await, RedisValue::Integer(2));
+        assert_eq!(
+            run(&mut h, &["DEL", "a", "a", "missing"]).await,
+            RedisValue::Integer(1)
+        );
+        assert_eq!(run(&mut h, &["UNLINK", "b"]).await, RedisValue::Integer(1));
+        assert_eq!(run(&mut h, &["DBSIZE"]).await, RedisValue::Integer(0));
+        assert_eq!(
+            run(&mut h, &["RANDOMKEY"]).await,
+            RedisValue::NullBulkString
+        );
+        assert_eq!(
+            run(&mut h, &["TYPE", "a"]).await,
+            RedisValue::SimpleString("none".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_rename_and_copy_keys_with_ttl() {
+        let mut h = handler();
+        run(&mut h, &["SET", "a", "1", "PX", "100000"]).await;
+        run(&mut h, &["SET", "b", "2"]).await;
+        assert_eq!(
+            run(&mut h, &["RENAMENX", "a", "b"]).await,
+            RedisValue::Integer(0)
+        );
+        assert_eq!(run(&mut h, &["RENAME", "a", "c"]).await, ok());
+        assert!(matches!(
+            run(&mut h, &["RENAME", "a", "c"]).await,
+            RedisValue::Error(e) if e == "ERR no such key"
+        ));
+        assert_eq!(
+            run(&mut h, &["COPY", "c", "b"]).await,
+            RedisValue::Integer(0)
+        );
+        assert_eq!(
+            run(&mut h, &["COPY", "c", "b", "REPLACE"]).await,
+            RedisValue::Integer(1)
+        );
+        assert!(matches!(
+            h.take_write_effects().as_slice(),
+            [(0, Request::Copy(s, d, None, true))] if s == "c" && d == "b"
+        ));
+        assert_eq!(
+            run(&mut h, &["GET", "b"]).await,
+            RedisValue::BulkString("1".to_owned())
+        );
+        assert!(h.store.lock().await.db(0).is_expiring("b"));
+    }
+
+    #[tokio::test]
+    async fn should_dump_and_restore_keys() {
+        let mut h = handler();
+        run(&mut h, &["SET", "s", "hello"]).await;
+        let payload = match run(&mut h, &["DUMP", "s"]).await {
+            RedisValue::BulkBytes(payload) => payload,
+            reply => panic!("DUMP should return the payload, got {reply:?}"),
+        };
+        assert!(payload.starts_with(b"\x00\x05hello\x0b\x00"));
+        assert_eq!(
+            run(&mut h, &["DUMP", "missing"]).await,
+            RedisValue::NullBulkString
+        );
+
+        let restore = |key: &str, ttl: &str, payload: &[u8], modifiers: &[&str]| {
+            let mut command = vec![
+                RedisValue::BulkString("RESTORE".to_owned()),
+                RedisValue::BulkString(key.to_owned()),
+                RedisValue::BulkString(ttl.to_owned()),
+                RedisValue::BulkBytes(payload.to_vec()),
+            ];
+            command.extend(
+                modifiers
+                    .iter()
+                    .map(|m| RedisValue::BulkString(m.to_string())),
+            );
+            get_request(RedisValue::Array(command)).unwrap()
+        };
+        assert_eq!(
+            h.handle_request(restore("t", "0", &payload, &["IDLETIME", "100"]))
+                .await,
+            ok()
+        );
+        assert!(matches!(
+            h.handle_request(restore("t", "0", &payload, &[])).await,
+            RedisValue::Error(e) if e.starts_with("BUSYKEY")
+        ));
+        assert_eq!(
+            h.handle_request(restore("t", "0", &payload, &["REPLACE", "FREQ", "42"]))
+                .await,
+            ok()
+        );
+        assert_eq!(
+            h.handle_request(restore("u", "1", &payload, &["ABSTTL"]))
+                .await,
+            ok()
+        );
+        let mut corrupt = payload.clone();
+        corrupt[2] = b'j';
+        assert_eq!(
+            h.handle_request(restore("v", "0", &corrupt, &[])).await,
+            RedisValue::Error("ERR DUMP payload version or checksum are wrong".to_owned())
+        );
+
+        assert_eq!(
+            run(&mut h, &["GET", "t"]).await,
+            RedisValue::BulkString("hello".to_owned())
+        );
+        assert_eq!(
+            run(&mut h, &["OBJECT", "FREQ", "t"]).await,
+            RedisValue::Integer(42)
+        );
+        assert_eq!(run(&mut h, &["EXISTS", "u"]).await, RedisValue::Integer(0));
+        assert!(get_request(RedisValue::make_bulk_array(
+            ["RESTORE", "k", "-1", "x"].map(String::from).to_vec()
+        ))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn should_reject_malformed_restore_payloads() {
+        let mut h = handler();
+        let with_footer = |mut payload: Vec<u8>| {
+            payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+            let crc = crc64(0, &payload);
+            payload.extend_from_slice(&crc.to_le_bytes());
+            payload
+        };
+        let huge = (1u64 << 62).to_be_bytes();
+        let payloads = [
+            // A string compressed from 2^62 bytes.
+            [&[0, 0xc3, 0x01, 0x81], &huge[..], &[0x00]].concat(),
+            // A list of 2^62 elements with none of them there.
+            [&[1, 0x81], &huge[..]].concat(),
+            // A value of an unknown type.
+            vec![0x7f, 0x01, b'a'],
+        ];
+        for payload in payloads {
+            let request = get_request(RedisValue::Array(vec![
+                RedisValue::BulkString("RESTORE".to_owned()),
+                RedisValue::BulkString("k".to_owned()),
+                RedisValue::BulkString("0".to_owned()),
+                RedisValue::BulkBytes(with_footer(payload)),
+            ]))
+            .unwrap();
+            assert_eq!(
+                h.handle_request(request).await,
+                RedisValue::Error("ERR Bad data format".to_owned())
+            );
+        }
+        assert_eq!(
+            run(&mut h, &["PING"]).await,
+            RedisValue::SimpleString("PONG".to_owned())
+        );
+        assert_eq!(run(&mut h, &["EXISTS", "k"]).await, RedisValue::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn should_list_keys_matching_a_pattern() {
+        let mut h = handler();
+        for key in ["foo", "foobar", "bar"] {
+            run(&mut h, &["SET", key, "1"]).await;
+        }
+        let keys = |reply: RedisValue| match reply {
+            RedisValue::Array(keys) => {
+                let mut keys: Vec<_> = keys
+                    .into_iter()
+                    .map(|key| match key {
+                        RedisValue::BulkString(key) => key,
+                        key => panic!("KEYS should return bulk strings, got {key:?}"),
+                    })
+                    .collect();
+                keys.sort();
+                keys
+            }
+            reply => panic!("KEYS should return an array, got {reply:?}"),
+        };
+        assert_eq!(
+            keys(run(&mut h, &["KEYS", "foo*"]).await),
+            ["foo", "foobar"]
+        );
+        assert_eq!(keys(run(&mut h, &["KEYS", "?ar"]).await), ["bar"]);
+        assert_eq!(keys(run(&mut h, &["KEYS", "*"]).await).len(), 3);
+        assert!(keys(run(&mut h, &["KEYS", "baz*"]).await).is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_report_object_metadata() {
+        let mut h = handler();
+        run(&mut h, &["SET", "n", "123"]).await;
+        run(&mut h, &["SET", "s", "hello"]).await;
+        assert_eq!(
+            run(&mut h, &["OBJECT", "ENCODING", "n"]).await,
+            RedisValue::BulkString("int".to_owned())
+        );
+        assert_eq!(
+            run(&mut h, &["OBJECT", "ENCODING", "s"]).await,
+            RedisValue::BulkString("embstr".to_owned())
+        );
+        assert_eq!(
+            run(&mut h, &["OBJECT", "IDLETIME", "s"]).await,
+            RedisValue::Integer(0)
+        );
+        assert_eq!(
+            run(&mut h, &["OBJECT", "REFCOUNT", "s"]).await,
+            RedisValue::Integer(1)
+        );
+        assert!(matches!(
+            run(&mut h, &["OBJECT", "FREQ", "s"]).await,
+            RedisValue::Integer(freq) if freq >= 5
+        ));
+        assert_eq!(
+            run(&mut h, &["OBJECT", "ENCODING", "missing"]).await,
+            RedisValue::NullBulkString
+        );
+    }
+
+    #[tokio::test]
+    async fn should_keep_databases_apart() {
+        let mut h = handler();
+        run(&mut h, &["SET", "k", "zero"]).await;
+        assert_eq!(run(&mut h, &["SELECT", "1"]).await, ok());
+        assert_eq!(run(&mut h, &["GET", "k"]).await, RedisValue::NullBulkString);
+        run(&mut h, &["SET", "k", "one"]).await;
+        assert_eq!(
+            run(&mut h, &["MOVE", "k", "0"]).await,
+            RedisValue::Integer(0)
+        );
+        assert_eq!(run(&mut h, &["SWAPDB", "0", "1"]).await, ok());
+        assert_eq!(
+            run(&mut h, &["GET", "k"]).await,
+            RedisValue::BulkString("zero".to_owned())
+        );
+        assert_eq!(
+            run(&mut h, &["MOVE", "k", "2"]).await,
+            RedisValue::Integer(1)
+        );
+        assert_eq!(run(&mut h, &["DBSIZE"]).await, RedisValue::Integer(0));
+        assert!(matches!(
+            run(&mut h, &["SELECT", "16"]).await,
+            RedisValue::Error(e) if e == "ERR DB index is out of range"
+        ));
+
+        assert_eq!(run(&mut h, &["FLUSHDB", "ASYNC"]).await, ok());
+        assert_eq!(run(&mut h, &["SELECT", "2"]).await, ok());
+        assert_eq!(run(&mut h, &["DBSIZE"]).await, RedisValue::Integer(1));
+        assert_eq!(run(&mut h, &["FLUSHALL"]).await, ok());
+        assert_eq!(run(&mut h, &["DBSIZE"]).await, RedisValue::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn should_fail_exec_when_watched_database_is_swapped() {
+        let store = Arc::new(Store::new());
+        let config = Arc::new(SystemConfig::default());
+        let mut h = RequestHandler::new(store.clone(), config.clone());
+        let mut other = RequestHandler::new(store, config);
+        run(&mut h, &["SET", "k", "v"]).await;
+        run(&mut h, &["WATCH", "k"]).await;
+        run(&mut other, &["SWAPDB", "0", "1"]).await;
+        run(&mut h, &["MULTI"]).await;
+        run(&mut h, &["GET", "k"]).await;
+        assert_eq!(run(&mut h, &["EXEC"]).await, RedisValue::NullArray);
+    }
+
+    #[tokio::test]
+    async fn should_save_and_load_snapshots() {
+        let dir = std::env::temp_dir().join(format!("save-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let args = [
+            "exec",
+            "--dir",
+            dir.to_str().unwrap(),
+            "--dbfilename",
+            "dump.rdb",
+        ];
+        let config = Arc::new(parse_args(args.into_iter().map(|arg| arg.to_owned())).unwrap());
+        let store = Arc::new(Store::new());
+        let mut h = RequestHandler::new(store.clone(), config.clone());
+        run(&mut h, &["SET", "k", "v", "PX", "60000"]).await;
+        run(&mut h, &["SELECT", "3"]).await;
+        run(&mut h, &["SET", "other", "1"]).await;
+        assert_eq!(store.get_persistence().get_dirty(), 2);
+        assert_eq!(run(&mut h, &["SAVE"]).await, ok());
+        assert_eq!(store.get_persistence().get_dirty(), 0);
+        assert!(matches!(run(&mut h, &["LASTSAVE"]).await, RedisValue::Integer(t) if t > 0));
+
+        let file = read_rdb_file(config.get_rdb_path()).unwrap();
+        assert_eq!(file.databases.len(), 2);
+        assert!(file.databases[&0].key_expires.contains_key("k"));
+        assert_eq!(
+            *file.databases[&3].key_vals["other"],
+            Value::String("1".to_owned())
+        );
+
+        run(&mut h, &["DEL", "other"]).await;
+        assert_eq!(
+            run(&mut h, &["BGSAVE"]).await,
+            RedisValue::SimpleString("Background saving started".to_owned())
+        );
+        while store.get_persistence().is_bgsave_in_progress() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let file = read_rdb_file(config.get_rdb_path()).unwrap();
+        assert_eq!(file.databases.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_propagate_writes_deterministically() {
+        let mut h = handler();
+        let command = |parts: &[&str]| {
+            RedisValue::make_bulk_array(parts.iter().map(|s| s.to_string()).collect()).serialize()
+        };
+        run(&mut h, &["SET", "k", "v", "PX", "100000"]).await;
+        let effects = h.take_write_effects();
+        let deadline = match effects.as_slice() {
+            [(0, Request::Set(_, _, Some(Expiry::At(deadline))))] => *deadline,
+            effects => panic!("SET should record its deadline, got {effects:?}"),
+        };
+        let snapshot = h.store.lock().await.snapshot();
+        assert_eq!(snapshot[&0].key_expires["k"], deadline);
+        let millis = deadline.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let mut buf = vec![];
+        let mut selected_db = Some(0);
+        serialize_effects(&effects, &mut selected_db, &mut buf);
+        assert_eq!(
+            buf,
+            command(&["SET", "k", "v", "PXAT", &millis.to_string()])
+        );
+
+        run(&mut h, &["MULTI"]).await;
+        run(&mut h, &["SET", "gone", "1", "PXAT", "1"]).await;
+        run(&mut h, &["GET", "gone"]).await;
+        run(&mut h, &["FLUSHDB", "ASYNC"]).await;
+        run(&mut h, &["EXEC"]).await;
+        let mut buf = vec![];
+        serialize_effects(&h.take_write_effects(), &mut selected_db, &mut buf);
+        let expected = [
+            command(&["MULTI"]),
+            command(&["SET", "gone", "1", "PXAT", "1"]),
+            command(&["DEL", "gone"]),
+            command(&["FLUSHDB", "ASYNC"]),
+            command(&["EXEC"]),
+        ];
+        assert_eq!(buf, expected.concat());
+    }
+
+    #[tokio::test]
+    async fn should_not_propagate_writes_that_change_nothing() {
+        let mut h = handler();
+        let noops: [&[&str]; 6] = [
+            &["DEL", "missing"],
+            &["RENAMENX", "missing", "k"],
+            &["MOVE", "missing", "1"],
+            &["COPY", "missing", "k"],
+            &["FLUSHDB"],
+            &["FLUSHALL"],
+        ];
+        for noop in noops {
+            run(&mut h, noop).await;
+            assert!(h.take_write_effects().is_empty(), "{noop:?} was propagated");
+        }
+        assert_eq!(h.store.get_persistence().get_dirty(), 0);
+
+        run(&mut h, &["SET", "k", "v"]).await;
+        run(&mut h, &["MULTI"]).await;
+        run(&mut h, &["DEL", "missing"]).await;
+        run(&mut h, &["DEL", "k"]).await;
+        run(&mut h, &["EXEC"]).await;
+        assert!(matches!(
+            h.take_write_effects().as_slice(),
+            [(0, Request::Del(keys))] if keys == &["k"]
+        ));
+        assert_eq!(h.store.get_persistence().get_dirty(), 2);
+    }
+
+    #[test]
+    fn should_propagate_function_restore_with_its_policy() {
+        for (policy, name) in [
+            (RestorePolicy::Append, "APPEND"),
+            (RestorePolicy::Replace, "REPLACE"),
+            (RestorePolicy::Flush, "FLUSH"),
+        ] {
+            let command = Request::FunctionRestore(b"payload".to_vec(), policy.clone())
+                .to_command()
+                .unwrap();
+            match &command {
+                RedisValue::Array(parts) => {
+                    assert_eq!(parts[3], RedisValue::BulkString(name.to_owned()))
+                }
+                command => panic!("expected a command, got {command:?}"),
+            }
+            assert!(matches!(
+                get_request(command).unwrap(),
+                Request::FunctionRestore(_, restored) if restored == policy
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_wait_for_replicas() {
+        let mut h = handler();
+        run(&mut h, &["SET", "k", "v"]).await;
+        assert_eq!(
+            run(&mut h, &["WAIT", "0", "0"]).await,
+            RedisValue::Integer(0)
+        );
+        assert_eq!(
+            run(&mut h, &["WAIT", "1", "10"]).await,
+            RedisValue::Integer(0)
+        );
+        assert!(matches!(
+            run(&mut h, &["WAITAOF", "1", "0", "0"]).await,
+            RedisValue::Error(e) if e.contains("appendonly is disabled")
+        ));
+        assert_eq!(
+            run(
+                &mut h,
+                &["REPLCONF", "listening-port", "6380", "capa", "psync2"]
+            )
+            .await,
+            ok()
+        );
+        assert_eq!(h.get_listening_port(), Some("6380".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn should_start_and_stop_replicating_at_runtime() {
+        let mut h = handler();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let info = |reply: RedisValue| match reply {
+            RedisValue::BulkString(info) => info,
+            reply => panic!("INFO should return a bulk string, got {reply:?}"),
+        };
+        let replid = info(run(&mut h, &["INFO"]).await)
+            .lines()
+            .find_map(|line| line.strip_prefix("master_replid:").map(str::to_owned))
+            .unwrap();
+
+        assert_eq!(run(&mut h, &["REPLICAOF", "127.0.0.1", &port]).await, ok());
+        assert!(info(run(&mut h, &["INFO"]).await).starts_with(&format!(
+            "role:slave\nmaster_host:127.0.0.1\nmaster_port:{port}\n"
+        )));
+        assert_eq!(
+            run(&mut h, &["SLAVEOF", "127.0.0.1", &port]).await,
+            RedisValue::SimpleString("OK Already connected to specified master".to_owned())
+        );
+        assert!(matches!(
+            run(&mut h, &["WAIT", "1", "0"]).await,
+            RedisValue::Error(e) if e.contains("replica")
+        ));
+        // The link to the master is torn down.
+        let (mut socket, _) = listener.accept().await.unwrap();
+        assert_eq!(run(&mut h, &["REPLICAOF", "NO", "ONE"]).await, ok());
+        let mut buf = vec![];
+        tokio::io::AsyncReadExt::read_to_end(&mut socket, &mut buf)
+            .await
+            .unwrap();
+
+        let info = info(run(&mut h, &["INFO"]).await);
+        assert!(info.starts_with("role:master\n"));
+        assert!(info.contains(&format!("master_replid2:{replid}\n")));
+        assert!(matches!(
+            run(&mut h, &["REPLICAOF", "127.0.0.1", "port"]).await,
+            RedisValue::Error(e) if e.contains("Invalid master port")
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_reject_writes_on_read_only_replicas() {
+        let store = Arc::new(Store::new());
+        let config = Arc::new(SystemConfig::default());
+        let mut h = RequestHandler::new(store.clone(), config.clone());
+        let mut master = RequestHandler::for_master_link(store, config);
+        let code = "#!lua name=lib
+            redis.register_function{function_name='getk', callback=function(keys) return redis.call('GET', keys[1]) end, flags={'no-writes'}}";
+        run(&mut h, &["FUNCTION", "LOAD", code]).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        assert_eq!(run(&mut h, &["REPLICAOF", "127.0.0.1", &port]).await, ok());
+        let read_only = || {
+            RedisValue::Error("READONLY You can't write against a read only replica.".to_owned())
+        };
+
+        assert_eq!(run(&mut h, &["SET", "k", "v"]).await, read_only());
+        assert_eq!(run(&mut master, &["SET", "k", "v"]).await, ok());
+        assert_eq!(
+            run(&mut h, &["GET", "k"]).await,
+            RedisValue::BulkString("v".to_owned())
+        );
+        assert_eq!(
+            run(&mut h, &["FCALL_RO", "getk", "1", "k"]).await,
+            RedisValue::BulkString("v".to_owned())
+        );
+        assert!(matches!(
+            run(&mut h, &["EVAL", "return redis.call('DEL', KEYS[1])", "1", "k"]).await,
+            RedisValue::Error(e) if e.contains("READONLY")
+        ));
+        assert_eq!(run(&mut h, &["MULTI"]).await, ok());
+        assert_eq!(run(&mut h, &["DEL", "k"]).await, read_only());
+        assert!(matches!(
+            run(&mut h, &["EXEC"]).await,
+            RedisValue::Error(e) if e.starts_with("EXECABORT")
+        ));
+
+        assert_eq!(
+            run(&mut h, &["CONFIG", "SET", "replica-read-only", "no"]).await,
+            ok()
+        );
+        assert_eq!(run(&mut h, &["DEL", "k"]).await, RedisValue::Integer(1));
+        run(&mut h, &["CONFIG", "SET", "replica-read-only", "yes"]).await;
+        assert_eq!(run(&mut h, &["REPLICAOF", "NO", "ONE"]).await, ok());
+        assert_eq!(run(&mut h, &["SET", "k", "v"]).await, ok());
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Display},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, HookTriggers, Lua, Table, Value, Variadic};
+
+use crate::parser::RedisValue;
+
+/// Scripts sent with EVAL or SCRIPT LOAD, keyed by the SHA1 of their body.
+#[derive(Default)]
+pub struct ScriptCache {
+    scripts: Mutex<HashMap<String, String>>,
+}
+
+impl ScriptCache {
+    /// Caches `script` and returns its SHA1 digest.
+    pub fn load(&self, script: &str) -> String {
+        let sha = sha1_hex(script);
+        self.scripts
+            .lock()
+            .unwrap()
+            .insert(sha.clone(), script.to_owned());
+        sha
+    }
+
+    pub fn get(&self, sha: &str) -> Option<String> {
+        self.scripts
+            .lock()
+            .unwrap()
+            .get(&sha.to_lowercase())
+            .cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.scripts
+            .lock()
+            .unwrap()
+            .contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.scripts.lock().unwrap().clear();
+    }
+}
+
+pub fn sha1_hex(data: &str) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+/// Number of Lua instructions between two checks of the time limit.
+const TIME_CHECK_INSTRUCTIONS: u32 = 10_000;
+
+/// Error reply of a `redis.call`, raised through Lua so that the script
+/// aborts and EVAL replies with the original error.
+#[derive(Debug)]
+struct CallError(String);
+
+impl Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CallError {}
+
+/// Runs `script` in a fresh Lua interpreter with the `KEYS`, `ARGV` and
+/// `redis` globals set up. `call` executes one command for `redis.call` and
+/// `redis.pcall`, and is expected to run against an already locked store so
+/// the whole script is atomic. The script is stopped once it runs longer
+/// than `time_limit`.
+pub fn run_script(
+    script: &str,
+    keys: Vec<String>,
+    args: Vec<String>,
+    time_limit: Option<Duration>,
+    call: impl FnMut(Vec<String>) -> RedisValue,
+) -> RedisValue {
+    run_with_redis_lib(call, time_limit, |lua| {
+        lua.globals().set("KEYS", keys)?;
+        lua.globals().set("ARGV", args)?;
+        lua.load(script).set_name("user_script").eval()
+    })
+}
+
+/// Loads a function library and calls `name` from it with the keys and
+/// arguments as its two parameters.
+pub fn run_function(
+    code: &str,
+    name: &str,
+    keys: Vec<String>,
+    args: Vec<String>,
+    time_limit: Option<Duration>,
+    call: impl FnMut(Vec<String>) -> RedisValue,
+) -> RedisValue {
+    run_with_redis_lib(call, time_limit, |lua| {
+        let registry = register_function_api(lua)?;
+        lua.load(library_body(code)).set_name(name).exec()?;
+        let function: Table = registry.get(name)?;
+        let callback: Function = function.get("callback")?;
+        callback.call((keys, args))
+    })
+}
+
+/// Runs the code of a function library and returns the functions it
+/// registers. `redis.call` is not available while loading.
+pub fn discover_functions(code: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let lua = Lua::new();
+    let discover = || -> mlua::Result<Vec<(String, Vec<String>)>> {
+        let registry = register_function_api(&lua)?;
+        lua.load(library_body(code)).set_name("library").exec()?;
+        let mut functions = vec![];
+        for pair in registry.pairs::<String, Table>() {
+            let (name, function) = pair?;
+            let flags: Vec<String> = function.get("flags")?;
+            functions.push((name, flags));
+        }
+        functions.sort();
+        Ok(functions)
+    };
+    discover().map_err(|e| e.to_string().lines().next().unwrap_or_default().to_owned())
+}
+
+/// Skips the `#!lua name=<library>` shebang line so Lua does not parse it.
+fn library_body(code: &str) -> &str {
+    if code.starts_with("#!") {
+        code.find('\n').map_or("", |pos| &code[pos..])
+    } else {
+        code
+    }
+}
+
+/// Installs `redis.register_function` (both the positional and the table
+/// form) and returns the table the registered functions are collected in.
+fn register_function_api(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let redis = match lua.globals().get::<_, Value>("redis")? {
+        Value::Table(redis) => redis,
+        _ => {
+            let redis = lua.create_table()?;
+            register_helpers(lua, &redis)?;
+            lua.globals().set("redis", redis.clone())?;
+            redis
+        }
+    };
+    let registry = lua.create_table()?;
+    let register: Function = lua
+        .load(
+            r#"
+            local registry = ...
+            return function(name, callback)
+                local flags = {}
+                if type(name) == 'table' then
+                    callback = name.callback
+                    flags = name.flags or {}
+                    name = name.function_name
+                end
+                if type(name) ~= 'string' or type(callback) ~= 'function' then
+                    error('wrong arguments to redis.register_function')
+                end
+                if registry[name] then
+                    error('Function ' .. name .. ' already exists')
+                end
+                registry[name] = {callback = callback, flags = flags}
+            end
+            "#,
+        )
+        .call(registry.clone())?;
+    redis.set("register_function", register)?;
+    Ok(registry)
+}
+
+fn run_with_redis_lib(
+    call: impl FnMut(Vec<String>) -> RedisValue,
+    time_limit: Option<Duration>,
+    body: impl for<'lua> FnOnce(&'lua Lua) -> mlua::Result<Value<'lua>>,
+) -> RedisValue {
+    let lua = Lua::new();
+    if let Some(limit) = time_limit {
+        let start = Instant::now();
+        let triggers = HookTriggers::new().every_nth_instruction(TIME_CHECK_INSTRUCTIONS);
+        lua.set_hook(triggers, move |_, _| {
+            if start.elapsed() < limit {
+                return Ok(());
+            }
+            Err(mlua::Error::external(CallError(format!(
+                "BUSY Script stopped after running for more than {} ms",
+                limit.as_millis()
+            ))))
+        });
+    }
+    let call = RefCell::new(call);
+    let result = lua.scope(|scope| {
+        let redis = lua.create_table()?;
+        redis.set(
+            "call",
+            scope.create_function(|lua, args: Variadic<Value>| {
+                match (call.borrow_mut())(to_command(args)?) {
+                    RedisValue::Error(e) => Err(mlua::Error::external(CallError(e))),
+                    reply => to_lua(lua, reply),
+                }
+            })?,
+        )?;
+        redis.set(
+            "pcall",
+            scope.create_function(|lua, args: Variadic<Value>| {
+                to_lua(lua, (call.borrow_mut())(to_command(args)?))
+            })?,
+        )?;
+        register_helpers(&lua, &redis)?;
+        lua.globals().set("redis", redis)?;
+        let value = body(&lua)?;
+        Ok(to_redis(value))
+    });
+    result.unwrap_or_else(error_reply)
+}
+
+fn register_helpers(lua: &Lua, redis: &Table) -> mlua::Result<()> {
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, msg: String| {
+            let reply = lua.create_table()?;
+            reply.set("err", msg)?;
+            Ok(reply)
+        })?,
+    )?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, msg: String| {
+            let reply = lua.create_table()?;
+            reply.set("ok", msg)?;
+            Ok(reply)
+        })?,
+    )?;
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_, data: String| Ok(sha1_hex(&data)))?,
+    )?;
+    redis.set("log", lua.create_function(|_, _: Variadic<Value>| Ok(()))?)?;
+    for (i, level) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"]
+        .into_iter()
+        .enumerate()
+    {
+        redis.set(level, i)?;
+    }
+    Ok(())
+}
+
+fn to_command(args: Variadic<Value>) -> mlua::Result<Vec<String>> {
+    if args.is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "Please specify at least one argument for this redis lib call".to_owned(),
+        ));
+    }
+    args.into_iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(s.to_str()?.to_owned()),
+            Value::Integer(i) => Ok(i.to_string()),
+            Value::Number(n) => Ok(format_number(n)),
+            _ => Err(mlua::Error::RuntimeError(
+                "Lua redis lib command arguments must be strings or integers".to_owned(),
+            )),
+        })
+        .collect()
+}
+
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        (n as i64).to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+/// Converts a command reply into the Lua value a script sees.
+fn to_lua(lua: &Lua, value: RedisValue) -> mlua::Result<Value<'_>> {
+    Ok(match value {
+        RedisValue::SimpleString(s) => {
+            let table = lua.create_table()?;
+            table.set("ok", s)?;
+            Value::Table(table)
+        }
+        RedisValue::Error(e) => {
+            let table = lua.create_table()?;
+            table.set("err", e)?;
+            Value::Table(table)
+        }
+        RedisValue::BulkString(s) => Value::String(lua.create_string(&s)?),
+        RedisValue::BulkBytes(b) => Value::String(lua.create_string(&b)?),
+        RedisValue::Integer(i) => Value::Integer(i),
+        RedisValue::NullBulkString | RedisValue::NullArray => Value::Boolean(false),
+        RedisValue::Array(values) => {
+            let table = lua.create_table()?;
+            for (i, value) in values.into_iter().enumerate() {
+                table.set(i + 1, to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+/// Converts the value returned by a script into its reply, following the
+/// Redis conversion rules (numbers are truncated, arrays stop at the first nil).
+fn to_redis(value: Value) -> RedisValue {
+    match value {
+        Value::String(s) => RedisValue::BulkString(s.to_string_lossy().into_owned()),
+        Value::Integer(i) => RedisValue::Integer(i),
+        Value::Number(n) => RedisValue::Integer(n as i64),
+        Value::Boolean(true) => RedisValue::Integer(1),
+        Value::Table(table) => {
+            if let Ok(Value::String(err)) = table.raw_get::<_, Value>("err") {
+                return RedisValue::Error(err.to_string_lossy().into_owned());
+            }
+            if let Ok(Value::String(ok)) = table.raw_get::<_, Value>("ok") {
+                return RedisValue::SimpleString(ok.to_string_lossy().into_owned());
+            }
+            let values = table
+                .sequence_values::<Value>()
+                .map_while(|value| value.ok())
+                .map(to_redis)
+                .collect();
+            RedisValue::Array(values)
+        }
+        _ => RedisValue::NullBulkString,
+    }
+}
+
+fn error_reply(err: mlua::Error) -> RedisValue {
+    if let Some(CallError(reply)) = find_call_error(&err) {
+        return RedisValue::Error(reply.clone());
+    }
+    let message = err.to_string();
+    let message = message.lines().next().unwrap_or_default();
+    RedisValue::Error(format!("ERR Error running script: {message}"))
+}
+
+fn find_call_error(err: &mlua::Error) -> Option<&CallError> {
+    match err {
+        mlua::Error::CallbackError { cause, .. } => find_call_error(cause),
+        mlua::Error::ExternalError(e) => e.downcast_ref::<CallError>(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        parser::RedisValue,
+        scripting::{discover_functions, run_function, run_script, sha1_hex},
+    };
+
+    fn no_calls(_: Vec<String>) -> RedisValue {
+        panic!("unexpected redis.call")
+    }
+
+    #[test]
+    fn should_compute_sha1() {
+        assert_eq!(
+            sha1_hex("return 1"),
+            "e0e1f9fabfc9d4800c877a703b823ac0578ff8db"
+        );
+    }
+
+    #[test]
+    fn should_convert_return_values() {
+        let script = "return {1, 'two', 3.7, true, {ok='fine'}, nil, 'hidden'}";
+        assert_eq!(
+            run_script(script, vec![], vec![], None, no_calls),
+            RedisValue::Array(vec![
+                RedisValue::Integer(1),
+                RedisValue::BulkString("two".to_owned()),
+                RedisValue::Integer(3),
+                RedisValue::Integer(1),
+                RedisValue::SimpleString("fine".to_owned()),
+            ])
+        );
+    }
+
+    #[test]
+    fn should_pass_keys_and_args_to_calls() {
+        let mut calls = vec![];
+        let result = run_script(
+            "return redis.call('SET', KEYS[1], ARGV[1], 'PX', 100)",
+            vec!["k".to_owned()],
+            vec!["v".to_owned()],
+            None,
+            |args| {
+                calls.push(args);
+                RedisValue::SimpleString("OK".to_owned())
+            },
+        );
+        assert_eq!(result, RedisValue::SimpleString("OK".to_owned()));
+        assert_eq!(calls, vec![vec!["SET", "k", "v", "PX", "100"]]);
+    }
+
+    #[test]
+    fn should_raise_call_errors_and_catch_pcall_errors() {
+        let failing = |_| RedisValue::Error("WRONGTYPE bad".to_owned());
+        assert_eq!(
+            run_script(
+                "redis.call('GET', 'x') return 1",
+                vec![],
+                vec![],
+                None,
+                failing
+            ),
+            RedisValue::Error("WRONGTYPE bad".to_owned())
+        );
+        assert_eq!(
+            run_script(
+                "return redis.pcall('GET', 'x')",
+                vec![],
+                vec![],
+                None,
+                failing
+            ),
+            RedisValue::Error("WRONGTYPE bad".to_owned())
+        );
+    }
+
+    #[test]
+    fn should_discover_and_run_library_functions() {
+        let code = "#!lua name=mylib
+            redis.register_function('echo_key', function(keys, args) return keys[1] end)
+            redis.register_function{function_name='ro', callback=function() return 1 end, flags={'no-writes'}}";
+        assert_eq!(
+            discover_functions(code).unwrap(),
+            vec![
+                ("echo_key".to_owned(), vec![]),
+                ("ro".to_owned(), vec!["no-writes".to_owned()])
+            ]
+        );
+        assert_eq!(
+            run_function(
+                code,
+                "echo_key",
+                vec!["k".to_owned()],
+                vec![],
+                None,
+                no_calls
+            ),
+            RedisValue::BulkString("k".to_owned())
+        );
+    }
+
+    #[test]
+    fn should_not_allow_calls_while_loading_library() {
+        let code = "#!lua name=bad\nredis.call('GET', 'x')";
+        assert!(discover_functions(code).is_err());
+    }
+
+    #[test]
+    fn should_report_script_errors() {
+        let result = run_script("return +", vec![], vec![], None, no_calls);
+        assert!(
+            matches!(result, RedisValue::Error(e) if e.starts_with("ERR Error running script"))
+        );
+    }
+
+    #[test]
+    fn should_stop_scripts_running_too_long() {
+        let limit = Some(Duration::from_millis(50));
+        let result = run_script("while true do end", vec![], vec![], limit, no_calls);
+        assert!(matches!(result, RedisValue::Error(e) if e.starts_with("BUSY")));
+        assert_eq!(
+            run_script("return 1", vec![], vec![], limit, no_calls),
+            RedisValue::Integer(1)
+        );
+    }
+}
@@ -1,55 +1,194 @@
 use crate::{
     config::SystemConfigArc,
-    parser::{parse_redis_value, RedisValue},
-    request::{get_request, RequestHandler},
+    parser::{frame_length, parse_next, RedisValue},
+    replication::{LinkState, LinkStatus, MasterLink},
+    request::{get_request, Request, RequestHandler},
     store::StoreArc,
 };
 use anyhow::anyhow;
 use anyhow::Result;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    time::{interval, sleep},
 };
 
-pub async fn start_slave_replica(store: StoreArc, config: SystemConfigArc) {
-    let (ip, port) = config.get_replication_config().get_ip_port();
-    let mut tcp_stream = TcpStream::connect(format!("{ip}:{port}")).await.unwrap();
-    handshake_with_master(config.clone(), &mut tcp_stream)
-        .await
-        .unwrap();
-    handle_updates_from_master(&mut tcp_stream, store, config).await;
+/// How often a replica reports the offset it applied to its master.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+/// Delay before connecting to the master again after the link dropped,
+/// doubled after every failed attempt up to the maximum.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Makes this server a replica of `ip:port`, following the new master
+/// instead of the current one if there is one.
+pub fn replicate(store: &StoreArc, config: SystemConfigArc, ip: String, port: String) {
+    // The old link stops before the new one starts, so the two never apply
+    // a stream at the same time.
+    let replication = store.get_replication();
+    replication.set_master(None);
+    let status = Arc::new(Mutex::new(LinkStatus::default()));
+    let task = tokio::spawn(start_slave_replica(
+        store.clone(),
+        config,
+        ip.clone(),
+        port.clone(),
+        status.clone(),
+    ));
+    replication.set_master(Some(MasterLink {
+        ip,
+        port,
+        task,
+        status,
+    }));
+}
+
+/// Stops replicating and turns this server into a master, keeping its
+/// dataset.
+pub fn stop_replicating(store: &StoreArc) {
+    let replication = store.get_replication();
+    if replication.set_master(None) {
+        replication.promote();
+    }
+}
+
+/// Follows the master at `ip:port`, connecting again with an exponential
+/// backoff whenever the link drops or cannot be established.
+async fn start_slave_replica(
+    store: StoreArc,
+    config: SystemConfigArc,
+    ip: String,
+    port: String,
+    status: Arc<Mutex<LinkStatus>>,
+) {
+    // Kept across links, so a stream continued with a partial
+    // synchronization applies to the database it last selected.
+    let mut req_handler = RequestHandler::for_master_link(store.clone(), config.clone());
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        let address = format!("{ip}:{port}");
+        let link = sync_with_master(&store, &config, &address, &status, &mut req_handler);
+        if let Err(e) = link.await {
+            eprintln!("lost the link to master {address}: {e}");
+        }
+        if set_state(&status, LinkState::Connect) == LinkState::Connected {
+            delay = MIN_RECONNECT_DELAY;
+        }
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Sets the state of the link, returning the previous one.
+fn set_state(status: &Mutex<LinkStatus>, state: LinkState) -> LinkState {
+    std::mem::replace(&mut status.lock().unwrap().state, state)
+}
+
+/// Connects to the master and synchronizes with it, then applies its
+/// stream until the link drops.
+async fn sync_with_master(
+    store: &StoreArc,
+    config: &SystemConfigArc,
+    address: &str,
+    status: &Mutex<LinkStatus>,
+    req_handler: &mut RequestHandler,
+) -> Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    set_state(status, LinkState::Handshake);
+    // Bytes read from the master but not handled yet. Replies, the RDB and
+    // the command stream can arrive in the same read.
+    let mut buf = BytesMut::with_capacity(512);
+    let replication = store.get_replication();
+    let psync_point = replication.get_psync_point();
+    match handshake_with_master(config, &mut stream, &mut buf, psync_point).await? {
+        SyncReply::Full(replid, offset) => {
+            set_state(status, LinkState::Transfer);
+            load_snapshot(store, &mut stream, &mut buf).await?;
+            replication.follow(&replid, offset);
+            *req_handler = RequestHandler::for_master_link(store.clone(), config.clone());
+        }
+        SyncReply::Continue(replid) => {
+            replication.resume(&replid);
+            req_handler.discard_master_stream();
+        }
+    }
+    *status.lock().unwrap() = LinkStatus {
+        state: LinkState::Connected,
+        last_io: Some(Instant::now()),
+    };
+    handle_updates_from_master(&mut stream, buf, store, req_handler, status).await
+}
+
+/// How the master answered PSYNC.
+enum SyncReply {
+    /// A snapshot follows, taken at this offset of the stream with this ID.
+    Full(String, u64),
+    /// The stream continues from the offset asked for, with this ID.
+    Continue(String),
 }
 
-async fn handshake_with_master(config: SystemConfigArc, stream: &mut TcpStream) -> Result<()> {
+async fn handshake_with_master(
+    config: &SystemConfigArc,
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+    (replid, offset): (String, i64),
+) -> Result<SyncReply> {
     let handshake1 = make_command(vec!["PING"]);
-    send_command(stream, handshake1).await;
-    check_response(stream, RedisValue::SimpleString("PONG".to_owned())).await?;
+    send_command(stream, handshake1).await?;
+    check_response(stream, buf, RedisValue::SimpleString("PONG".to_owned())).await?;
 
     let handshake2 = make_command(vec!["REPLCONF", "listening-port", &config.get_port()]);
-    send_command(stream, handshake2).await;
-    check_response(stream, RedisValue::SimpleString("OK".to_owned())).await?;
+    send_command(stream, handshake2).await?;
+    check_response(stream, buf, RedisValue::SimpleString("OK".to_owned())).await?;
 
     let handshake3 = make_command(vec!["REPLCONF", "capa", "psync2"]);
-    send_command(stream, handshake3).await;
-    check_response(stream, RedisValue::SimpleString("OK".to_owned())).await?;
+    send_command(stream, handshake3).await?;
+    check_response(stream, buf, RedisValue::SimpleString("OK".to_owned())).await?;
 
-    let handshake4 = make_command(vec!["PSYNC", "?", "-1"]);
-    send_command(stream, handshake4).await;
-    check_response(stream, RedisValue::SimpleString("FULLRESYNC 75cd7bc10c49047e0d163660f3b90625b1af31dc 0".to_owned())).await?;
-    // read_rdb_file(stream).await?;
+    let handshake4 = make_command(vec!["PSYNC", &replid, &offset.to_string()]);
+    send_command(stream, handshake4).await?;
+    let response = read_response(stream, buf).await?;
+    let reply = match &response {
+        RedisValue::SimpleString(reply) => parse_psync_reply(reply, &replid),
+        _ => None,
+    };
+    let Some(reply) = reply else {
+        return Err(anyhow!(
+            "Invalid reponse from master: {:?} expected: FULLRESYNC or CONTINUE",
+            response
+        ));
+    };
     println!("handshake done");
-    Ok(())
+    Ok(reply)
 }
 
-async fn read_rdb_file(stream: &mut TcpStream) -> Result<()> {
-    let mut buf = BytesMut::with_capacity(512);
-    let read_size = stream.read_buf(&mut buf).await.unwrap();
-    if read_size == 0 {
-        return Err(anyhow!("No response from master"));
+/// Parses `FULLRESYNC <replid> <offset>`, whatever ID the master chose, or
+/// `CONTINUE [<replid>]`, which keeps `replid` when the master omits it.
+fn parse_psync_reply(reply: &str, replid: &str) -> Option<SyncReply> {
+    let mut parts = reply.split(' ');
+    match parts.next()? {
+        "FULLRESYNC" => {
+            let replid = parts.next()?;
+            let offset = parts.next()?.parse().ok()?;
+            Some(SyncReply::Full(replid.to_owned(), offset))
+        }
+        "CONTINUE" => {
+            let replid = parts.next().unwrap_or(replid);
+            Some(SyncReply::Continue(replid.to_owned()))
+        }
+        _ => None,
     }
-    println!("rdb buf: {:?}", buf);
-    Ok(())
+}
+
+/// Replaces the dataset with the snapshot the master sends after
+/// FULLRESYNC, so the command stream that follows applies on top of it.
+async fn load_snapshot(store: &StoreArc, stream: &mut TcpStream, buf: &mut BytesMut) -> Result<()> {
+    store.replace_with_rdb_stream(stream, buf).await
 }
 
 fn make_command(commands: Vec<&str>) -> RedisValue {
@@ -60,13 +199,24 @@ fn make_command(commands: Vec<&str>) -> RedisValue {
     RedisValue::Array(redis_commands)
 }
 
-async fn check_response(stream: &mut TcpStream, expected_response: RedisValue) -> Result<()> {
-    let mut buf = BytesMut::with_capacity(512);
-    let read_size = stream.read_buf(&mut buf).await.unwrap();
-    if read_size == 0 {
-        return Err(anyhow!("No response from master"));
+/// Reads the next reply, waiting for more bytes while it is incomplete.
+async fn read_response(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<RedisValue> {
+    loop {
+        if let Some(response) = parse_next(buf)? {
+            return Ok(response);
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(anyhow!("No response from master"));
+        }
     }
-    let response = parse_redis_value(&mut buf).unwrap();
+}
+
+async fn check_response(
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+    expected_response: RedisValue,
+) -> Result<()> {
+    let response = read_response(stream, buf).await?;
     if response != expected_response {
         return Err(anyhow!(
             "Invalid reponse from master: {:?} expected: {:?}",
@@ -77,28 +227,236 @@ async fn check_response(stream: &mut TcpStream, expected_response: RedisValue) -
     Ok(())
 }
 
-async fn send_command(tcp_stream: &mut TcpStream, command: RedisValue) {
-    tcp_stream
-        .write_all(command.serialize().as_bytes())
-        .await
-        .unwrap();
+async fn send_command(tcp_stream: &mut TcpStream, command: RedisValue) -> Result<()> {
+    tcp_stream.write_all(&command.serialize()).await?;
+    Ok(())
 }
 
+/// Applies the master's stream and acknowledges it, until the link drops.
 async fn handle_updates_from_master(
     stream: &mut TcpStream,
-    store: StoreArc,
-    config: SystemConfigArc,
-) {
-    let mut buf = BytesMut::with_capacity(512);
-    let mut req_handler = RequestHandler::new(store, config.clone());
+    mut buf: BytesMut,
+    store: &StoreArc,
+    req_handler: &mut RequestHandler,
+    status: &Mutex<LinkStatus>,
+) -> Result<()> {
+    let mut ack_interval = interval(ACK_PERIOD);
     loop {
-        let read_size = stream.read_buf(&mut buf).await.unwrap();
-        if read_size == 0 {
-            return;
+        while let Some((value, bytes)) = next_command(&mut buf)? {
+            // Every byte counts towards the replication offset, even the
+            // ones of commands this server does not know.
+            req_handler.record_master_stream(&bytes);
+            let request = match get_request(value) {
+                Result::Ok(request) => request,
+                Err(e) => {
+                    eprintln!("failed to apply a command from master: {e}");
+                    continue;
+                }
+            };
+            if matches!(&request, Request::REPLCONF(options) if options.first().is_some_and(|(option, _)| option == "getack"))
+            {
+                // The offset acknowledged does not include the GETACK itself.
+                send_ack(stream, store).await?;
+            }
+            let _response = req_handler.handle_request(request).await;
+        }
+        tokio::select! {
+            read_size = stream.read_buf(&mut buf) => {
+                if read_size? == 0 {
+                    return Err(anyhow!("Connection closed by master"));
+                }
+                status.lock().unwrap().last_io = Some(Instant::now());
+            }
+            _ = ack_interval.tick() => send_ack(stream, store).await?,
+        }
+    }
+}
+
+/// Sends REPLCONF ACK with the offset of the master's stream applied so
+/// far. With an AOF, the offset is also reported as FACK once it is synced.
+async fn send_ack(stream: &mut TcpStream, store: &StoreArc) -> Result<()> {
+    let offset = store.get_replication().get_offset().to_string();
+    let mut ack = vec!["REPLCONF", "ACK", &offset];
+    let aof = store.get_aof();
+    if aof.is_enabled() {
+        aof.flush()?;
+        aof.sync()?;
+        ack.extend(["FACK", &offset]);
+    }
+    stream.write_all(&make_command(ack).serialize()).await?;
+    Ok(())
+}
+
+/// Parses the next command of the master's stream along with its bytes.
+fn next_command(buf: &mut BytesMut) -> Result<Option<(RedisValue, Bytes)>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    let Some(len) = frame_length(buf, 0)? else {
+        return Ok(None);
+    };
+    let mut frame = buf.split_to(len);
+    let bytes = frame.clone().freeze();
+    Ok(parse_next(&mut frame)?.map(|value| (value, bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        config::SystemConfig,
+        parser::RedisValue,
+        rdb::{write_rdb, RdbDatabase, RdbFile},
+        replication::LinkStatus,
+        request::RequestHandler,
+        slave::{replicate, stop_replicating, sync_with_master},
+        store::Store,
+        value::Value,
+    };
+
+    const REPLID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+
+    fn command(parts: &[&str]) -> Vec<u8> {
+        RedisValue::make_bulk_array(parts.iter().map(|part| part.to_string()).collect()).serialize()
+    }
+
+    /// Answers the handshake of a replica up to PSYNC, which is returned.
+    async fn accept_handshake(listener: &TcpListener) -> (TcpStream, Vec<u8>) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 512];
+        for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n"] {
+            let _ = socket.read(&mut buf).await.unwrap();
+            socket.write_all(reply.as_bytes()).await.unwrap();
+        }
+        let len = socket.read(&mut buf).await.unwrap();
+        (socket, buf[..len].to_vec())
+    }
+
+    /// Sends FULLRESYNC at `offset`, the snapshot of `file` and `stream` in
+    /// one write, then waits for the replica to acknowledge them.
+    async fn full_sync(socket: &mut TcpStream, offset: u64, file: &RdbFile, stream: &[u8]) {
+        let mut image = vec![];
+        write_rdb(file, &mut image).unwrap();
+        let mut data = format!("+FULLRESYNC {REPLID} {offset}\r\n").into_bytes();
+        data.extend(format!("${}\r\n", image.len()).into_bytes());
+        data.extend(image);
+        data.extend(stream);
+        socket.write_all(&data).await.unwrap();
+        let _ = socket.read(&mut [0; 512]).await.unwrap();
+    }
+
+    fn empty_file() -> RdbFile {
+        RdbFile {
+            version: 11,
+            aux: vec![],
+            databases: BTreeMap::new(),
+            functions: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn should_load_the_snapshot_before_applying_the_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let file = RdbFile {
+            databases: BTreeMap::from([(
+                0,
+                RdbDatabase {
+                    key_vals: HashMap::from([(
+                        "snapshot".to_owned(),
+                        Arc::new(Value::String("1".to_owned())),
+                    )]),
+                    key_expires: HashMap::new(),
+                },
+            )]),
+            ..empty_file()
+        };
+        let set = command(&["SET", "streamed", "2"]);
+        let stream = set.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = accept_handshake(&listener).await;
+            full_sync(&mut socket, 100, &file, &stream).await;
+        });
+
+        let config = Arc::new(SystemConfig::default());
+        let store = Arc::new(Store::new());
+        store.set("stale".to_owned(), "0".to_owned()).await;
+        let status = Mutex::new(LinkStatus::default());
+        let mut handler = RequestHandler::for_master_link(store.clone(), config.clone());
+        let link = sync_with_master(&store, &config, &address, &status, &mut handler);
+        assert!(link.await.is_err());
+
+        let replication = store.get_replication();
+        assert_eq!(replication.get_offset(), 100 + set.len() as u64);
+        assert!(replication
+            .info()
+            .contains(&format!("master_replid:{REPLID}\n")));
+
+        let mut keyspace = store.lock().await;
+        assert_eq!(keyspace.db(0).get("stale").unwrap(), None);
+        assert_eq!(
+            keyspace.db(0).get("snapshot").unwrap(),
+            Some("1".to_owned())
+        );
+        assert_eq!(
+            keyspace.db(0).get("streamed").unwrap(),
+            Some("2".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reconnect_and_continue_the_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let stream = [command(&["SELECT", "1"]), command(&["SET", "a", "1"])].concat();
+        let offset = 100 + stream.len() as u64;
+        let master = tokio::spawn(async move {
+            // The first connection drops before the handshake.
+            drop(listener.accept().await.unwrap());
+            let (mut socket, psync) = accept_handshake(&listener).await;
+            assert_eq!(psync, command(&["PSYNC", "?", "-1"]));
+            full_sync(&mut socket, 100, &empty_file(), &stream).await;
+            drop(socket);
+            let (mut socket, psync) = accept_handshake(&listener).await;
+            socket.write_all(b"+CONTINUE\r\n").await.unwrap();
+            socket
+                .write_all(&command(&["SET", "b", "2"]))
+                .await
+                .unwrap();
+            (socket, psync)
+        });
+
+        let store = Arc::new(Store::new());
+        let config = Arc::new(SystemConfig::default());
+        replicate(&store, config, "127.0.0.1".to_owned(), port);
+        let (_socket, psync) = master.await.unwrap();
+        let next = (offset + 1).to_string();
+        assert_eq!(psync, command(&["PSYNC", REPLID, &next]));
+
+        for _ in 0..100 {
+            if store.lock().await.db(1).get("b").unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-        println!("rdb buf: {:?}", buf);
-        let request = get_request(parse_redis_value(&mut buf).unwrap()).unwrap();
-        println!("receiving update from master {:?}", request);
-        let _response = req_handler.handle_request(request.clone()).await;
+        // The continued stream applies to the database selected before.
+        assert_eq!(
+            store.lock().await.db(1).get("b").unwrap(),
+            Some("2".to_owned())
+        );
+        let info = store.get_replication().info();
+        assert!(info.contains("master_link_status:up\n"));
+        assert!(info.contains("master_sync_in_progress:0\n"));
+        stop_replicating(&store);
     }
 }
@@ -1,62 +1,1001 @@
-use std::sync::Arc;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, time::Duration};
 
-use tokio::sync::Mutex;
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use tokio::io::AsyncRead;
+use tokio::sync::{Mutex, MutexGuard};
 
 use tokio::time::sleep;
+
+use crate::aof::Aof;
+use crate::functions::FunctionLibraries;
+use crate::glob::glob_match;
+use crate::notify::{self, Notifier};
+use crate::persistence::Persistence;
+use crate::pubsub::{PubSub, PubSubArc};
+use crate::rdb::{self, RdbDatabase, RdbFile, RdbItem};
+use crate::replication::Replication;
+use crate::request::{Request, WriteEffect};
+use crate::scripting::ScriptCache;
+use crate::value::{Entry, Value};
+
+const EXPIRE_CYCLE_PERIOD: Duration = Duration::from_millis(100);
+const DEFAULT_DATABASES: usize = 16;
+pub const DEFAULT_BUSY_REPLY_THRESHOLD: u64 = 5000;
+/// Version reported in saved files, the one whose RDB format we write.
+const REDIS_VERSION: &str = "7.2.0";
+pub const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
 pub type StoreArc = Arc<Store>;
 pub struct Store {
-    data: Arc<Mutex<HashMap<String, String>>>,
+    data: Arc<Mutex<Keyspace>>,
+    notifier: Arc<Notifier>,
+    scripts: ScriptCache,
+    functions: FunctionLibraries,
+    persistence: Arc<Persistence>,
+    aof: Arc<Aof>,
+    replication: Arc<Replication>,
+    /// Milliseconds a script may run before it is stopped, 0 for no limit.
+    busy_reply_threshold: AtomicU64,
+}
+
+/// The data behind the store lock. Commands run against a locked `Keyspace`
+/// so that a whole transaction can execute without other clients interleaving.
+pub struct Keyspace {
+    databases: Vec<Database>,
+}
+
+/// One of the numbered databases a connection can SELECT.
+pub struct Database {
+    index: usize,
+    values: HashMap<String, Entry>,
+    expires: HashMap<String, SystemTime>,
+    /// Deadlines in expiry order. Entries may be stale when a key was
+    /// overwritten, so they are checked against `expires` when popped.
+    expire_queue: BinaryHeap<Reverse<(SystemTime, String)>>,
+    /// Modification versions of the keys some connection is watching.
+    watched: HashMap<String, WatchedKey>,
+    notifier: Arc<Notifier>,
+    /// Xorshift state for RANDOMKEY and the LFU counter.
+    rng_state: u64,
+    /// Keys removed because they expired, not yet propagated as DEL.
+    expired: Vec<String>,
+}
+
+struct WatchedKey {
+    watchers: usize,
+    version: u64,
+}
+
+impl Database {
+    fn new(index: usize, notifier: Arc<Notifier>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Database {
+            index,
+            values: HashMap::new(),
+            expires: HashMap::new(),
+            expire_queue: BinaryHeap::new(),
+            watched: HashMap::new(),
+            notifier,
+            rng_state: (seed ^ index as u64) | 1,
+            expired: vec![],
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        self.rng_state
+    }
+
+    fn is_expired(&self, key: &str) -> bool {
+        self.expires
+            .get(key)
+            .is_some_and(|deadline| *deadline <= SystemTime::now())
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.signal_modified(key);
+        self.expires.remove(key);
+        self.values.remove(key)
+    }
+
+    /// Bumps the version of a watched key so pending transactions on it fail.
+    fn signal_modified(&mut self, key: &str) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+    }
+
+    /// Starts watching `key` and returns its current version.
+    pub fn watch(&mut self, key: &str) -> u64 {
+        self.expire_if_needed(key);
+        let watched = self.watched.entry(key.to_owned()).or_insert(WatchedKey {
+            watchers: 0,
+            version: 0,
+        });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    pub fn unwatch(&mut self, key: &str) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// Whether `key` was modified, deleted or has expired since it was
+    /// watched at `version`.
+    pub fn is_modified_since(&mut self, key: &str, version: u64) -> bool {
+        self.expire_if_needed(key);
+        !matches!(self.watched.get(key), Some(watched) if watched.version == version)
+    }
+
+    /// Lazily expires `key` on access, the same way the expire cycle would.
+    fn expire_if_needed(&mut self, key: &str) {
+        if self.is_expired(key) {
+            self.remove(key);
+            self.expired.push(key.to_owned());
+            self.notifier
+                .notify(notify::EXPIRED, "expired", key, self.index);
+        }
+    }
+
+    /// Removes every key whose deadline has passed.
+    fn remove_expired_keys(&mut self) {
+        let now = SystemTime::now();
+        while let Some(Reverse((deadline, _))) = self.expire_queue.peek() {
+            if *deadline > now {
+                break;
+            }
+            let Reverse((deadline, key)) = self.expire_queue.pop().unwrap();
+            if self.expires.get(&key) == Some(&deadline) {
+                self.expire_if_needed(&key);
+            }
+        }
+    }
+
+    /// Looks up a live key and records the access in its LRU/LFU metadata.
+    fn lookup(&mut self, key: &str) -> Option<&Entry> {
+        self.expire_if_needed(key);
+        let random = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;
+        let entry = self.values.get_mut(key)?;
+        entry.touch(random);
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: String, val: String) {
+        self.signal_modified(&key);
+        let is_new = self
+            .values
+            .insert(key.clone(), Entry::new(Value::String(val)))
+            .is_none();
+        if is_new {
+            self.notifier.notify(notify::NEW, "new", &key, self.index);
+        }
+        self.notifier
+            .notify(notify::STRING, "set", &key, self.index);
+    }
+
+    fn set_expire_at(&mut self, key: String, deadline: SystemTime) {
+        self.expires.insert(key.clone(), deadline);
+        self.expire_queue.push(Reverse((deadline, key)));
+    }
+
+    pub fn set(&mut self, key: String, val: String) {
+        self.expires.remove(&key);
+        self.insert(key, val);
+    }
+
+    pub fn set_with_expire(&mut self, key: String, val: String, expire: Duration) {
+        self.set_with_deadline(key, val, SystemTime::now() + expire);
+    }
+
+    pub fn set_with_deadline(&mut self, key: String, val: String, deadline: SystemTime) {
+        self.insert(key.clone(), val);
+        self.set_expire_at(key, deadline);
+    }
+
+    /// Returns the string stored at `key`, failing if it holds another type.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let val = match self.lookup(key).map(|entry| entry.value.as_ref()) {
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(_) => return Err(anyhow!(WRONG_TYPE)),
+            None => None,
+        };
+        if val.is_none() {
+            self.notifier
+                .notify(notify::KEY_MISS, "keymiss", key, self.index);
+        }
+        Ok(val)
+    }
+
+    /// Returns the value at `key`, recording the access.
+    pub fn get_value(&mut self, key: &str) -> Option<Arc<Value>> {
+        self.lookup(key).map(|entry| entry.value.clone())
+    }
+
+    /// Creates `key` from a DUMP payload already decoded into `entry`.
+    /// Fails if the key exists and `replace` is not set. A deadline in the
+    /// past only deletes the replaced key.
+    pub fn restore(
+        &mut self,
+        key: &str,
+        entry: Entry,
+        deadline: Option<SystemTime>,
+        replace: bool,
+    ) -> Result<()> {
+        if self.contains(key) && !replace {
+            return Err(anyhow!("BUSYKEY Target key name already exists."));
+        }
+        if deadline.is_some_and(|deadline| deadline <= SystemTime::now()) {
+            if self.remove(key).is_some() {
+                self.notifier
+                    .notify(notify::GENERIC, "del", key, self.index);
+            }
+            return Ok(());
+        }
+        let is_new = !self.contains(key);
+        self.import(key, entry, deadline);
+        if is_new {
+            self.notifier.notify(notify::NEW, "new", key, self.index);
+        }
+        self.notifier
+            .notify(notify::GENERIC, "restore", key, self.index);
+        Ok(())
+    }
+
+    /// Deletes the given keys and returns how many of them existed.
+    pub fn delete(&mut self, keys: &[String]) -> usize {
+        keys.iter()
+            .filter(|key| {
+                self.expire_if_needed(key);
+                let deleted = self.remove(key).is_some();
+                if deleted {
+                    self.notifier
+                        .notify(notify::GENERIC, "del", key, self.index);
+                }
+                deleted
+            })
+            .count()
+    }
+
+    /// Counts the existing keys, counting a key once per time it is given.
+    pub fn exists(&mut self, keys: &[String]) -> usize {
+        keys.iter().filter(|key| self.lookup(key).is_some()).count()
+    }
+
+    pub fn key_type(&mut self, key: &str) -> &'static str {
+        self.lookup(key)
+            .map_or("none", |entry| entry.value.type_name())
+    }
+
+    /// Alters the last access time of the given keys and returns how many
+    /// of them exist.
+    pub fn touch(&mut self, keys: &[String]) -> usize {
+        self.exists(keys)
+    }
+
+    /// Renames `from` to `to`, keeping its TTL. With `nx` nothing happens if
+    /// `to` exists. Returns whether the key was renamed.
+    pub fn rename(&mut self, from: &str, to: &str, nx: bool) -> Result<bool> {
+        self.expire_if_needed(from);
+        self.expire_if_needed(to);
+        if !self.values.contains_key(from) {
+            return Err(anyhow!("no such key"));
+        }
+        if nx && self.values.contains_key(to) {
+            return Ok(false);
+        }
+        if from == to {
+            return Ok(true);
+        }
+        let (entry, deadline) = self.take(from).unwrap();
+        self.import(to, entry, deadline);
+        self.notifier
+            .notify(notify::GENERIC, "rename_from", from, self.index);
+        self.notifier
+            .notify(notify::GENERIC, "rename_to", to, self.index);
+        Ok(true)
+    }
+
+    /// Removes a live key, returning it with its deadline.
+    fn take(&mut self, key: &str) -> Option<(Entry, Option<SystemTime>)> {
+        self.expire_if_needed(key);
+        let deadline = self.expires.get(key).copied();
+        self.remove(key).map(|entry| (entry, deadline))
+    }
+
+    /// Stores `entry` under `key`, replacing whatever was there.
+    fn import(&mut self, key: &str, entry: Entry, deadline: Option<SystemTime>) {
+        self.remove(key);
+        self.values.insert(key.to_owned(), entry);
+        if let Some(deadline) = deadline {
+            self.set_expire_at(key.to_owned(), deadline);
+        }
+    }
+
+    fn contains(&mut self, key: &str) -> bool {
+        self.expire_if_needed(key);
+        self.values.contains_key(key)
+    }
+
+    /// Bumps every watched key that currently exists, for commands that
+    /// replace the whole database.
+    fn signal_all_modified(&mut self) {
+        for (key, watched) in self.watched.iter_mut() {
+            if self.values.contains_key(key) {
+                watched.version += 1;
+            }
+        }
+    }
+
+    /// Removes every key. With `lazy` the values are freed on another thread.
+    fn flush(&mut self, lazy: bool) {
+        self.signal_all_modified();
+        let values = std::mem::take(&mut self.values);
+        self.expires.clear();
+        self.expire_queue.clear();
+        if lazy {
+            std::thread::spawn(move || drop(values));
+        }
+    }
+
+    pub fn random_key(&mut self) -> Option<String> {
+        while !self.values.is_empty() {
+            let index = self.next_random() as usize % self.values.len();
+            let key = self.values.keys().nth(index).cloned().unwrap();
+            self.expire_if_needed(&key);
+            if self.values.contains_key(&key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    pub fn is_expiring(&self, key: &str) -> bool {
+        self.expires.contains_key(key)
+    }
+
+    /// Number of keys, including expired keys not yet reclaimed, as in Redis.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the entry for OBJECT without counting it as an access.
+    pub fn peek(&mut self, key: &str) -> Option<&Entry> {
+        self.expire_if_needed(key);
+        self.values.get(key)
+    }
+
+    pub fn get_matching_keys(&self, pattern: &str) -> Vec<String> {
+        self.values
+            .keys()
+            .filter(|key| glob_match(pattern, key) && !self.is_expired(key))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Keyspace {
+    fn new(databases: usize, notifier: Arc<Notifier>) -> Self {
+        Keyspace {
+            databases: (0..databases)
+                .map(|index| Database::new(index, notifier.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn db(&mut self, index: usize) -> &mut Database {
+        &mut self.databases[index]
+    }
+
+    pub fn count(&self) -> usize {
+        self.databases.len()
+    }
+
+    fn pair(&mut self, a: usize, b: usize) -> (&mut Database, &mut Database) {
+        assert_ne!(a, b);
+        if a < b {
+            let (left, right) = self.databases.split_at_mut(b);
+            (&mut left[a], &mut right[0])
+        } else {
+            let (left, right) = self.databases.split_at_mut(a);
+            (&mut right[0], &mut left[b])
+        }
+    }
+
+    /// Exchanges the contents of two databases. Connections stay on their
+    /// index, so they see the other data right away.
+    pub fn swap(&mut self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        let (first, second) = self.pair(a, b);
+        first.signal_all_modified();
+        second.signal_all_modified();
+        std::mem::swap(&mut first.values, &mut second.values);
+        std::mem::swap(&mut first.expires, &mut second.expires);
+        std::mem::swap(&mut first.expire_queue, &mut second.expire_queue);
+        first.signal_all_modified();
+        second.signal_all_modified();
+    }
+
+    /// Moves `key` with its TTL to another database. Nothing happens if the
+    /// key is missing or already exists in the target.
+    pub fn move_key(&mut self, key: &str, from: usize, to: usize) -> bool {
+        let (source, target) = self.pair(from, to);
+        if !source.contains(key) || target.contains(key) {
+            return false;
+        }
+        let (entry, deadline) = source.take(key).unwrap();
+        target.import(key, entry, deadline);
+        source
+            .notifier
+            .notify(notify::GENERIC, "move_from", key, from);
+        target.notifier.notify(notify::GENERIC, "move_to", key, to);
+        true
+    }
+
+    /// Copies `source` with its TTL to `destination`, possibly in another
+    /// database. Returns false if the source is missing, or if the
+    /// destination exists and `replace` is not set.
+    pub fn copy(
+        &mut self,
+        source: &str,
+        from: usize,
+        destination: &str,
+        to: usize,
+        replace: bool,
+    ) -> bool {
+        let source_db = &mut self.databases[from];
+        source_db.expire_if_needed(source);
+        let Some(entry) = source_db.values.get(source) else {
+            return false;
+        };
+        let copied = Entry::new(entry.value.clone());
+        let deadline = source_db.expires.get(source).copied();
+        let target = &mut self.databases[to];
+        if target.contains(destination) && !replace {
+            return false;
+        }
+        target.import(destination, copied, deadline);
+        target
+            .notifier
+            .notify(notify::GENERIC, "copy_to", destination, to);
+        true
+    }
+
+    pub fn flush_db(&mut self, index: usize, lazy: bool) {
+        self.databases[index].flush(lazy);
+    }
+
+    pub fn flush_all(&mut self, lazy: bool) {
+        for db in self.databases.iter_mut() {
+            db.flush(lazy);
+        }
+    }
+
+    /// Adds a key read from an RDB image. Fails if the image has more
+    /// databases than this server.
+    fn import(
+        &mut self,
+        db: usize,
+        key: &str,
+        value: Value,
+        deadline: Option<SystemTime>,
+    ) -> Result<()> {
+        if db >= self.count() {
+            return Err(anyhow!("DB index {db} is out of range"));
+        }
+        self.db(db).import(key, Entry::new(value), deadline);
+        Ok(())
+    }
+
+    /// Replaces the keys of every database with those of `other`, like a
+    /// FLUSHALL followed by loading them, but in one step.
+    fn replace(&mut self, other: Keyspace) {
+        for (db, loaded) in self.databases.iter_mut().zip(other.databases) {
+            db.flush(true);
+            db.values = loaded.values;
+            db.expires = loaded.expires;
+            db.expire_queue = loaded.expire_queue;
+            db.signal_all_modified();
+        }
+    }
+
+    fn remove_expired_keys(&mut self) {
+        for db in self.databases.iter_mut() {
+            db.remove_expired_keys();
+        }
+    }
+
+    /// Returns a DEL for every key that expired since the last call. The
+    /// master propagates expirations instead of letting replicas and the AOF
+    /// expire keys on their own clock.
+    pub fn take_expired(&mut self) -> Vec<WriteEffect> {
+        let mut effects = vec![];
+        for db in self.databases.iter_mut() {
+            effects.extend(
+                db.expired
+                    .drain(..)
+                    .map(|key| (db.index, Request::Del(vec![key]))),
+            );
+        }
+        effects
+    }
+
+    /// Copies the live keys of every non-empty database, so they can be
+    /// saved after the lock is released.
+    pub fn snapshot(&self) -> BTreeMap<usize, RdbDatabase> {
+        let mut databases = BTreeMap::new();
+        for db in self.databases.iter().filter(|db| !db.is_empty()) {
+            let mut database = RdbDatabase::default();
+            for (key, entry) in db.values.iter().filter(|(key, _)| !db.is_expired(key)) {
+                database.key_vals.insert(key.clone(), entry.value.clone());
+                if let Some(deadline) = db.expires.get(key) {
+                    database.key_expires.insert(key.clone(), *deadline);
+                }
+            }
+            if !database.key_vals.is_empty() {
+                databases.insert(db.index, database);
+            }
+        }
+        databases
+    }
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Store {
     pub fn new() -> Self {
+        Self::with_databases(DEFAULT_DATABASES)
+    }
+
+    pub fn with_databases(databases: usize) -> Self {
+        let notifier = Arc::new(Notifier::new(Arc::new(PubSub::new())));
+        let data = Arc::new(Mutex::new(Keyspace::new(databases, notifier.clone())));
+        let aof = Arc::new(Aof::default());
+        let replication = Arc::new(Replication::default());
+        start_expire_cycle(Arc::downgrade(&data), aof.clone(), replication.clone());
         Store {
-            data: Arc::new(Mutex::new(HashMap::new())),
+            data,
+            notifier,
+            scripts: ScriptCache::default(),
+            functions: FunctionLibraries::default(),
+            persistence: Arc::new(Persistence::new()),
+            aof,
+            replication,
+            busy_reply_threshold: AtomicU64::new(DEFAULT_BUSY_REPLY_THRESHOLD),
         }
     }
 
-    pub async fn add_multiple_keys(&self, map: HashMap<String, String>) {
+    /// Locks the keyspace for running one or more commands atomically.
+    pub async fn lock(&self) -> MutexGuard<'_, Keyspace> {
+        self.data.lock().await
+    }
+
+    pub fn get_scripts(&self) -> &ScriptCache {
+        &self.scripts
+    }
+
+    pub fn get_functions(&self) -> &FunctionLibraries {
+        &self.functions
+    }
+
+    pub fn get_persistence(&self) -> &Arc<Persistence> {
+        &self.persistence
+    }
+
+    pub fn get_aof(&self) -> &Arc<Aof> {
+        &self.aof
+    }
+
+    pub fn get_replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    /// How long a script may run, or none if it may run forever.
+    pub fn get_script_time_limit(&self) -> Option<Duration> {
+        match self.busy_reply_threshold.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        }
+    }
+
+    pub fn get_busy_reply_threshold(&self) -> u64 {
+        self.busy_reply_threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn set_busy_reply_threshold(&self, millis: u64) {
+        self.busy_reply_threshold.store(millis, Ordering::Relaxed);
+    }
+
+    /// Logs the writes of one request to the AOF and sends them to the
+    /// replicas. Called with the keyspace locked.
+    pub fn propagate(&self, effects: &[WriteEffect]) {
+        self.aof.append(effects);
+        self.replication.propagate(effects);
+    }
+
+    /// Builds an RDB image of the locked keyspace and the function
+    /// libraries.
+    pub fn snapshot(&self, keyspace: &Keyspace) -> RdbFile {
+        let ctime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        RdbFile {
+            version: rdb::RDB_VERSION as u32,
+            aux: vec![
+                ("redis-ver".to_owned(), REDIS_VERSION.to_owned()),
+                ("redis-bits".to_owned(), "64".to_owned()),
+                ("ctime".to_owned(), ctime.to_string()),
+            ],
+            databases: keyspace.snapshot(),
+            functions: self.functions.get_codes(),
+        }
+    }
+
+    pub fn get_pubsub(&self) -> PubSubArc {
+        self.notifier.get_pubsub()
+    }
+
+    pub fn get_notify_keyspace_events(&self) -> String {
+        notify::flags_to_string(self.notifier.get_flags())
+    }
+
+    pub fn set_notify_keyspace_events(&self, flags: &str) -> Result<()> {
+        self.notifier.set_flags(notify::parse_flags(flags)?);
+        Ok(())
+    }
+
+    /// Loads the functions, keys and expire times of an RDB file. Fails if
+    /// the file has more databases than this server.
+    pub async fn load_rdb(&self, file: RdbFile) -> Result<()> {
+        for code in file.functions {
+            if let Err(e) = self.functions.load(&code, true) {
+                eprintln!("failed to load a function library from the RDB file: {e}");
+            }
+        }
+        for (db, database) in file.databases {
+            self.add_multiple_keys(db, database.key_vals).await?;
+            self.set_multiple_expires(db, database.key_expires).await?;
+        }
+        Ok(())
+    }
+
+    /// Loads the RDB file at `path` key by key as it is read, so the file
+    /// is never held in memory as a whole.
+    pub async fn load_rdb_file(self: &Arc<Self>, path: String) -> Result<()> {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let reader = BufReader::new(File::open(path)?);
+            rdb::parse_with(reader, |item| store.load_rdb_item(item))
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Replaces the dataset and the functions with an RDB image sent as a
+    /// `$<len>\r\n` bulk string, as masters do on a full resync. The image
+    /// is loaded into a new dataset while its bytes arrive, and swapped in
+    /// once complete, so clients read the old one in the meantime. Bytes
+    /// read past the image are left in `buf`.
+    pub async fn replace_with_rdb_stream<R: AsyncRead + Unpin>(
+        &self,
+        reader: &mut R,
+        buf: &mut BytesMut,
+    ) -> Result<()> {
+        let len = rdb::read_bulk_len(reader, buf).await?;
+        let count = self.data.lock().await.count();
+        let keyspace = Keyspace::new(count, self.notifier.clone());
+        let loaded = Arc::new(std::sync::Mutex::new((keyspace, vec![])));
+        let sink = loaded.clone();
+        rdb::parse_stream(reader, buf, len, move |item| {
+            let (keyspace, functions) = &mut *sink.lock().unwrap();
+            match item {
+                RdbItem::Function(code) => functions.push(code),
+                RdbItem::Key(db, key, value, deadline) => {
+                    keyspace.import(db, &key, value, deadline)?
+                }
+                RdbItem::Aux(_, _) | RdbItem::ResizeDb(_, _) => {}
+            }
+            Ok(())
+        })
+        .await?;
+        let (keyspace, functions) = Arc::into_inner(loaded)
+            .expect("the parser is done with the dataset")
+            .into_inner()
+            .unwrap();
         let mut data = self.data.lock().await;
+        data.replace(keyspace);
+        self.functions.replace_all(functions);
+        Ok(())
+    }
+
+    /// Adds one section of an RDB file being parsed on a blocking thread.
+    fn load_rdb_item(&self, item: RdbItem) -> Result<()> {
+        match item {
+            RdbItem::Function(code) => {
+                if let Err(e) = self.functions.load(&code, true) {
+                    eprintln!("failed to load a function library from the RDB file: {e}");
+                }
+            }
+            RdbItem::Key(db, key, value, deadline) => {
+                let mut keyspace = self.data.blocking_lock();
+                keyspace.import(db, &key, value, deadline)?;
+            }
+            RdbItem::Aux(_, _) | RdbItem::ResizeDb(_, _) => {}
+        }
+        Ok(())
+    }
+
+    pub async fn add_multiple_keys(
+        &self,
+        db: usize,
+        map: HashMap<String, Arc<Value>>,
+    ) -> Result<()> {
+        let mut data = self.data.lock().await;
+        if db >= data.count() {
+            return Err(anyhow!("DB index {db} is out of range"));
+        }
+        let db = data.db(db);
         for (key, value) in map {
-            data.insert(key, value);
+            db.values.insert(key, Entry::new(value));
         }
+        Ok(())
     }
 
-    pub fn set_multiple_expires(&self, map: HashMap<String, Duration>) {
-        for (key, expire) in map {
-            self.set_expirey(key, expire);
+    pub async fn set_multiple_expires(
+        &self,
+        db: usize,
+        map: HashMap<String, SystemTime>,
+    ) -> Result<()> {
+        let mut data = self.data.lock().await;
+        if db >= data.count() {
+            return Err(anyhow!("DB index {db} is out of range"));
+        }
+        let db = data.db(db);
+        for (key, deadline) in map {
+            db.set_expire_at(key, deadline);
         }
+        Ok(())
     }
 
     pub async fn set(&self, key: String, val: String) {
-        let mut data = self.data.lock().await;
-        data.insert(key, val);
+        self.data.lock().await.db(0).set(key, val);
     }
 
     pub async fn set_with_expire(&self, key: String, val: String, expire: Duration) {
-        let key_clone = key.clone();
-        let mut data = self.data.lock().await;
-        data.insert(key, val);
-        self.set_expirey(key_clone, expire);
+        self.data
+            .lock()
+            .await
+            .db(0)
+            .set_with_expire(key, val, expire);
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.data.lock().await.db(0).get(&key)
+    }
+
+    pub async fn get_matching_keys(&self, pattern: String) -> Vec<String> {
+        self.data.lock().await.db(0).get_matching_keys(&pattern)
+    }
+}
+
+/// Periodically removes expired keys so that they are freed, and their
+/// expired events published and DELs propagated, even if nobody accesses
+/// them again.
+fn start_expire_cycle(data: Weak<Mutex<Keyspace>>, aof: Arc<Aof>, replication: Arc<Replication>) {
+    tokio::spawn(async move {
+        loop {
+            sleep(EXPIRE_CYCLE_PERIOD).await;
+            let Some(data) = data.upgrade() else {
+                return;
+            };
+            let mut keyspace = data.lock().await;
+            keyspace.remove_expired_keys();
+            let effects = keyspace.take_expired();
+            aof.append(&effects);
+            replication.propagate(&effects);
+            drop(keyspace);
+            if !effects.is_empty() {
+                if let Err(e) = aof.flush() {
+                    eprintln!("failed to write to the append only file: {e}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        parser::RedisValue,
+        pubsub::Subscriber,
+        rdb::{write_rdb, RdbDatabase, RdbFile},
+        store::Store,
+        value::Value,
+    };
+
+    #[tokio::test]
+    async fn should_reject_databases_out_of_range() {
+        let store = Store::with_databases(4);
+        let database = RdbDatabase {
+            key_vals: HashMap::from([("k".to_owned(), Arc::new(Value::String("v".to_owned())))]),
+            key_expires: HashMap::new(),
+        };
+        let file = RdbFile {
+            version: 11,
+            aux: vec![],
+            databases: BTreeMap::from([(4, database)]),
+            functions: vec![],
+        };
+        assert!(store.load_rdb(file).await.is_err());
+        assert!(store
+            .set_multiple_expires(9, HashMap::from([("k".to_owned(), SystemTime::now())]))
+            .await
+            .is_err());
     }
 
-    fn set_expirey(&self, key: String, expire: Duration) {
-        let data_clone = self.data.clone();
+    #[tokio::test]
+    async fn should_share_values_with_snapshots() {
+        let store = Store::new();
+        store.set("kept".to_owned(), "1".to_owned()).await;
+        store.set("changed".to_owned(), "1".to_owned()).await;
+        let snapshot = store.snapshot(&*store.lock().await);
+        store.set("changed".to_owned(), "2".to_owned()).await;
+
+        let saved = &snapshot.databases[&0].key_vals;
+        let mut keyspace = store.lock().await;
+        let kept = keyspace.db(0).get_value("kept").unwrap();
+        assert!(Arc::ptr_eq(&saved["kept"], &kept));
+        assert_eq!(*saved["changed"], Value::String("1".to_owned()));
+        assert_eq!(keyspace.db(0).get("changed").unwrap(), Some("2".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn should_replace_the_dataset_with_an_rdb_stream() {
+        let key_vals: HashMap<_, _> = (0..2000)
+            .map(|i| {
+                (
+                    format!("key:{i}"),
+                    Arc::new(Value::String("v".repeat(i % 50))),
+                )
+            })
+            .collect();
+        let file = RdbFile {
+            version: 11,
+            aux: vec![],
+            databases: BTreeMap::from([(
+                1,
+                RdbDatabase {
+                    key_vals,
+                    key_expires: HashMap::new(),
+                },
+            )]),
+            functions: vec![],
+        };
+        let mut image = vec![];
+        write_rdb(&file, &mut image).unwrap();
+        let mut data = format!("${}\r\n", image.len()).into_bytes();
+        data.extend(image);
+        data.extend(RedisValue::make_bulk_array(vec!["PING".to_owned()]).serialize());
+
+        // A small pipe, so the image arrives in many reads.
+        let (mut reader, mut writer) = tokio::io::duplex(64);
+        let (half_sent, mut half_sent_rx) = tokio::sync::oneshot::channel();
+        let (resume, resume_rx) = tokio::sync::oneshot::channel::<()>();
         tokio::spawn(async move {
-            sleep(expire).await;
-            let mut data = data_clone.lock().await;
-            data.remove(&key);
+            let (first, second) = data.split_at(data.len() / 2);
+            for chunk in first.chunks(100) {
+                writer.write_all(chunk).await.unwrap();
+            }
+            half_sent.send(()).unwrap();
+            resume_rx.await.unwrap();
+            for chunk in second.chunks(100) {
+                writer.write_all(chunk).await.unwrap();
+            }
         });
+        let store = Arc::new(Store::new());
+        store.set("stale".to_owned(), "0".to_owned()).await;
+        let loading = store.clone();
+        let load = tokio::spawn(async move {
+            let mut buf = BytesMut::new();
+            loading
+                .replace_with_rdb_stream(&mut reader, &mut buf)
+                .await
+                .map(|_| (reader, buf))
+        });
+
+        // The old dataset stays readable until the whole image is loaded.
+        (&mut half_sent_rx).await.unwrap();
+        let mut keyspace = store.lock().await;
+        assert_eq!(keyspace.db(0).get("stale").unwrap(), Some("0".to_owned()));
+        assert_eq!(keyspace.db(1).len(), 0);
+        drop(keyspace);
+        resume.send(()).unwrap();
+        let (mut reader, mut buf) = load.await.unwrap().unwrap();
+
+        let mut keyspace = store.lock().await;
+        assert_eq!(keyspace.db(0).get("stale").unwrap(), None);
+        assert_eq!(keyspace.db(1).len(), 2000);
+        assert_eq!(keyspace.db(1).get("key:49").unwrap(), Some("v".repeat(49)));
+        drop(keyspace);
+        // The command after the image is left for the caller, whether it
+        // was read along with the image or not.
+        while reader.read_buf(&mut buf).await.unwrap() > 0 {}
+        assert_eq!(&buf[..], b"*1\r\n$4\r\nPING\r\n");
     }
-    pub async fn get(&self, key: String) -> Option<String> {
-        let data = self.data.lock().await;
-        data.get(&key).cloned()
+
+    #[tokio::test]
+    async fn should_publish_expired_event_without_access() {
+        let store = Store::new();
+        store.set_notify_keyspace_events("Ex").unwrap();
+        let mut sub = Subscriber::new(store.get_pubsub());
+        sub.subscribe(vec!["__keyevent@0__:expired".to_owned()]);
+
+        store
+            .set_with_expire(
+                "session".to_owned(),
+                "v".to_owned(),
+                Duration::from_millis(10),
+            )
+            .await;
+        let msg = tokio::time::timeout(Duration::from_secs(1), sub.recv())
+            .await
+            .expect("expired event was not published");
+        assert_eq!(
+            msg.unwrap(),
+            RedisValue::make_bulk_array(vec![
+                "message".to_owned(),
+                "__keyevent@0__:expired".to_owned(),
+                "session".to_owned()
+            ])
+        );
     }
 
-    pub async fn get_matching_keys(&self, pattern: String) -> Vec<String> {
-        assert_eq!(pattern, "*");
-        let data = self.data.lock().await;
-        data.keys().cloned().collect()
+    #[tokio::test]
+    async fn should_not_expire_overwritten_key() {
+        let store = Store::new();
+        store
+            .set_with_expire("k".to_owned(), "v1".to_owned(), Duration::from_millis(10))
+            .await;
+        store.set("k".to_owned(), "v2".to_owned()).await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(
+            store.get("k".to_owned()).await.unwrap(),
+            Some("v2".to_owned())
+        );
     }
 }
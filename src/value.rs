@@ -0,0 +1,216 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Counter given to new keys so they are not evicted right away, as in Redis.
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// Minutes after which an idle key's access counter is halved one step.
+const LFU_DECAY_TIME: Duration = Duration::from_secs(60);
+
+/// Default limits under which Redis keeps collections in their compact
+/// encodings.
+const MAX_LISTPACK_ENTRIES: usize = 128;
+const MAX_LISTPACK_VALUE: usize = 64;
+const MAX_INTSET_ENTRIES: usize = 512;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    List(VecDeque<String>),
+    Set(HashSet<String>),
+    /// Members with their scores.
+    SortedSet(HashMap<String, f64>),
+    Hash(HashMap<String, String>),
+    Stream(Stream),
+}
+
+impl Value {
+    /// Name reported by TYPE.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Hash(_) => "hash",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// Encoding reported by OBJECT ENCODING, using the same thresholds Redis
+    /// uses to pick its in-memory representation.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(s) if s.len() <= 20 && s.parse::<i64>().is_ok() => "int",
+            Value::String(s) if s.len() <= 44 => "embstr",
+            Value::String(_) => "raw",
+            Value::List(list) if is_small(list.len(), list.iter()) => "listpack",
+            Value::List(_) => "quicklist",
+            Value::Set(set)
+                if set.len() <= MAX_INTSET_ENTRIES
+                    && set.iter().all(|member| member.parse::<i64>().is_ok()) =>
+            {
+                "intset"
+            }
+            Value::Set(set) if is_small(set.len(), set.iter()) => "listpack",
+            Value::Set(_) => "hashtable",
+            Value::SortedSet(zset) if is_small(zset.len(), zset.keys()) => "listpack",
+            Value::SortedSet(_) => "skiplist",
+            Value::Hash(hash) if is_small(hash.len(), hash.keys().chain(hash.values())) => {
+                "listpack"
+            }
+            Value::Hash(_) => "hashtable",
+            Value::Stream(_) => "stream",
+        }
+    }
+}
+
+fn is_small<'a>(len: usize, mut items: impl Iterator<Item = &'a String>) -> bool {
+    len <= MAX_LISTPACK_ENTRIES && items.all(|item| item.len() <= MAX_LISTPACK_VALUE)
+}
+
+/// Stream entry IDs are a millisecond time and a sequence number.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(String, String)>>,
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    pub max_deleted_id: StreamId,
+    /// Count of all entries ever added, including deleted ones.
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumerGroup {
+    pub name: String,
+    pub last_id: StreamId,
+    /// Logical read counter, -1 when unknown.
+    pub entries_read: i64,
+    pub pending: Vec<PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+/// A delivered entry that was not acknowledged yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry {
+    pub id: StreamId,
+    pub consumer: String,
+    /// Unix time in milliseconds of the last delivery.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Consumer {
+    pub name: String,
+    /// Unix times in milliseconds of the last interaction and the last
+    /// successful read.
+    pub seen_time: u64,
+    pub active_time: u64,
+}
+
+/// A value in the keyspace with the access metadata OBJECT reports. Values
+/// are never modified in place, so snapshots share them with the keyspace.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub value: Arc<Value>,
+    last_access: Instant,
+    lfu_counter: u8,
+    lfu_decrement_time: Instant,
+}
+
+impl Entry {
+    pub fn new(value: impl Into<Arc<Value>>) -> Self {
+        let now = Instant::now();
+        Entry {
+            value: value.into(),
+            last_access: now,
+            lfu_counter: LFU_INIT_VAL,
+            lfu_decrement_time: now,
+        }
+    }
+
+    /// Records an access for the LRU clock and the LFU counter. `random` is a
+    /// uniformly distributed number in `[0, 1)`.
+    pub fn touch(&mut self, random: f64) {
+        let now = Instant::now();
+        self.last_access = now;
+        self.lfu_counter = self.decayed_counter();
+        self.lfu_decrement_time = now;
+        if self.lfu_counter < u8::MAX {
+            let base = self.lfu_counter.saturating_sub(LFU_INIT_VAL) as f64;
+            if random < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                self.lfu_counter += 1;
+            }
+        }
+    }
+
+    /// Backdates the last access, as RESTORE ... IDLETIME does.
+    pub fn set_idle_time(&mut self, idle: Duration) {
+        self.last_access = Instant::now().checked_sub(idle).unwrap_or(self.last_access);
+    }
+
+    pub fn set_freq(&mut self, freq: u8) {
+        self.lfu_counter = freq;
+        self.lfu_decrement_time = Instant::now();
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.last_access.elapsed()
+    }
+
+    /// Logarithmic access frequency, decayed by the time the key was idle.
+    pub fn freq(&self) -> u8 {
+        self.decayed_counter()
+    }
+
+    fn decayed_counter(&self) -> u8 {
+        let periods = self.lfu_decrement_time.elapsed().as_secs() / LFU_DECAY_TIME.as_secs();
+        self.lfu_counter
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::{Entry, Value, LFU_INIT_VAL};
+
+    #[test]
+    fn should_pick_string_encoding() {
+        assert_eq!(Value::String("12345".to_owned()).encoding(), "int");
+        assert_eq!(Value::String("hello".to_owned()).encoding(), "embstr");
+        assert_eq!(Value::String("x".repeat(45)).encoding(), "raw");
+    }
+
+    #[test]
+    fn should_pick_collection_encoding() {
+        let ints = Value::Set(["1".to_owned(), "2".to_owned()].into());
+        assert_eq!(ints.encoding(), "intset");
+        let small = Value::Set(["a".to_owned()].into());
+        assert_eq!(small.encoding(), "listpack");
+        let big = Value::List((0..200).map(|i| i.to_string()).collect());
+        assert_eq!(big.encoding(), "quicklist");
+    }
+
+    #[test]
+    fn should_increment_frequency_logarithmically() {
+        let mut entry = Entry::new(Value::String("v".to_owned()));
+        assert_eq!(entry.freq(), LFU_INIT_VAL);
+        entry.touch(0.0);
+        assert_eq!(entry.freq(), LFU_INIT_VAL + 1);
+        // Past the initial value an access only counts with probability
+        // 1 / (10 * (counter - 5) + 1).
+        entry.touch(0.5);
+        assert_eq!(entry.freq(), LFU_INIT_VAL + 1);
+    }
+}
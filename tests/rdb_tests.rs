@@ -1,13 +1,37 @@
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, sync::Arc};
 
-    use redis_starter_rust::rdb::read_rdb_file;
+    use redis_starter_rust::{
+        rdb::{crc64, read_rdb_file},
+        value::Value,
+    };
 
     #[test]
     fn should_read_a_key() {
         let val = read_rdb_file("tests/dump.rdb".to_owned()).expect("failed to read rdb");
-        let expect = HashMap::from([("mykey".to_owned(), "myval".to_owned())]);
-        assert_eq!(val.key_vals, expect);
+        let expect = HashMap::from([(
+            "mykey".to_owned(),
+            Arc::new(Value::String("myval".to_owned())),
+        )]);
+        assert_eq!(val.databases[&0].key_vals, expect);
+    }
+
+    #[test]
+    fn should_read_aux_fields() {
+        let val = read_rdb_file("tests/dump.rdb".to_owned()).expect("failed to read rdb");
+        assert_eq!(val.version, 7);
+        assert_eq!(val.aux[0], ("redis-ver".to_owned(), "6.0.16".to_owned()));
+        assert_eq!(val.aux[1], ("redis-bits".to_owned(), "64".to_owned()));
+    }
+
+    #[test]
+    fn should_have_a_valid_checksum() {
+        // The trailer is the CRC-64/Jones of everything before it.
+        let data = std::fs::read("tests/dump.rdb").unwrap();
+        let (body, trailer) = data.split_at(data.len() - 8);
+        let checksum = u64::from_le_bytes(trailer.try_into().unwrap());
+        assert_eq!(checksum, 0xdc64e5271201bcca);
+        assert_eq!(crc64(0, body), checksum);
     }
 }